@@ -0,0 +1,55 @@
+//! Answers every text message with the same text and befriends whoever asks.
+//!
+//! Takes the same options as `p2pchat`, give it a profile of its own so it
+//! doesn't share an identity with the TUI:
+//!
+//! ```sh
+//! cargo run --example echo_bot -- --profile echo
+//! ```
+use std::error::Error;
+use std::process::ExitCode;
+
+use clap::Parser;
+use libp2p::PeerId;
+use p2pchat::bot::{self, Bot};
+use p2pchat::config::Config;
+use p2pchat::network::Client;
+use p2pchat::network::chat::{Message, MessageBody};
+use p2pchat::node::Node;
+use tracing_subscriber::EnvFilter;
+
+struct Echo;
+impl Bot for Echo {
+    async fn on_message(&mut self, client: &mut Client, peer: PeerId, message: &Message) {
+        // edits, deletions and reactions aren't echoed
+        let MessageBody::Text { content, .. } = message.body() else {
+            return;
+        };
+        let body = MessageBody::Text {
+            content: content.clone(),
+            reply_to: Some(message.id()),
+        };
+        // not waiting for the acknowledgement keeps the bot answering others
+        client.send(peer, body).await;
+    }
+    async fn on_friend_request(&mut self, _client: &mut Client, peer: PeerId) -> bool {
+        tracing::info!("befriending {peer}");
+        true
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let config = Config::parse();
+    // without a terminal to draw on, logs go to stderr
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log))
+        .with_writer(std::io::stderr)
+        .init();
+    let node = Node::open(&config).await?;
+    println!("{}", node.client.id);
+    Ok(match bot::run(Echo, node).await {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
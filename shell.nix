@@ -14,5 +14,8 @@ pkgs.mkShellNoCC {
     rust-analyzer
     openssl
     pkg-config
+    # for the `audio` feature
+    libopus
+    alsa-lib
   ];
 }
@@ -0,0 +1,354 @@
+use libp2p::PeerId;
+use std::{collections::HashMap, error::Error, process::ExitCode, sync::Arc};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::network::{self, Contact, Event};
+use crate::node::{Node, shutdown};
+use crate::tui::{self, Tui};
+use crate::{
+    cli, identity, profile,
+    settings::{self, SaveFile, Settings, get_config_save_file_path},
+};
+#[cfg(unix)]
+use crate::{daemon, settings::Setting};
+
+/// Exit statuses, clap exits with 2 on invalid arguments
+/// and returned errors exit with 1.
+mod exit_code {
+    /// The network couldn't start, e.g. an address couldn't be listened on
+    pub const NETWORK: u8 = 3;
+    /// Shutdown didn't finish, e.g. the database couldn't be flushed
+    pub const UNCLEAN_SHUTDOWN: u8 = 4;
+    /// The message database couldn't be opened, e.g. it's locked or corrupt
+    pub const DATABASE: u8 = 5;
+    /// The app panicked, like an uncaught panic on the main thread
+    pub const PANIC: u8 = 101;
+    /// Stopped by a signal, 128 plus its number like shells report it
+    pub const SIGINT: u8 = 130;
+    pub const SIGTERM: u8 = 143;
+}
+
+/// Runs what `p2pchat` was asked to: the TUI, a single command or the daemon.
+pub async fn run(config: Config) -> Result<ExitCode, Box<dyn Error>> {
+    settings::set_dirs(config.dirs())?;
+    settings::create_config_path()?;
+    // each profile logs to its own file, the terminal belongs to the TUI
+    let log_file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_config_save_file_path(SaveFile::Log))?;
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log))
+        .with_ansi(false)
+        .with_writer(std::sync::Mutex::new(log_file))
+        .init();
+    let mut settings = Settings::load().await;
+    let db = match Database::open().await {
+        Ok(db) => db,
+        Err(err) => {
+            tracing::error!("failed to open the message database: {err}");
+            eprintln!("p2pchat: failed to open the message database: {err}");
+            return Ok(ExitCode::from(exit_code::DATABASE));
+        }
+    };
+    if let Some(command) = config.command.clone() {
+        return Ok(cli::run(command, &config, settings, db).await);
+    }
+    if config.headless {
+        #[cfg(unix)]
+        return headless(&config, settings, db).await;
+        #[cfg(not(unix))]
+        return Err("headless mode needs unix sockets".into());
+    }
+    // loaded before the terminal is taken over, so a failure is still readable
+    let saved_keys = identity::load()?;
+    let mut tui = Tui::new();
+    // Without a saved identity this is the first run, set everything up before going online
+    let keys = match saved_keys {
+        Some(keys) => keys,
+        None => match tui::onboarding::run(&mut tui, &mut settings).await {
+            Ok(Some(keys)) => keys,
+            Ok(None) => {
+                tui.exit();
+                return Ok(ExitCode::SUCCESS);
+            }
+            Err(err) => {
+                tui.exit();
+                return Err(err.into());
+            }
+        },
+    };
+    let tui_tx = tui.event_tx.clone();
+
+    let network_config = config.network(&settings);
+    let settings = Arc::new(RwLock::new(settings));
+    // a daemon running this profile already owns the network and database writes
+    #[cfg(unix)]
+    let attached = match daemon::attach::connect(
+        settings.clone(),
+        tui_tx.clone(),
+        network_config.request_timeout,
+    )
+    .await
+    {
+        Ok(attached) => attached,
+        Err(err) => {
+            tui.exit();
+            return Err(err.into());
+        }
+    };
+    #[cfg(not(unix))]
+    let attached = None;
+    let (network_task, mut client, mut network_event) = match attached {
+        Some((client, relay)) => {
+            tracing::info!("attached to the daemon");
+            // events arrive through the relay instead
+            let (_, network_event) = tokio::sync::mpsc::channel(1);
+            (relay, client, network_event)
+        }
+        None => {
+            let (contacts_tx, contacts) = tokio::sync::mpsc::unbounded_channel();
+            tokio::spawn(show_contacts(contacts, tui_tx.clone()));
+            match network::new(
+                settings.clone(),
+                contacts_tx,
+                db.clone(),
+                keys,
+                network_config,
+            )
+            .await
+            {
+                Ok((event_loop, client, network_event)) => {
+                    (tokio::spawn(event_loop.run()), client, network_event)
+                }
+                Err(err) => {
+                    tui.exit();
+                    tracing::error!("failed to start the network: {err}");
+                    eprintln!("p2pchat: {err}");
+                    return Ok(ExitCode::from(exit_code::NETWORK));
+                }
+            }
+        }
+    };
+    let token = CancellationToken::new();
+    let child_token = token.child_token();
+
+    let tui_task = tokio::spawn(tui::run(
+        client.clone(),
+        db.clone(),
+        settings.clone(),
+        token.clone(),
+        tui,
+        config.profile().to_string(),
+    ));
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut status = ExitCode::SUCCESS;
+    loop {
+        // Read full lines from stdin
+        tokio::select! {
+            _ = child_token.cancelled() => break,
+            code = &mut signal => {
+                tracing::info!("stopping on signal");
+                status = ExitCode::from(code);
+                token.cancel();
+                break;
+            }
+            Some(event) = network_event.recv() => {
+                if let Some(notice) = event.notice() {
+                    let _ = tui_tx.send(tui::Event::Notice(notice));
+                }
+                match event {
+                    Event::InboundMessage { message, sender } => {
+                        let peer_id = PeerId::from_public_key(&(*sender).into());
+                        tracing::info!("recived message {} from {peer_id}", message.id());
+                        let Some(message) = tui::types::Message::received(&message, peer_id) else {
+                            continue;
+                        };
+                        let _ = tui_tx.send(tui::Event::MessageReceived(message));
+                    }
+                    Event::PresenceChanged { peer, status, message } => {
+                        let presence = tui::types::Presence { status, message };
+                        let _ = tui_tx.send(tui::Event::Presence(peer, presence));
+                    }
+                    Event::Typing { peer, typing } => {
+                        let _ = tui_tx.send(tui::Event::Typing(peer, typing));
+                    }
+                    Event::ConversationUpdated { peer } => {
+                        let _ = tui_tx.send(tui::Event::ConversationUpdated(peer));
+                    }
+                    Event::OutboundMessageReceived { message_id } => {
+                        tracing::info!("{} message was received!", message_id);
+                    },
+                    Event::OutboundMessageInvalidSignature { message_id } => {
+                        tracing::info!("{message_id} message has an invalid signature");
+                    },
+                    Event::OutboundMessageRejected { message_id } => {
+                        tracing::info!("{} message was rejected by the receiver", message_id);
+                    },
+                    Event::Call { call_id, peer, state } => {
+                        let _ = tui_tx.send(tui::Event::Call(tui::types::Call {
+                            id: call_id,
+                            peer,
+                            state,
+                        }));
+                    }
+                    Event::Dialed { .. } | Event::Unsupported { .. } | Event::ListenerFailed { .. } => {}
+                    Event::DialFailed { target, reason } => {
+                        tracing::info!("failed to dial {target}: {reason}");
+                    }
+                    Event::ContactCard { token } => {
+                        let _ = tui_tx.send(tui::Event::ContactCard(token));
+                    }
+                    Event::MessageSendFailed { message_id, peer, reason } => {
+                        tracing::info!("message {message_id} to {peer} wasn't delivered: {reason}");
+                    }
+                    Event::FriendRequestFailed { peer, request, reason } => {
+                        tracing::info!("{request:?} to {peer} failed: {reason}");
+                    }
+                    Event::FriendRequest { peer, request } => {
+                        tracing::info!("{peer} sent {request:?}");
+                    }
+                    Event::PeerConnected { peer, address } => {
+                        tracing::info!("connected to {peer} at {address}");
+                    }
+                    Event::PeerDisconnected { peer, reason } => match reason {
+                        Some(reason) => tracing::info!("disconnected from {peer}: {reason}"),
+                        None => tracing::info!("disconnected from {peer}"),
+                    },
+                    Event::ConnectionFailed { peer, reason } => {
+                        tracing::info!("couldn't connect to {peer}: {reason}");
+                    }
+                    Event::InboundFailure { peer, protocol, reason } => {
+                        tracing::info!("{protocol} request from {peer} failed: {reason}");
+                    }
+                    Event::Route { peer, route } => {
+                        let _ = tui_tx.send(tui::Event::Route(peer, route));
+                    }
+                }
+            }
+        }
+    }
+    // nobody listens to the network anymore, but the event loop
+    // still reports what happens while it says goodbye
+    tokio::spawn(async move { while network_event.recv().await.is_some() {} });
+    let switch_profile = match tui_task.await {
+        Ok(Ok(switch_profile)) => switch_profile,
+        Ok(Err(err)) => {
+            tracing::error!("the interface failed: {err}");
+            status = ExitCode::FAILURE;
+            None
+        }
+        Err(err) => {
+            tracing::error!("the interface stopped: {err}");
+            status = ExitCode::from(exit_code::PANIC);
+            None
+        }
+    };
+    if !shutdown(&mut client, network_task, db).await && status == ExitCode::SUCCESS {
+        status = ExitCode::from(exit_code::UNCLEAN_SHUTDOWN);
+    }
+    if let Some(profile) = switch_profile {
+        tracing::info!("switching to profile {profile}");
+        return Err(profile::relaunch(&profile).into());
+    }
+    Ok(status)
+}
+/// Adds the contacts the event loop finds to the contact list.
+async fn show_contacts(
+    mut contacts: tokio::sync::mpsc::UnboundedReceiver<Contact>,
+    tui_tx: tokio::sync::mpsc::UnboundedSender<tui::Event>,
+) {
+    while let Some(Contact { peer, name }) = contacts.recv().await {
+        let contact = tui::types::Contact {
+            peer_id: peer,
+            name,
+        };
+        if tui_tx.send(tui::Event::AddContact(contact)).is_err() {
+            break;
+        }
+    }
+}
+/// Runs the network without a terminal until a signal stops it,
+/// scripts and the TUI control it through the daemon's socket.
+#[cfg(unix)]
+async fn headless(
+    config: &Config,
+    settings: HashMap<settings::SettingName, Setting>,
+    db: Database,
+) -> Result<ExitCode, Box<dyn Error>> {
+    // a second daemon for the profile would run the same identity on the same database
+    let socket = match daemon::bind(&daemon::socket_path()) {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::error!("failed to serve the control socket: {err}");
+            eprintln!("p2pchat: {err}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    // nobody is there to go through the onboarding
+    let keys = identity::load_or_create()?;
+    let network_config = config.network(&settings);
+    let mut node = match Node::start(network_config, settings, db, keys).await {
+        Ok(node) => node,
+        Err(err) => {
+            tracing::error!("failed to start the network: {err}");
+            eprintln!("p2pchat: {err}");
+            return Ok(ExitCode::from(exit_code::NETWORK));
+        }
+    };
+    let token = CancellationToken::new();
+    let stop = token.clone();
+    let signal = tokio::spawn(async move {
+        let code = shutdown_signal().await;
+        tracing::info!("stopping on signal");
+        stop.cancel();
+        code
+    });
+    let daemon = daemon::Daemon::new(node.client.clone(), node.db.clone(), node.settings.clone());
+    // contacts the event loop finds for the TUI go to subscribers instead
+    let mut status = match daemon
+        .serve(socket, &mut node.events, &mut node.contacts, token)
+        .await
+    {
+        Ok(()) => signal
+            .await
+            .map(ExitCode::from)
+            .unwrap_or(ExitCode::FAILURE),
+        Err(err) => {
+            signal.abort();
+            tracing::error!("failed to serve the control socket: {err}");
+            eprintln!("p2pchat: {err}");
+            ExitCode::FAILURE
+        }
+    };
+    if !node.stop().await && status == ExitCode::SUCCESS {
+        status = ExitCode::from(exit_code::UNCLEAN_SHUTDOWN);
+    }
+    Ok(status)
+}
+/// Resolves to the exit status once we are asked to stop, the terminal's
+/// ctrl-c is a key press in raw mode so these come from outside.
+async fn shutdown_signal() -> u8 {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return exit_code::SIGINT;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => exit_code::SIGINT,
+            _ = terminate.recv() => exit_code::SIGTERM,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        exit_code::SIGINT
+    }
+}
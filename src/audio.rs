@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+mod codec;
+mod device;
+
+pub use codec::{Codec, CodecKind};
+pub use device::{AudioInput, AudioOutput};
+#[cfg(test)]
+pub(crate) use device::{FileInput, FileOutput};
+
+// Calls are mono 48kHz, sent in 20ms frames
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const FRAME_SAMPLES: usize = 960;
+
+// Raw 16-bit little endian PCM files used in place of a microphone / speakers,
+// handy on machines without sound hardware.
+const INPUT_FILE_ENV: &str = "P2PCHAT_AUDIO_INPUT";
+const OUTPUT_FILE_ENV: &str = "P2PCHAT_AUDIO_OUTPUT";
+
+/// Whether calls can record and play anything, on devices or the files above.
+pub fn available() -> bool {
+    cfg!(feature = "audio")
+        || (std::env::var_os(INPUT_FILE_ENV).is_some()
+            && std::env::var_os(OUTPUT_FILE_ENV).is_some())
+}
+#[cfg(not(feature = "audio"))]
+fn unavailable() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "built without audio devices",
+    )
+}
+pub fn open_input() -> std::io::Result<Box<dyn AudioInput>> {
+    if let Some(path) = std::env::var_os(INPUT_FILE_ENV) {
+        return Ok(Box::new(device::FileInput::open(PathBuf::from(path))?));
+    }
+    #[cfg(feature = "audio")]
+    {
+        Ok(Box::new(device::CpalInput::open()?))
+    }
+    #[cfg(not(feature = "audio"))]
+    {
+        Err(unavailable())
+    }
+}
+pub fn open_output() -> std::io::Result<Box<dyn AudioOutput>> {
+    if let Some(path) = std::env::var_os(OUTPUT_FILE_ENV) {
+        return Ok(Box::new(device::FileOutput::create(PathBuf::from(path))?));
+    }
+    #[cfg(feature = "audio")]
+    {
+        Ok(Box::new(device::CpalOutput::open()?))
+    }
+    #[cfg(not(feature = "audio"))]
+    {
+        Err(unavailable())
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodecKind {
+    Opus,
+    /// Uncompressed 16-bit PCM, used when a node is built without Opus support
+    Pcm,
+}
+impl CodecKind {
+    /// Codecs this build can encode and decode, most preferred first.
+    pub fn supported() -> Vec<CodecKind> {
+        #[cfg(feature = "audio")]
+        {
+            vec![CodecKind::Opus, CodecKind::Pcm]
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            vec![CodecKind::Pcm]
+        }
+    }
+    /// Picks our most preferred codec that the remote also offers.
+    pub fn negotiate(offered: &[CodecKind]) -> Option<CodecKind> {
+        Self::supported()
+            .into_iter()
+            .find(|codec| offered.contains(codec))
+    }
+    pub fn build(self) -> std::io::Result<Box<dyn Codec>> {
+        match self {
+            #[cfg(feature = "audio")]
+            CodecKind::Opus => Ok(Box::new(OpusCodec::new()?)),
+            #[cfg(not(feature = "audio"))]
+            CodecKind::Opus => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "built without opus support",
+            )),
+            CodecKind::Pcm => Ok(Box::new(PcmCodec)),
+        }
+    }
+}
+
+pub trait Codec: Send {
+    fn encode(&mut self, frame: &[i16]) -> std::io::Result<Vec<u8>>;
+    fn decode(&mut self, packet: &[u8]) -> std::io::Result<Vec<i16>>;
+}
+
+pub struct PcmCodec;
+impl Codec for PcmCodec {
+    fn encode(&mut self, frame: &[i16]) -> std::io::Result<Vec<u8>> {
+        Ok(frame.iter().flat_map(|s| s.to_le_bytes()).collect())
+    }
+    fn decode(&mut self, packet: &[u8]) -> std::io::Result<Vec<i16>> {
+        Ok(packet
+            .chunks_exact(2)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]))
+            .collect())
+    }
+}
+
+#[cfg(feature = "audio")]
+pub struct OpusCodec {
+    encoder: opus::Encoder,
+    decoder: opus::Decoder,
+}
+#[cfg(feature = "audio")]
+impl OpusCodec {
+    // Largest packet opus recommends allocating for
+    const MAX_PACKET: usize = 4000;
+
+    pub fn new() -> std::io::Result<Self> {
+        use crate::audio::SAMPLE_RATE;
+        Ok(Self {
+            encoder: opus::Encoder::new(SAMPLE_RATE, opus::Channels::Mono, opus::Application::Voip)
+                .map_err(std::io::Error::other)?,
+            decoder: opus::Decoder::new(SAMPLE_RATE, opus::Channels::Mono)
+                .map_err(std::io::Error::other)?,
+        })
+    }
+}
+#[cfg(feature = "audio")]
+impl Codec for OpusCodec {
+    fn encode(&mut self, frame: &[i16]) -> std::io::Result<Vec<u8>> {
+        self.encoder
+            .encode_vec(frame, Self::MAX_PACKET)
+            .map_err(std::io::Error::other)
+    }
+    fn decode(&mut self, packet: &[u8]) -> std::io::Result<Vec<i16>> {
+        let mut frame = vec![0i16; crate::audio::FRAME_SAMPLES];
+        let len = self
+            .decoder
+            .decode(packet, &mut frame, false)
+            .map_err(std::io::Error::other)?;
+        frame.truncate(len);
+        Ok(frame)
+    }
+}
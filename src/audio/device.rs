@@ -0,0 +1,280 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+pub trait AudioInput: Send {
+    /// Fills the whole frame with captured samples.
+    /// Returns false once the input is exhausted.
+    fn read_frame(&mut self, frame: &mut [i16]) -> std::io::Result<bool>;
+}
+pub trait AudioOutput: Send {
+    fn write_frame(&mut self, frame: &[i16]) -> std::io::Result<()>;
+}
+
+/// Reads raw 16-bit little endian mono PCM from a file.
+pub struct FileInput {
+    reader: BufReader<File>,
+}
+impl FileInput {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+}
+impl AudioInput for FileInput {
+    fn read_frame(&mut self, frame: &mut [i16]) -> std::io::Result<bool> {
+        let mut bytes = vec![0u8; frame.len() * 2];
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match self.reader.read(&mut bytes[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            return Ok(false);
+        }
+        // pad the last partial frame with silence
+        bytes[filled..].fill(0);
+        for (sample, chunk) in frame.iter_mut().zip(bytes.chunks_exact(2)) {
+            *sample = i16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(true)
+    }
+}
+
+/// Writes raw 16-bit little endian mono PCM to a file.
+pub struct FileOutput {
+    writer: BufWriter<File>,
+}
+impl FileOutput {
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+impl AudioOutput for FileOutput {
+    fn write_frame(&mut self, frame: &[i16]) -> std::io::Result<()> {
+        for sample in frame {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(feature = "audio")]
+pub use cpal_device::{CpalInput, CpalOutput};
+
+#[cfg(feature = "audio")]
+mod cpal_device {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        thread::JoinHandle,
+    };
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{FromSample, Sample, SampleFormat, SizedSample};
+
+    use super::{AudioInput, AudioOutput};
+    use crate::audio::{FRAME_SAMPLES, SAMPLE_RATE};
+
+    // Playback buffer is capped so latency doesn't grow when the peer sends faster than we play
+    const MAX_BUFFERED_FRAMES: usize = 10;
+
+    fn config() -> cpal::StreamConfig {
+        cpal::StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+    fn io_err(err: impl std::fmt::Display) -> std::io::Error {
+        std::io::Error::other(err.to_string())
+    }
+    // Devices take samples in their own format, calls convert them from and to i16
+    macro_rules! with_sample_type {
+        ($format:expr, $build:ident($($arg:expr),*)) => {
+            match $format {
+                SampleFormat::I8 => $build::<i8>($($arg),*),
+                SampleFormat::I16 => $build::<i16>($($arg),*),
+                SampleFormat::I32 => $build::<i32>($($arg),*),
+                SampleFormat::I64 => $build::<i64>($($arg),*),
+                SampleFormat::U8 => $build::<u8>($($arg),*),
+                SampleFormat::U16 => $build::<u16>($($arg),*),
+                SampleFormat::U32 => $build::<u32>($($arg),*),
+                SampleFormat::U64 => $build::<u64>($($arg),*),
+                SampleFormat::F32 => $build::<f32>($($arg),*),
+                SampleFormat::F64 => $build::<f64>($($arg),*),
+                format => Err(io_err(format!("unsupported sample format {format}"))),
+            }
+        };
+    }
+    fn input_stream<T>(
+        device: &cpal::Device,
+        tx: mpsc::Sender<Vec<i16>>,
+    ) -> std::io::Result<cpal::Stream>
+    where
+        T: SizedSample,
+        i16: FromSample<T>,
+    {
+        device
+            .build_input_stream(
+                &config(),
+                move |data: &[T], _| {
+                    let _ = tx.send(data.iter().map(|s| i16::from_sample(*s)).collect());
+                },
+                |err| tracing::error!("audio input error: {err}"),
+                None,
+            )
+            .map_err(io_err)
+    }
+    fn output_stream<T>(
+        device: &cpal::Device,
+        playback: Arc<Mutex<VecDeque<i16>>>,
+    ) -> std::io::Result<cpal::Stream>
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        device
+            .build_output_stream(
+                &config(),
+                move |data: &mut [T], _| {
+                    let mut buffer = playback.lock().expect("audio buffer poisoned");
+                    for sample in data.iter_mut() {
+                        *sample = T::from_sample(buffer.pop_front().unwrap_or(0));
+                    }
+                },
+                |err| tracing::error!("audio output error: {err}"),
+                None,
+            )
+            .map_err(io_err)
+    }
+
+    // cpal streams are not Send, so each one lives on its own thread until dropped
+    struct StreamThread {
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+    impl StreamThread {
+        fn spawn<F>(build: F) -> std::io::Result<Self>
+        where
+            F: FnOnce() -> std::io::Result<cpal::Stream> + Send + 'static,
+        {
+            let stop = Arc::new(AtomicBool::new(false));
+            let (ready_tx, ready_rx) = mpsc::channel();
+            let thread_stop = stop.clone();
+            let thread = std::thread::spawn(move || {
+                let stream = match build().and_then(|s| s.play().map(|_| s).map_err(io_err)) {
+                    Ok(stream) => {
+                        let _ = ready_tx.send(Ok(()));
+                        stream
+                    }
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                };
+                while !thread_stop.load(Ordering::Relaxed) {
+                    std::thread::park();
+                }
+                drop(stream);
+            });
+            ready_rx
+                .recv()
+                .map_err(|_| io_err("audio thread exited"))??;
+            Ok(Self {
+                stop,
+                thread: Some(thread),
+            })
+        }
+    }
+    impl Drop for StreamThread {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                thread.thread().unpark();
+                let _ = thread.join();
+            }
+        }
+    }
+
+    pub struct CpalInput {
+        samples: mpsc::Receiver<Vec<i16>>,
+        pending: VecDeque<i16>,
+        _stream: StreamThread,
+    }
+    impl CpalInput {
+        pub fn open() -> std::io::Result<Self> {
+            let (tx, rx) = mpsc::channel();
+            let stream = StreamThread::spawn(move || {
+                let device = cpal::default_host()
+                    .default_input_device()
+                    .ok_or_else(|| io_err("no input device"))?;
+                let format = device.default_input_config().map_err(io_err)?;
+                with_sample_type!(format.sample_format(), input_stream(&device, tx))
+            })?;
+            Ok(Self {
+                samples: rx,
+                pending: VecDeque::with_capacity(FRAME_SAMPLES * 2),
+                _stream: stream,
+            })
+        }
+    }
+    impl AudioInput for CpalInput {
+        fn read_frame(&mut self, frame: &mut [i16]) -> std::io::Result<bool> {
+            while self.pending.len() < frame.len() {
+                match self.samples.recv() {
+                    Ok(chunk) => self.pending.extend(chunk),
+                    Err(_) => return Ok(false),
+                }
+            }
+            for sample in frame.iter_mut() {
+                *sample = self.pending.pop_front().unwrap_or(0);
+            }
+            Ok(true)
+        }
+    }
+
+    pub struct CpalOutput {
+        buffer: Arc<Mutex<VecDeque<i16>>>,
+        _stream: StreamThread,
+    }
+    impl CpalOutput {
+        pub fn open() -> std::io::Result<Self> {
+            let buffer = Arc::new(Mutex::new(VecDeque::<i16>::new()));
+            let playback = buffer.clone();
+            let stream = StreamThread::spawn(move || {
+                let device = cpal::default_host()
+                    .default_output_device()
+                    .ok_or_else(|| io_err("no output device"))?;
+                let format = device.default_output_config().map_err(io_err)?;
+                with_sample_type!(format.sample_format(), output_stream(&device, playback))
+            })?;
+            Ok(Self {
+                buffer,
+                _stream: stream,
+            })
+        }
+    }
+    impl AudioOutput for CpalOutput {
+        fn write_frame(&mut self, frame: &[i16]) -> std::io::Result<()> {
+            let mut buffer = self.buffer.lock().expect("audio buffer poisoned");
+            buffer.extend(frame);
+            let max = FRAME_SAMPLES * MAX_BUFFERED_FRAMES;
+            if buffer.len() > max {
+                let excess = buffer.len() - max;
+                buffer.drain(..excess);
+            }
+            Ok(())
+        }
+    }
+}
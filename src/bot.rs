@@ -0,0 +1,93 @@
+use std::future::Future;
+
+use libp2p::PeerId;
+
+use crate::network::chat::Message;
+use crate::network::friends::FriendRequest;
+use crate::network::{Client, Event};
+use crate::node::Node;
+
+/// An automated responder that lives on the network as an ordinary peer,
+/// e.g. an echo bot or one posting CI results. Every hook does nothing by default.
+///
+/// Hooks run one at a time on the bot's loop while the events behind them wait,
+/// so anything slow, like waiting for a peer to acknowledge a reply, belongs in a
+/// task with its own clone of the client.
+pub trait Bot: Send {
+    /// Called once the network is up, before any event, e.g. to start a timer.
+    fn on_start(&mut self, client: &mut Client) -> impl Future<Output = ()> + Send {
+        let _ = client;
+        async {}
+    }
+    /// A message from `peer`, including edits, deletions and reactions.
+    fn on_message(
+        &mut self,
+        client: &mut Client,
+        peer: PeerId,
+        message: &Message,
+    ) -> impl Future<Output = ()> + Send {
+        let _ = (client, peer, message);
+        async {}
+    }
+    /// `peer` wants to be friends, accepted if this resolves to `true`. Declines by default.
+    fn on_friend_request(
+        &mut self,
+        client: &mut Client,
+        peer: PeerId,
+    ) -> impl Future<Output = bool> + Send {
+        let _ = (client, peer);
+        async { false }
+    }
+    /// Every event, after the hooks above handled it.
+    fn on_event(&mut self, client: &mut Client, event: &Event) -> impl Future<Output = ()> + Send {
+        let _ = (client, event);
+        async {}
+    }
+}
+/// Runs `bot` on `node` until ctrl-c or the network stops, then stops the node.
+/// Resolves to `false` if it didn't stop cleanly.
+pub async fn run(mut bot: impl Bot, mut node: Node) -> bool {
+    tracing::info!("bot running as {}", node.client.id);
+    bot.on_start(&mut node.client).await;
+    loop {
+        let event = tokio::select! {
+            event = node.events.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            // nobody reads them without a terminal
+            Some(_) = node.contacts.recv() => continue,
+            _ = tokio::signal::ctrl_c() => {
+                tracing::info!("stopping on signal");
+                break;
+            }
+        };
+        let client = &mut node.client;
+        match &event {
+            Event::InboundMessage { message, sender } => {
+                let peer = PeerId::from_public_key(&(**sender).clone().into());
+                bot.on_message(client, peer, message).await;
+            }
+            Event::FriendRequest {
+                peer,
+                request: FriendRequest::AddFriend { .. },
+            } => {
+                let accept = bot.on_friend_request(client, *peer).await;
+                let (mut client, peer) = (client.clone(), *peer);
+                // answering waits on the peer, events keep coming meanwhile
+                tokio::spawn(async move {
+                    let answered = match accept {
+                        true => client.accept_friend_req(peer).await,
+                        false => client.deny_friend_req(peer).await,
+                    };
+                    if let Err(err) = answered {
+                        tracing::info!("failed to answer the friend request of {peer}: {err}");
+                    }
+                });
+            }
+            _ => {}
+        }
+        bot.on_event(client, &event).await;
+    }
+    node.stop().await
+}
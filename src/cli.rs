@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::Subcommand;
+use libp2p::PeerId;
+
+use crate::config::Config;
+#[cfg(unix)]
+use crate::daemon::{self, rpc::Call};
+use crate::db::Database;
+use crate::network::Event;
+use crate::network::chat::MessageBody;
+use crate::node::Node;
+use crate::settings::{Setting, SettingName, SettingValue, Settings};
+
+/// Commands for scripts, they go through the daemon when one runs for the profile
+/// and otherwise use the stores directly, starting the network only when it is needed.
+/// Without a daemon, commands for a peer wait until it is reached through its known
+/// addresses or the LAN, up to the request timeout.
+#[derive(Subcommand, Debug, Clone)]
+pub enum CliCommand {
+    /// Send a text message, returns once the peer acknowledged it and prints its id
+    Send { peer: PeerId, text: String },
+    /// List contacts as `peer id`, `name` and `unread count`, tab separated
+    Contacts,
+    /// Send and answer friend requests
+    Friends {
+        #[command(subcommand)]
+        command: FriendsCommand,
+    },
+    /// Our own identity
+    Identity {
+        #[command(subcommand)]
+        command: IdentityCommand,
+    },
+    /// Read and change settings, by the names shown in the settings screen
+    Settings {
+        #[command(subcommand)]
+        command: SettingsCommand,
+    },
+}
+#[derive(Subcommand, Debug, Clone)]
+pub enum FriendsCommand {
+    /// Send a friend request
+    Add { peer: PeerId },
+    /// Accept a friend request
+    Accept { peer: PeerId },
+    /// Decline a friend request
+    Deny { peer: PeerId },
+}
+#[derive(Subcommand, Debug, Clone)]
+pub enum IdentityCommand {
+    /// Print our peer id
+    Show,
+}
+#[derive(Subcommand, Debug, Clone)]
+pub enum SettingsCommand {
+    /// Print a setting, e.g. `settings get name`
+    Get { name: SettingName },
+    /// Change a setting, e.g. `settings set name Alice`
+    Set { name: SettingName, value: String },
+}
+pub async fn run(
+    command: CliCommand,
+    config: &Config,
+    settings: HashMap<SettingName, Setting>,
+    db: Database,
+) -> ExitCode {
+    let status = match execute(command, config, settings, db.clone()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!("command failed: {err}");
+            eprintln!("p2pchat: {err}");
+            ExitCode::FAILURE
+        }
+    };
+    if let Err(err) = db.close().await {
+        tracing::error!("failed to flush the database: {err}");
+    }
+    status
+}
+async fn execute(
+    command: CliCommand,
+    config: &Config,
+    mut settings: HashMap<SettingName, Setting>,
+    db: Database,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        CliCommand::Send { peer, text } => {
+            let body = MessageBody::Text {
+                content: text,
+                reply_to: None,
+            };
+            #[cfg(unix)]
+            if let Some(id) = daemon::request(Call::Send {
+                peer,
+                body: body.clone(),
+            })
+            .await?
+            {
+                println!("{}", id?.as_str().unwrap_or_default());
+                return Ok(());
+            }
+            let mut node = start_network(config, settings, db, peer).await?;
+            let sent = node.client.send(peer, body).await.result().await;
+            node.stop().await;
+            println!("{}", sent?);
+        }
+        CliCommand::Contacts => {
+            #[cfg(unix)]
+            if let Some(contacts) = daemon::request(Call::Contacts).await? {
+                for contact in contacts?.as_array().into_iter().flatten() {
+                    println!(
+                        "{}",
+                        contact_line(
+                            contact["peer_id"].as_str().unwrap_or_default(),
+                            contact["name"].as_str().unwrap_or_default(),
+                            &contact["unread"],
+                        )
+                    );
+                }
+                return Ok(());
+            }
+            for line in contact_lines(&db).await? {
+                println!("{line}");
+            }
+        }
+        CliCommand::Friends { command } => {
+            #[cfg(unix)]
+            {
+                let call = match command {
+                    FriendsCommand::Add { peer } => Call::AddFriend { peer },
+                    FriendsCommand::Accept { peer } => Call::AnswerFriend { peer, accept: true },
+                    FriendsCommand::Deny { peer } => Call::AnswerFriend {
+                        peer,
+                        accept: false,
+                    },
+                };
+                if let Some(result) = daemon::request(call).await? {
+                    result?;
+                    return Ok(());
+                }
+            }
+            let peer = match command {
+                FriendsCommand::Add { peer }
+                | FriendsCommand::Accept { peer }
+                | FriendsCommand::Deny { peer } => peer,
+            };
+            let mut node = start_network(config, settings, db, peer).await?;
+            let answered = match command {
+                FriendsCommand::Add { peer } => node.client.send_friend_request(peer).await,
+                FriendsCommand::Accept { peer } => node.client.accept_friend_req(peer).await,
+                FriendsCommand::Deny { peer } => node.client.deny_friend_req(peer).await,
+            };
+            node.stop().await;
+            answered?;
+        }
+        CliCommand::Identity {
+            command: IdentityCommand::Show,
+        } => {
+            let keys = crate::identity::load()?.ok_or(NO_IDENTITY)?;
+            println!("{}", keys.public().to_peer_id());
+        }
+        CliCommand::Settings {
+            command: SettingsCommand::Get { name },
+        } => {
+            println!("{}", setting(&settings, name));
+        }
+        CliCommand::Settings {
+            command: SettingsCommand::Set { name, value },
+        } => {
+            // the daemon keeps its settings in memory, it has to make the change
+            #[cfg(unix)]
+            if let Some(result) = daemon::request(Call::SetSetting {
+                name,
+                value: value.clone(),
+            })
+            .await?
+            {
+                result?;
+                return Ok(());
+            }
+            set_setting(&mut settings, name, &value)?;
+            Settings::save(&settings).await?;
+        }
+    }
+    Ok(())
+}
+const NO_IDENTITY: &str = "no identity yet, run p2pchat once to set one up";
+
+/// Tab separated, so scripts can split it even when names have spaces.
+fn contact_line(peer_id: &str, name: &str, unread: &dyn std::fmt::Display) -> String {
+    format!("{peer_id}\t{name}\t{unread}")
+}
+async fn contact_lines(db: &Database) -> Result<Vec<String>, Box<dyn Error>> {
+    let contacts = db.contact_summaries().await?;
+    Ok(contacts
+        .iter()
+        .map(|c| contact_line(&c.peer_id.to_string(), &c.name, &c.unread))
+        .collect())
+}
+/// The value of a setting, its default if it was never set.
+fn setting(settings: &HashMap<SettingName, Setting>, name: SettingName) -> SettingValue {
+    settings
+        .get(&name)
+        .map(|setting| setting.get_value().clone())
+        .unwrap_or_else(|| name.spec().default.clone())
+}
+/// Parses and validates `value` for the setting, which the caller still has to save.
+fn set_setting(
+    settings: &mut HashMap<SettingName, Setting>,
+    name: SettingName,
+    value: &str,
+) -> Result<(), Box<dyn Error>> {
+    let value = SettingValue::parse(name.spec().kind, value)?;
+    settings
+        .entry(name)
+        .or_insert_with(|| name.spec().default_setting())
+        .set_value(name, value)?;
+    Ok(())
+}
+/// Starts the network for a single command and waits until `peer` is connected.
+async fn start_network(
+    config: &Config,
+    settings: HashMap<SettingName, Setting>,
+    db: Database,
+    peer: PeerId,
+) -> Result<Node, Box<dyn Error>> {
+    let keys = crate::identity::load()?.ok_or(NO_IDENTITY)?;
+    let network_config = config.network(&settings);
+    let timeout = network_config.request_timeout;
+    let mut node = Node::start(network_config, settings, db, keys).await?;
+    if !connected(&mut node, peer, timeout).await {
+        node.stop().await;
+        return Err(format!("couldn't reach {peer}, is it online?").into());
+    }
+    Ok(node)
+}
+/// Waits for the event loop to connect to `peer`, by redialing contacts or finding it
+/// on the LAN. A request sent before then fails right away, nobody knows where to send it.
+async fn connected(node: &mut Node, peer: PeerId, timeout: Duration) -> bool {
+    let connecting = async {
+        while let Some(event) = node.events.recv().await {
+            if matches!(event, Event::PeerConnected { peer: connected, .. } if connected == peer) {
+                return true;
+            }
+        }
+        false
+    };
+    tokio::time::timeout(timeout, connecting)
+        .await
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::network::dial::DialTarget;
+    use libp2p::Multiaddr;
+    use libp2p::identity::Keypair;
+
+    async fn node(listen: Multiaddr) -> (Node, PeerId) {
+        let keys = Keypair::generate_ed25519();
+        let id = keys.public().to_peer_id();
+        let db = Database::open_in_memory().await.unwrap();
+        let config = NetworkConfig::local(vec![listen]);
+        (
+            Node::start(config, HashMap::new(), db, keys).await.unwrap(),
+            id,
+        )
+    }
+
+    #[test]
+    fn settings_are_read_as_set_and_default_otherwise() {
+        let mut settings = HashMap::new();
+        assert_eq!(
+            setting(&settings, SettingName::TcpPort),
+            SettingName::TcpPort.spec().default
+        );
+        set_setting(&mut settings, SettingName::TcpPort, "4001").unwrap();
+        set_setting(&mut settings, SettingName::Name, "Alice").unwrap();
+        assert_eq!(
+            setting(&settings, SettingName::TcpPort),
+            SettingValue::Int(4001)
+        );
+        assert_eq!(setting(&settings, SettingName::Name).to_string(), "Alice");
+    }
+    #[test]
+    fn invalid_values_leave_the_setting_alone() {
+        let mut settings = HashMap::new();
+        set_setting(&mut settings, SettingName::TcpPort, "4001").unwrap();
+        assert!(set_setting(&mut settings, SettingName::TcpPort, "many").is_err());
+        assert!(set_setting(&mut settings, SettingName::TcpPort, "70000").is_err());
+        assert_eq!(
+            setting(&settings, SettingName::TcpPort),
+            SettingValue::Int(4001)
+        );
+    }
+    #[tokio::test]
+    async fn contacts_are_listed_one_per_line() {
+        let db = Database::open_in_memory().await.unwrap();
+        assert!(contact_lines(&db).await.unwrap().is_empty());
+        let alice = PeerId::random();
+        db.ensure_contact(alice).await.unwrap();
+        db.name_contact(alice, "Alice Liddell".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            contact_lines(&db).await.unwrap(),
+            [format!("{alice}\tAlice Liddell\t0")]
+        );
+    }
+    #[tokio::test]
+    async fn commands_wait_for_the_peer_to_connect() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listen: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let (mut alice, alice_id) = node(listen.clone()).await;
+        let (mut bob, bob_id) = node("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await;
+
+        let nobody = PeerId::random();
+        assert!(!connected(&mut alice, nobody, Duration::from_millis(200)).await);
+
+        bob.client
+            .dial(DialTarget::Contact {
+                peer: alice_id,
+                addresses: vec![listen],
+            })
+            .await;
+        assert!(connected(&mut alice, bob_id, Duration::from_secs(10)).await);
+        alice.stop().await;
+        bob.stop().await;
+    }
+}
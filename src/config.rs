@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use libp2p::Multiaddr;
+
+use crate::cli::CliCommand;
+use crate::profile;
+use crate::settings::{Dirs, Setting, SettingName, SettingValue};
+
+/// Options given on the command line or through `P2PCHAT_*` environment variables.
+/// They override the settings file without being written to it, flags win over the environment.
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "Peer to peer chat in the terminal")]
+pub struct Config {
+    /// Directory for settings and the identity
+    #[arg(long, env = "P2PCHAT_CONFIG_DIR")]
+    pub config_dir: Option<PathBuf>,
+    /// Directory for the message database
+    #[arg(long, env = "P2PCHAT_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Named profile with its own identity, settings, database and logs,
+    /// lets isolated instances run side by side
+    #[arg(long, env = "P2PCHAT_PROFILE", value_parser = profile::parse_name)]
+    pub profile: Option<String>,
+    /// Log filter, e.g. `info` or `p2pchat=debug,libp2p=warn`
+    #[arg(long, env = "P2PCHAT_LOG", default_value = "info")]
+    pub log: String,
+    /// Addresses to listen on instead of the transport settings, can be repeated
+    #[arg(long = "listen", env = "P2PCHAT_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<Multiaddr>,
+    /// Find peers on the local network with mDNS
+    #[arg(long, env = "P2PCHAT_MDNS")]
+    pub mdns: Option<bool>,
+    /// Find peers through the DHT
+    #[arg(long, env = "P2PCHAT_DHT")]
+    pub dht: Option<bool>,
+    /// Relays to be reachable through instead of the relay setting, can be repeated
+    #[arg(long = "relay", env = "P2PCHAT_RELAY", value_delimiter = ',')]
+    pub relays: Vec<Multiaddr>,
+    /// Relay connections for other peers
+    #[arg(long, env = "P2PCHAT_RELAY_SERVER")]
+    pub relay_server: Option<bool>,
+    /// Addresses this node is reachable at from outside, e.g. behind a port forward,
+    /// handed out in contact cards and relay reservations. Can be repeated
+    #[arg(
+        long = "external-address",
+        env = "P2PCHAT_EXTERNAL_ADDRESS",
+        value_delimiter = ','
+    )]
+    pub external_addresses: Vec<Multiaddr>,
+    /// Run without a terminal, controlled through a unix socket in the config directory.
+    /// Starting the TUI while this runs attaches to it
+    #[arg(long, env = "P2PCHAT_HEADLESS")]
+    pub headless: bool,
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+/// What the network is started with, after layering the config over the settings.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub listen: Vec<Multiaddr>,
+    /// `listen` is what the settings say, so ports the OS picks may be saved to them
+    pub listen_from_settings: bool,
+    pub mdns: bool,
+    pub mdns_interval: Duration,
+    pub dht: bool,
+    pub idle_timeout: Duration,
+    pub request_timeout: Duration,
+    pub max_streams: usize,
+    pub relays: Vec<Multiaddr>,
+    pub relay_server: bool,
+    pub external_addresses: Vec<Multiaddr>,
+}
+#[cfg(test)]
+impl NetworkConfig {
+    /// Listens on `listen` only, with nothing found or relayed unless the test sets it.
+    pub(crate) fn local(listen: Vec<Multiaddr>) -> Self {
+        NetworkConfig {
+            listen,
+            listen_from_settings: false,
+            mdns: false,
+            mdns_interval: Duration::from_secs(60),
+            dht: false,
+            idle_timeout: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(5),
+            max_streams: 16,
+            relays: vec![],
+            relay_server: false,
+            external_addresses: vec![],
+        }
+    }
+}
+impl Config {
+    pub fn dirs(&self) -> Dirs {
+        let defaults = Dirs::default();
+        let config = self.config_dir.clone().unwrap_or(defaults.config);
+        // the database lives next to the settings unless told otherwise
+        let data = self.data_dir.clone().unwrap_or_else(|| config.clone());
+        let profiles = config.join("profiles");
+        match self.profile() {
+            profile::DEFAULT => Dirs {
+                config,
+                data,
+                profiles,
+            },
+            profile => Dirs {
+                config: profiles.join(profile),
+                data: data.join("profiles").join(profile),
+                profiles,
+            },
+        }
+    }
+    pub fn profile(&self) -> &str {
+        self.profile.as_deref().unwrap_or(profile::DEFAULT)
+    }
+    pub fn network(&self, settings: &HashMap<SettingName, Setting>) -> NetworkConfig {
+        let flag = |name| {
+            matches!(
+                settings.get(&name).map(|s| s.get_value()),
+                Some(SettingValue::Bool(true))
+            )
+        };
+        // settings were validated on load, so these are in range
+        let int = |name: SettingName| match settings.get(&name).map(|s| s.get_value()) {
+            Some(SettingValue::Int(value)) => *value as u64,
+            _ => match name.spec().default {
+                SettingValue::Int(value) => value as u64,
+                _ => 0,
+            },
+        };
+        let listen = match self.listen.is_empty() {
+            false => self.listen.clone(),
+            // Listen on all interfaces, port 0 lets the OS assign one
+            true => {
+                let mut hosts = vec!["/ip4/0.0.0.0"];
+                if flag(SettingName::Ipv6) {
+                    hosts.push("/ip6/::");
+                }
+                let mut listen = Vec::new();
+                for host in hosts {
+                    if flag(SettingName::ListenQuic) {
+                        let port = int(SettingName::QuicPort);
+                        listen.push(format!("{host}/udp/{port}/quic-v1"));
+                    }
+                    if flag(SettingName::ListenTcp) {
+                        let port = int(SettingName::TcpPort);
+                        listen.push(format!("{host}/tcp/{port}"));
+                    }
+                }
+                listen
+                    .iter()
+                    .map(|addr| addr.parse().expect("listen address to be valid"))
+                    .collect()
+            }
+        };
+        let relays = match self.relays.is_empty() {
+            false => self.relays.clone(),
+            true => match settings.get(&SettingName::Relays).map(|s| s.get_value()) {
+                Some(SettingValue::String(Some(relays))) => relays
+                    .split(',')
+                    .filter_map(|relay| match relay.trim().parse() {
+                        Ok(relay) => Some(relay),
+                        Err(err) => {
+                            tracing::warn!("ignoring relay {relay}: {err}");
+                            None
+                        }
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            },
+        };
+        NetworkConfig {
+            listen,
+            listen_from_settings: self.listen.is_empty(),
+            mdns: self.mdns.unwrap_or_else(|| flag(SettingName::LanDiscovery)),
+            mdns_interval: Duration::from_secs(int(SettingName::MdnsInterval)),
+            dht: self.dht.unwrap_or_else(|| flag(SettingName::Dht)),
+            idle_timeout: Duration::from_secs(int(SettingName::IdleTimeout)),
+            request_timeout: Duration::from_secs(int(SettingName::RequestTimeout)),
+            max_streams: int(SettingName::MaxStreams) as usize,
+            relays,
+            relay_server: self
+                .relay_server
+                .unwrap_or_else(|| flag(SettingName::RelayServer)),
+            external_addresses: self.external_addresses.clone(),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::registry;
+
+    fn settings(overrides: &[(SettingName, SettingValue)]) -> HashMap<SettingName, Setting> {
+        let mut settings: HashMap<_, _> = registry()
+            .iter()
+            .map(|spec| (spec.name, spec.default_setting()))
+            .collect();
+        for (name, value) in overrides {
+            settings
+                .get_mut(name)
+                .unwrap()
+                .set_value(*name, value.clone())
+                .unwrap();
+        }
+        settings
+    }
+    fn config(args: &[&str]) -> Config {
+        Config::try_parse_from(std::iter::once("p2pchat").chain(args.iter().copied())).unwrap()
+    }
+
+    // Only `env_sits_between_flags_and_settings` sets variables, the other tests
+    // leave out what it sets since tests share the environment.
+    #[test]
+    fn settings_are_used_unless_a_flag_is_given() {
+        let settings = settings(&[
+            (SettingName::Dht, SettingValue::Bool(true)),
+            (SettingName::RelayServer, SettingValue::Bool(true)),
+            (SettingName::ListenQuic, SettingValue::Bool(false)),
+            (SettingName::TcpPort, SettingValue::Int(4001)),
+            (SettingName::RequestTimeout, SettingValue::Int(7)),
+        ]);
+        let network = config(&[]).network(&settings);
+        assert!(network.dht);
+        assert!(network.relay_server);
+        assert!(network.listen_from_settings);
+        assert_eq!(network.listen, ["/ip4/0.0.0.0/tcp/4001".parse().unwrap()]);
+        assert_eq!(network.request_timeout, Duration::from_secs(7));
+
+        let network = config(&[
+            "--dht=false",
+            "--relay-server=false",
+            "--listen=/ip4/127.0.0.1/tcp/0,/ip4/127.0.0.1/udp/0/quic-v1",
+        ])
+        .network(&settings);
+        assert!(!network.dht);
+        assert!(!network.relay_server);
+        assert!(!network.listen_from_settings);
+        assert_eq!(network.listen.len(), 2);
+        // settings without a flag still apply
+        assert_eq!(network.request_timeout, Duration::from_secs(7));
+    }
+    #[test]
+    fn missing_settings_fall_back_to_their_defaults() {
+        let network = config(&[]).network(&HashMap::new());
+        let default = |name: SettingName| match name.spec().default {
+            SettingValue::Int(value) => value as u64,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            network.request_timeout,
+            Duration::from_secs(default(SettingName::RequestTimeout))
+        );
+        assert_eq!(network.max_streams as u64, default(SettingName::MaxStreams));
+    }
+    #[test]
+    fn env_sits_between_flags_and_settings() {
+        let relay =
+            "/ip4/203.0.113.7/tcp/4001/p2p/12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE";
+        let settings = settings(&[
+            (SettingName::LanDiscovery, SettingValue::Bool(false)),
+            (
+                SettingName::Relays,
+                SettingValue::String(Some(relay.into())),
+            ),
+        ]);
+        let network = config(&[]).network(&settings);
+        assert!(!network.mdns);
+        assert_eq!(network.relays, [relay.parse().unwrap()]);
+
+        // SAFETY: no other test reads or writes these variables
+        unsafe {
+            std::env::set_var("P2PCHAT_MDNS", "true");
+            std::env::set_var("P2PCHAT_RELAY", "/ip4/127.0.0.1/tcp/1,/ip4/127.0.0.1/tcp/2");
+        }
+        let network = config(&[]).network(&settings);
+        assert!(network.mdns);
+        assert_eq!(network.relays.len(), 2);
+
+        let network = config(&["--mdns=false", "--relay=/ip4/127.0.0.1/tcp/3"]).network(&settings);
+        assert!(!network.mdns);
+        assert_eq!(network.relays, ["/ip4/127.0.0.1/tcp/3".parse().unwrap()]);
+
+        // SAFETY: as above
+        unsafe {
+            std::env::remove_var("P2PCHAT_MDNS");
+            std::env::remove_var("P2PCHAT_RELAY");
+        }
+    }
+}
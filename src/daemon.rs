@@ -0,0 +1,455 @@
+pub mod attach;
+pub mod rpc;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::{RwLock, broadcast};
+use tokio_util::sync::CancellationToken;
+
+use crate::daemon::rpc::{
+    Call, Incoming, Notification, Outcome, RemoteEvent, Request, Response, RpcError,
+};
+use crate::db::Database;
+use crate::network::{Client, Contact, Event};
+use crate::settings::{
+    SaveFile, Setting, SettingName, SettingValue, Settings, get_config_save_file_path,
+};
+
+/// Where the daemon of the current profile listens, next to its settings.
+pub fn socket_path() -> PathBuf {
+    get_config_save_file_path(SaveFile::Socket)
+}
+/// Connects to the daemon of this profile, `None` if none is running.
+pub async fn connect() -> std::io::Result<Option<UnixStream>> {
+    match UnixStream::connect(socket_path()).await {
+        Ok(stream) => Ok(Some(stream)),
+        // nothing running, or a socket left behind by a daemon that crashed
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+/// Makes a single call to the daemon of this profile, `None` if none is running.
+pub async fn request(call: Call) -> std::io::Result<Option<Result<Value, RpcError>>> {
+    let Some(stream) = connect().await? else {
+        return Ok(None);
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut request = json!(Request::new(0, call)).to_string();
+    request.push('\n');
+    writer.write_all(request.as_bytes()).await?;
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        // without a subscription only our response arrives
+        if let Ok(Incoming::Response(response)) = serde_json::from_str(&line) {
+            return Ok(Some(match response.outcome {
+                Outcome::Result(value) => Ok(value),
+                Outcome::Error(err) => Err(err),
+            }));
+        }
+    }
+    Err(std::io::ErrorKind::UnexpectedEof.into())
+}
+/// Runs the network for clients on the control socket instead of a terminal,
+/// e.g. scripts or a TUI attached to it.
+#[derive(Clone)]
+pub struct Daemon {
+    client: Client,
+    db: Database,
+    settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
+    events: broadcast::Sender<RemoteEvent>,
+}
+impl Daemon {
+    pub fn new(
+        client: Client,
+        db: Database,
+        settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
+    ) -> Self {
+        // subscribers that fall this far behind miss events
+        let (events, _) = broadcast::channel(256);
+        Daemon {
+            client,
+            db,
+            settings,
+            events,
+        }
+    }
+    /// Serves the control `socket` from [`bind`] until `token` is cancelled,
+    /// passing network events and the `contacts` the event loop finds on to
+    /// subscribed connections.
+    pub async fn serve(
+        &self,
+        socket: ControlSocket,
+        network_events: &mut mpsc::Receiver<Event>,
+        contacts: &mut UnboundedReceiver<Contact>,
+        token: CancellationToken,
+    ) -> std::io::Result<()> {
+        let path = socket_path();
+        tracing::info!("control socket at {}", path.display());
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                accepted = socket.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        tokio::spawn(self.clone().serve_connection(stream));
+                    }
+                    Err(err) => tracing::error!("failed to accept a control connection: {err}"),
+                },
+                Some(event) = network_events.recv() => self.publish(event),
+                Some(Contact { peer, name }) = contacts.recv() => {
+                    let _ = self.events.send(RemoteEvent::Contact { peer, name });
+                }
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+    fn publish(&self, event: Event) {
+        // sending only fails while nobody is subscribed
+        if let Some(text) = event.notice() {
+            let _ = self.events.send(RemoteEvent::Notice { text });
+        }
+        if let Some(event) = RemoteEvent::from_event(event) {
+            let _ = self.events.send(event);
+        }
+    }
+    async fn serve_connection(self, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        // responses and events go out in the order they are ready
+        let (line_tx, mut line_rx) = mpsc::unbounded_channel::<String>();
+        let writing = tokio::spawn(async move {
+            while let Some(mut line) = line_rx.recv().await {
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut lines = BufReader::new(reader).lines();
+        let mut subscribed = false;
+        while let Ok(Some(line)) = lines.next_line().await {
+            let request = match Request::parse(&line) {
+                Ok(request) => request,
+                Err((id, err)) => {
+                    let response = Response::new(id, Err(err));
+                    let _ = line_tx.send(json!(response).to_string());
+                    continue;
+                }
+            };
+            if matches!(request.call, Call::Subscribe) && !subscribed {
+                subscribed = true;
+                tokio::spawn(forward_events(self.events.subscribe(), line_tx.clone()));
+            }
+            let daemon = self.clone();
+            let line_tx = line_tx.clone();
+            // calls waiting on a peer don't hold up the others
+            tokio::spawn(async move {
+                let result = daemon.call(request.call).await;
+                if let Some(id) = request.id {
+                    let response = Response::new(Some(id), result);
+                    let _ = line_tx.send(json!(response).to_string());
+                }
+            });
+        }
+        writing.abort();
+    }
+    async fn call(&self, call: Call) -> Result<Value, RpcError> {
+        let mut client = self.client.clone();
+        match call {
+            Call::Identity => Ok(json!({ "peer_id": client.id })),
+            Call::Contacts => {
+                let contacts = self.db.contact_summaries().await?;
+                Ok(contacts
+                    .into_iter()
+                    .map(|contact| {
+                        json!({
+                            "peer_id": contact.peer_id,
+                            "name": contact.name,
+                            "pinned": contact.pinned,
+                            "unread": contact.unread,
+                            "last_message": contact.last_message.map(|last| json!({
+                                "sender": last.sender,
+                                "content": last.content,
+                                "deleted": last.deleted,
+                                "time": last.time,
+                            })),
+                        })
+                    })
+                    .collect())
+            }
+            Call::Send { peer, body } => Ok(json!(client.send(peer, body).await.result().await?)),
+            Call::Dial { target } => {
+                let target = target
+                    .parse()
+                    .map_err(|err| RpcError::new(RpcError::INVALID_PARAMS, err))?;
+                client.dial(target).await;
+                Ok(Value::Null)
+            }
+            Call::RequestName { peer } => Ok(json!(client.request_name(peer).await?)),
+            Call::VerifyName { peer, name } => Ok(json!(client.verify_name(peer, name).await?)),
+            Call::AddFriend { peer } => {
+                client.send_friend_request(peer).await?;
+                Ok(Value::Null)
+            }
+            Call::AnswerFriend { peer, accept } => {
+                match accept {
+                    true => client.accept_friend_req(peer).await?,
+                    false => client.deny_friend_req(peer).await?,
+                }
+                Ok(Value::Null)
+            }
+            Call::Typing { peer, typing } => {
+                client.set_typing(peer, typing).await;
+                Ok(Value::Null)
+            }
+            Call::SetSetting { name, value } => {
+                let value = SettingValue::parse(name.spec().kind, &value)?;
+                client.set_setting(name, value).await?;
+                Ok(Value::Null)
+            }
+            Call::ReloadSettings => {
+                *self.settings.write().await = Settings::load().await;
+                client.broadcast_presence().await;
+                Ok(Value::Null)
+            }
+            Call::ExportCard => {
+                client.export_contact_card().await;
+                Ok(Value::Null)
+            }
+            Call::ImportCard { card } => {
+                client.import_contact_card(card).await;
+                Ok(Value::Null)
+            }
+            // events are forwarded by the connection
+            Call::Subscribe => Ok(Value::Null),
+        }
+    }
+}
+/// The daemon's listening socket, the profile stays locked for as long as it lives.
+pub struct ControlSocket {
+    listener: UnixListener,
+    _lock: std::fs::File,
+}
+/// Listens on `path`, taking over a socket left behind by a daemon that didn't stop cleanly.
+/// Fails if a daemon already runs there, so it's called before the node starts.
+pub fn bind(path: &Path) -> std::io::Result<ControlSocket> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // the lock goes away with the process, a socket file doesn't, so only the lock
+    // tells whether the socket is still in use. The lock file itself stays.
+    let lock = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path.with_extension("lock"))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "a daemon is already running for this profile",
+            ));
+        }
+        Err(std::fs::TryLockError::Error(err)) => return Err(err),
+    }
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    // whoever can connect controls the node, so the socket is bound in a directory
+    // only we can enter and moved into place once it's ours alone
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    let private = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&private);
+    Ok(ControlSocket {
+        listener: listener?,
+        _lock: lock,
+    })
+}
+async fn forward_events(
+    mut events: broadcast::Receiver<RemoteEvent>,
+    line_tx: mpsc::UnboundedSender<String>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let notification = json!(Notification::new(event)).to_string();
+                if line_tx.send(notification).is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::info!("a subscriber missed {missed} events");
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libp2p::PeerId;
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::network::Command;
+    use crate::network::friends::{FriendCommand, FriendResponse};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2pchat-daemon-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn only_we_can_connect_to_the_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir();
+        let path = dir.join("daemon.sock");
+        let _listener = bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // nothing is left of the directory it was bound in, only the lock is next to it
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["daemon.lock", "daemon.sock"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[tokio::test]
+    async fn a_second_daemon_is_refused() {
+        let dir = temp_dir();
+        let path = dir.join("daemon.sock");
+        let _listener = bind(&path).unwrap();
+        let err = bind(&path).err().expect("the socket to be taken");
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        // the lock refuses it even once the socket file is gone
+        std::fs::remove_file(&path).unwrap();
+        let err = bind(&path).err().expect("the profile to be locked");
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[tokio::test]
+    async fn sockets_left_behind_are_taken_over() {
+        let dir = temp_dir();
+        let path = dir.join("daemon.sock");
+        drop(bind(&path).unwrap());
+        assert!(path.exists());
+        let _listener = bind(&path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A connection to a daemon whose commands end up in the returned receiver.
+    struct Connection {
+        lines: Lines<BufReader<ReadHalf<UnixStream>>>,
+        writer: WriteHalf<UnixStream>,
+        commands: mpsc::Receiver<Command>,
+        id: PeerId,
+    }
+    impl Connection {
+        async fn open() -> Self {
+            let id = PeerId::random();
+            let settings = Arc::new(RwLock::new(HashMap::new()));
+            let (client, commands) =
+                Client::forwarding(id, settings.clone(), Duration::from_secs(5));
+            let db = Database::open_in_memory().await.unwrap();
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            tokio::spawn(Daemon::new(client, db, settings).serve_connection(theirs));
+            let (reader, writer) = tokio::io::split(ours);
+            Connection {
+                lines: BufReader::new(reader).lines(),
+                writer,
+                commands,
+                id,
+            }
+        }
+        async fn call(&mut self, line: &str) -> Value {
+            self.writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+            self.response().await
+        }
+        async fn response(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered_with_their_id() {
+        let mut connection = Connection::open().await;
+        let response = connection
+            .call(r#"{"jsonrpc": "2.0", "id": 1, "method": "identity"}"#)
+            .await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["peer_id"], connection.id.to_string());
+        let response = connection
+            .call(r#"{"jsonrpc": "2.0", "id": 2, "method": "contacts"}"#)
+            .await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["result"], json!([]));
+    }
+    #[tokio::test]
+    async fn invalid_calls_are_answered_with_an_error() {
+        let mut connection = Connection::open().await;
+        let response = connection.call("not json").await;
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], RpcError::PARSE);
+        let response = connection
+            .call(r#"{"jsonrpc": "2.0", "id": 3, "method": "dial", "params": {"target": "?"}}"#)
+            .await;
+        assert_eq!(response["id"], 3);
+        assert_eq!(response["error"]["code"], RpcError::INVALID_PARAMS);
+        // the connection is still usable afterwards
+        let response = connection
+            .call(r#"{"jsonrpc": "2.0", "id": 4, "method": "identity"}"#)
+            .await;
+        assert_eq!(response["id"], 4);
+    }
+    #[tokio::test]
+    async fn calls_go_to_the_network() {
+        let mut connection = Connection::open().await;
+        let peer = PeerId::random();
+        let request = json!(Request::new(5, Call::AddFriend { peer })).to_string();
+        connection
+            .writer
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .unwrap();
+        let Some(Command::FriendCommand(FriendCommand::AddFriend { peer: to, reply })) =
+            connection.commands.recv().await
+        else {
+            panic!("expected a friend request");
+        };
+        assert_eq!(to, peer);
+        reply.send(Ok(FriendResponse::AddFriendAck)).unwrap();
+        let response = connection.response().await;
+        assert_eq!(response["id"], 5);
+        assert_eq!(response["result"], Value::Null);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use libp2p::PeerId;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::RwLock;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::daemon;
+use crate::daemon::rpc::{Call, Incoming, Outcome, RemoteEvent, Request};
+use crate::network::chat::ChatCommand;
+use crate::network::contact_card::ContactCardCommand;
+use crate::network::dial::DialCommand;
+use crate::network::friends::{FriendCommand, FriendResponse};
+use crate::network::pending::{NetError, Reply};
+use crate::network::presence::PresenceCommand;
+use crate::network::{Client, Command};
+use crate::settings::{Setting, SettingName};
+use crate::tui;
+
+/// Hands the daemon's answer to whoever sent the command
+type Responder = Box<dyn FnOnce(Result<Value, NetError>) + Send>;
+
+/// Connects to the daemon of this profile if one is running. Commands sent through the
+/// returned client go to the daemon and its events to `tui_tx`, until the task stops
+/// on [`Client::shutdown`]. The daemon keeps running after we detach.
+pub async fn connect(
+    settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
+    tui_tx: UnboundedSender<tui::Event>,
+    request_timeout: Duration,
+) -> std::io::Result<Option<(Client, JoinHandle<()>)>> {
+    let Some(stream) = daemon::connect().await? else {
+        return Ok(None);
+    };
+    let (reader, writer) = stream.into_split();
+    let mut remote = Remote {
+        writer,
+        lines: BufReader::new(reader).lines(),
+        next_id: 0,
+        pending: HashMap::new(),
+        connected: true,
+        tui_tx,
+    };
+    let id = remote.identity().await?;
+    remote.send(Call::Subscribe, ignore("subscribe")).await;
+    let (client, commands) = Client::forwarding(id, settings, request_timeout);
+    Ok(Some((client, tokio::spawn(remote.run(commands)))))
+}
+struct Remote {
+    writer: OwnedWriteHalf,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    next_id: u64,
+    /// Calls waiting for their response, by request id
+    pending: HashMap<u64, Responder>,
+    /// Whether the daemon is still there
+    connected: bool,
+    tui_tx: UnboundedSender<tui::Event>,
+}
+impl Remote {
+    /// Asks for the daemon's peer id before anything else can arrive.
+    async fn identity(&mut self) -> std::io::Result<PeerId> {
+        let request = json!(Request::new(self.next_id, Call::Identity)).to_string();
+        self.next_id += 1;
+        self.write(request).await?;
+        let invalid = |err| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        let Some(line) = self.lines.next_line().await? else {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        };
+        match serde_json::from_str::<Incoming>(&line).map_err(|err| invalid(err.to_string()))? {
+            Incoming::Response(response) => match response.outcome {
+                Outcome::Result(value) => serde_json::from_value(value["peer_id"].clone())
+                    .map_err(|err| invalid(err.to_string())),
+                Outcome::Error(err) => Err(invalid(err.to_string())),
+            },
+            Incoming::Notification(_) => Err(invalid("expected our identity".to_string())),
+        }
+    }
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Shutdown(reply)) => {
+                        let _ = reply.send(Ok(()));
+                        break;
+                    }
+                    Some(command) => self.forward(command).await,
+                    None => break,
+                },
+                line = self.lines.next_line(), if self.connected => match line {
+                    Ok(Some(line)) => self.receive(&line),
+                    _ => self.disconnected(),
+                },
+            }
+        }
+    }
+    async fn forward(&mut self, command: Command) {
+        let (call, respond) = match command {
+            Command::ChatCommand(ChatCommand::SendMessage {
+                receiver,
+                body,
+                reply,
+                ..
+            }) => (
+                Call::Send {
+                    peer: receiver,
+                    body,
+                },
+                respond_with(reply, |id| id),
+            ),
+            Command::FriendCommand(FriendCommand::RequestName { peer, reply }) => (
+                Call::RequestName { peer },
+                respond_with(reply, |name| FriendResponse::RequestName { name }),
+            ),
+            Command::FriendCommand(FriendCommand::VerifyName { name, peer, reply }) => (
+                Call::VerifyName { peer, name },
+                respond_with(reply, FriendResponse::VerifyName),
+            ),
+            Command::FriendCommand(FriendCommand::AddFriend { peer, reply }) => (
+                Call::AddFriend { peer },
+                respond_with(reply, |()| FriendResponse::AddFriendAck),
+            ),
+            Command::FriendCommand(FriendCommand::AcceptFriend {
+                peer,
+                decision,
+                reply,
+            }) => (
+                Call::AnswerFriend {
+                    peer,
+                    accept: decision,
+                },
+                respond_with(reply, |()| FriendResponse::AcceptFriendAck),
+            ),
+            Command::CallCommand(_) => {
+                // the audio streams end at the daemon
+                let notice = "calls aren't available while attached to a daemon".to_string();
+                let _ = self.tui_tx.send(tui::Event::Notice(notice));
+                return;
+            }
+            // the settings file was saved by us, the daemon rereads it
+            Command::PresenceCommand(PresenceCommand::Broadcast) => {
+                (Call::ReloadSettings, ignore("reload settings"))
+            }
+            Command::PresenceCommand(PresenceCommand::Typing { peer, typing }) => {
+                (Call::Typing { peer, typing }, ignore("send typing"))
+            }
+            Command::DialCommand(DialCommand::Dial { target }) => (
+                Call::Dial {
+                    target: target.to_string(),
+                },
+                ignore("dial"),
+            ),
+            Command::ContactCardCommand(ContactCardCommand::Export) => {
+                (Call::ExportCard, ignore("export the contact card"))
+            }
+            Command::ContactCardCommand(ContactCardCommand::Import { card }) => {
+                (Call::ImportCard { card }, ignore("import the contact card"))
+            }
+            Command::Shutdown(_) => unreachable!("shutdown to stop the relay before forwarding"),
+        };
+        self.send(call, respond).await;
+    }
+    async fn send(&mut self, call: Call, respond: Responder) {
+        if !self.connected {
+            respond(Err(NetError::Closed));
+            return;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        match self.write(json!(Request::new(id, call)).to_string()).await {
+            Ok(()) => {
+                self.pending.insert(id, respond);
+            }
+            Err(_) => {
+                respond(Err(NetError::Closed));
+                self.disconnected();
+            }
+        }
+    }
+    async fn write(&mut self, mut line: String) -> std::io::Result<()> {
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await
+    }
+    fn receive(&mut self, line: &str) {
+        match serde_json::from_str::<Incoming>(line) {
+            Ok(Incoming::Response(response)) => {
+                let Some(respond) = response.id.and_then(|id| self.pending.remove(&id)) else {
+                    return;
+                };
+                match response.outcome {
+                    Outcome::Result(value) => respond(Ok(value)),
+                    Outcome::Error(err) => respond(Err(err.into())),
+                }
+            }
+            Ok(Incoming::Notification(notification)) => {
+                if let Some(event) = tui_event(notification.params) {
+                    let _ = self.tui_tx.send(event);
+                }
+            }
+            Err(err) => tracing::info!("unreadable message from the daemon: {err}"),
+        }
+    }
+    /// Fails everything still waiting, later commands fail right away.
+    fn disconnected(&mut self) {
+        self.connected = false;
+        for (_, respond) in self.pending.drain() {
+            respond(Err(NetError::Closed));
+        }
+        let notice = "the daemon stopped, restart to reconnect".to_string();
+        let _ = self.tui_tx.send(tui::Event::Notice(notice));
+    }
+}
+/// Answers `reply` with the result decoded as `T` and turned into what the command expects.
+fn respond_with<T, U>(reply: Reply<U>, into: impl FnOnce(T) -> U + Send + 'static) -> Responder
+where
+    T: DeserializeOwned,
+    U: Send + 'static,
+{
+    Box::new(move |result| {
+        let result = result.and_then(|value| {
+            serde_json::from_value(value).map_err(|_| NetError::UnexpectedResponse)
+        });
+        let _ = reply.send(result.map(into));
+    })
+}
+/// For commands nobody waits on, failures are only logged.
+fn ignore(action: &'static str) -> Responder {
+    Box::new(move |result| {
+        if let Err(err) = result {
+            tracing::info!("the daemon failed to {action}: {err}");
+        }
+    })
+}
+/// What the TUI shows of a daemon event, it reads messages from the shared database.
+fn tui_event(event: RemoteEvent) -> Option<tui::Event> {
+    let event = match event {
+        RemoteEvent::Message { peer, message } => {
+            tui::Event::MessageReceived(tui::types::Message::received(&message, peer)?)
+        }
+        RemoteEvent::Contact { peer, name } => tui::Event::AddContact(tui::types::Contact {
+            peer_id: peer,
+            name,
+        }),
+        RemoteEvent::Presence {
+            peer,
+            status,
+            message,
+        } => tui::Event::Presence(peer, tui::types::Presence { status, message }),
+        RemoteEvent::Typing { peer, typing } => tui::Event::Typing(peer, typing),
+        RemoteEvent::ConversationUpdated { peer } => tui::Event::ConversationUpdated(peer),
+        RemoteEvent::Route { peer, route } => tui::Event::Route(peer, route),
+        RemoteEvent::ContactCard { token } => tui::Event::ContactCard(token),
+        RemoteEvent::Notice { text } => tui::Event::Notice(text),
+        // deliveries aren't shown, friend requests come with a notice
+        RemoteEvent::Delivered { .. } | RemoteEvent::FriendRequest { .. } => return None,
+    };
+    Some(event)
+}
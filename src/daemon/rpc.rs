@@ -0,0 +1,397 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::network::Event;
+use crate::network::chat::{Message, MessageBody};
+use crate::network::contact_card::ContactCard;
+use crate::network::friends::FriendRequest;
+use crate::network::nat::Route;
+use crate::network::pending::NetError;
+use crate::network::presence::UserStatus;
+use crate::settings::{SettingError, SettingName};
+
+// The control API is JSON-RPC 2.0 with one message per line
+pub const VERSION: &str = "2.0";
+
+/// A call to the daemon, e.g. `{"jsonrpc": "2.0", "id": 1, "method": "contacts"}`.
+/// Calls without an id get no response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub call: Call,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Call {
+    /// Our peer id
+    Identity,
+    /// Every contact with their unread count and latest message
+    Contacts,
+    /// Resolves to the message id once `peer` acknowledged it
+    Send {
+        peer: PeerId,
+        body: MessageBody,
+    },
+    /// The outcome arrives as a notice
+    Dial {
+        target: String,
+    },
+    RequestName {
+        peer: PeerId,
+    },
+    VerifyName {
+        peer: PeerId,
+        name: String,
+    },
+    AddFriend {
+        peer: PeerId,
+    },
+    AnswerFriend {
+        peer: PeerId,
+        accept: bool,
+    },
+    Typing {
+        peer: PeerId,
+        typing: bool,
+    },
+    /// `value` is parsed like input in the settings screen
+    SetSetting {
+        name: SettingName,
+        value: String,
+    },
+    /// Picks up the settings file after another process saved it
+    /// and tells peers our status
+    ReloadSettings,
+    /// The card arrives as a `contact_card` event
+    ExportCard,
+    ImportCard {
+        card: ContactCard,
+    },
+    /// Starts sending events to this connection
+    Subscribe,
+}
+impl Request {
+    pub fn new(id: u64, call: Call) -> Self {
+        Request {
+            jsonrpc: VERSION.to_string(),
+            id: Some(id),
+            call,
+        }
+    }
+    /// Reads one line of the control socket, failing with the id if that much can be read.
+    pub fn parse(line: &str) -> Result<Self, (Option<u64>, RpcError)> {
+        let value = serde_json::from_str::<Value>(line)
+            .map_err(|err| (None, RpcError::new(RpcError::PARSE, err)))?;
+        let id = value["id"].as_u64();
+        if value["jsonrpc"] != VERSION || !value["method"].is_string() {
+            let err = RpcError::new(RpcError::INVALID_REQUEST, "not a JSON-RPC 2.0 request");
+            return Err((id, err));
+        }
+        serde_json::from_value(value).map_err(|err| {
+            // the method decides which params are expected, so it's checked first
+            let code = match err.to_string().starts_with("unknown variant") {
+                true => RpcError::METHOD_NOT_FOUND,
+                false => RpcError::INVALID_PARAMS,
+            };
+            (id, RpcError::new(code, err))
+        })
+    }
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+/// Serialized as the `result` or `error` field of the response
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+impl Response {
+    pub fn new(id: Option<u64>, result: Result<Value, RpcError>) -> Self {
+        Response {
+            jsonrpc: VERSION.to_string(),
+            id,
+            outcome: match result {
+                Ok(value) => Outcome::Result(value),
+                Err(err) => Outcome::Error(err),
+            },
+        }
+    }
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    /// Why a request to a peer failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<NetError>,
+}
+impl RpcError {
+    pub const PARSE: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const NETWORK: i64 = -32000;
+    pub const DATABASE: i64 = -32001;
+    pub const SETTINGS: i64 = -32002;
+
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl std::error::Error for RpcError {}
+impl From<NetError> for RpcError {
+    fn from(err: NetError) -> Self {
+        RpcError {
+            code: RpcError::NETWORK,
+            message: err.to_string(),
+            data: Some(err),
+        }
+    }
+}
+impl From<RpcError> for NetError {
+    fn from(err: RpcError) -> Self {
+        err.data.unwrap_or(NetError::Failed(err.message))
+    }
+}
+impl From<tokio_rusqlite::Error> for RpcError {
+    fn from(err: tokio_rusqlite::Error) -> Self {
+        RpcError::new(RpcError::DATABASE, err)
+    }
+}
+impl From<SettingError> for RpcError {
+    fn from(err: SettingError) -> Self {
+        match err {
+            SettingError::Save(_) => RpcError::new(RpcError::SETTINGS, err),
+            _ => RpcError::new(RpcError::INVALID_PARAMS, err),
+        }
+    }
+}
+/// Sent to subscribed connections, `{"jsonrpc": "2.0", "method": "event", "params": {...}}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Notification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: RemoteEvent,
+}
+impl Notification {
+    pub fn new(event: RemoteEvent) -> Self {
+        Notification {
+            jsonrpc: VERSION.to_string(),
+            method: "event".to_string(),
+            params: event,
+        }
+    }
+}
+/// What an attached client reads from the daemon
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Incoming {
+    Response(Response),
+    Notification(Notification),
+}
+/// The network events subscribers see, keyed by `type`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteEvent {
+    Message {
+        peer: PeerId,
+        message: Message,
+    },
+    /// A peer was discovered or added
+    Contact {
+        peer: PeerId,
+        name: String,
+    },
+    Presence {
+        peer: PeerId,
+        status: UserStatus,
+        message: Option<String>,
+    },
+    Typing {
+        peer: PeerId,
+        typing: bool,
+    },
+    ConversationUpdated {
+        peer: PeerId,
+    },
+    /// The receiver acknowledged our message
+    Delivered {
+        message_id: Uuid,
+    },
+    FriendRequest {
+        peer: PeerId,
+        request: FriendRequest,
+    },
+    Route {
+        peer: PeerId,
+        route: Option<Route>,
+    },
+    ContactCard {
+        token: String,
+    },
+    /// Something the user should be told, e.g. that a dial failed
+    Notice {
+        text: String,
+    },
+}
+impl RemoteEvent {
+    /// The event as subscribers see it, `None` for ones only the daemon cares about.
+    pub fn from_event(event: Event) -> Option<Self> {
+        let event = match event {
+            Event::InboundMessage { message, sender } => RemoteEvent::Message {
+                peer: PeerId::from_public_key(&(*sender).into()),
+                message,
+            },
+            Event::PresenceChanged {
+                peer,
+                status,
+                message,
+            } => RemoteEvent::Presence {
+                peer,
+                status,
+                message,
+            },
+            Event::Typing { peer, typing } => RemoteEvent::Typing { peer, typing },
+            Event::ConversationUpdated { peer } => RemoteEvent::ConversationUpdated { peer },
+            Event::OutboundMessageReceived { message_id } => RemoteEvent::Delivered { message_id },
+            Event::FriendRequest { peer, request } => RemoteEvent::FriendRequest { peer, request },
+            Event::Route { peer, route } => RemoteEvent::Route { peer, route },
+            Event::ContactCard { token } => RemoteEvent::ContactCard { token },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn error_code(line: &str) -> (Option<u64>, i64) {
+        let (id, err) = Request::parse(line).expect_err("an invalid request");
+        (id, err.code)
+    }
+
+    #[test]
+    fn requests_parse_with_and_without_params() {
+        let request =
+            Request::parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "contacts"}"#).unwrap();
+        assert_eq!(request.id, Some(1));
+        assert!(matches!(request.call, Call::Contacts));
+
+        let peer = PeerId::random();
+        let line = json!({
+            "jsonrpc": "2.0",
+            "method": "typing",
+            "params": { "peer": peer, "typing": true },
+        });
+        let request = Request::parse(&line.to_string()).unwrap();
+        assert_eq!(request.id, None);
+        assert!(matches!(request.call, Call::Typing { peer: p, typing: true } if p == peer));
+    }
+    #[test]
+    fn requests_round_trip() {
+        let line = json!(Request::new(
+            7,
+            Call::Dial {
+                target: "/ip4/127.0.0.1/tcp/1".to_string(),
+            }
+        ))
+        .to_string();
+        let request = Request::parse(&line).unwrap();
+        assert_eq!(request.id, Some(7));
+        assert!(matches!(request.call, Call::Dial { target } if target == "/ip4/127.0.0.1/tcp/1"));
+    }
+    #[test]
+    fn malformed_json_is_a_parse_error() {
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 1"#),
+            (None, RpcError::PARSE)
+        );
+    }
+    #[test]
+    fn requests_without_a_version_or_method_are_invalid() {
+        assert_eq!(
+            error_code(r#"{"id": 2, "method": "contacts"}"#),
+            (Some(2), RpcError::INVALID_REQUEST)
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 3}"#),
+            (Some(3), RpcError::INVALID_REQUEST)
+        );
+    }
+    #[test]
+    fn unknown_methods_are_not_found() {
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 4, "method": "reboot"}"#),
+            (Some(4), RpcError::METHOD_NOT_FOUND)
+        );
+    }
+    #[test]
+    fn mismatched_params_are_invalid_params() {
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 5, "method": "add_friend"}"#),
+            (Some(5), RpcError::INVALID_PARAMS)
+        );
+        assert_eq!(
+            error_code(
+                r#"{"jsonrpc": "2.0", "id": 6, "method": "add_friend", "params": {"peer": "nope"}}"#
+            ),
+            (Some(6), RpcError::INVALID_PARAMS)
+        );
+    }
+    #[test]
+    fn responses_carry_either_a_result_or_an_error() {
+        let ok = json!(Response::new(Some(1), Ok(json!(["a"]))));
+        assert_eq!(ok, json!({ "jsonrpc": "2.0", "id": 1, "result": ["a"] }));
+        let err = json!(Response::new(
+            None,
+            Err(RpcError::new(RpcError::PARSE, "bad"))
+        ));
+        assert_eq!(
+            err,
+            json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32700, "message": "bad" } })
+        );
+    }
+    #[test]
+    fn clients_tell_responses_from_events() {
+        let response = json!(Response::new(Some(1), Ok(Value::Null))).to_string();
+        assert!(matches!(
+            serde_json::from_str(&response).unwrap(),
+            Incoming::Response(Response { id: Some(1), .. })
+        ));
+        let event = RemoteEvent::Notice {
+            text: "hi".to_string(),
+        };
+        let notification = json!(Notification::new(event)).to_string();
+        assert!(matches!(
+            serde_json::from_str(&notification).unwrap(),
+            Incoming::Notification(Notification {
+                params: RemoteEvent::Notice { .. },
+                ..
+            })
+        ));
+    }
+}
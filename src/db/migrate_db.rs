@@ -1,18 +1,93 @@
-use tokio::fs::read_to_string;
-use tokio_rusqlite::{Connection, Result};
+use tokio_rusqlite::{Connection, Result, rusqlite};
 
-async fn migrate(conn: &Connection) -> Result<()> {
-    // TODO: Change the path to be win compatible
-    let sql_stmt = read_to_string("src/db/migration.sql").await;
-    conn.call(|conn| {
-        let Ok(sql) = sql_stmt else {
-            let e = sql_stmt.unwrap_err();
-            panic!("couldnt extract migration statement. {e}");
-        };
+// Each migration upgrades the schema by one version, the current version is kept in
+// `PRAGMA user_version`. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // from before versioned migrations, kept where and as it shipped
+    include_str!("migration.sql"),
+    include_str!("migrations/002_timestamps.sql"),
+    include_str!("migrations/003_contact_list.sql"),
+    include_str!("migrations/004_address_book.sql"),
+    include_str!("migrations/005_peer_info.sql"),
+    include_str!("migrations/006_message_threads.sql"),
+];
 
-        conn.execute(sql.as_str(), [])?;
-        Ok(())
+pub(super) async fn migrate(conn: &Connection) -> Result<()> {
+    conn.call(|conn| {
+        let version: usize =
+            conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+        }
+        Ok::<_, rusqlite::Error>(())
     })
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn version(conn: &Connection) -> usize {
+        conn.call(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)))
+            .await
+            .unwrap() as usize
+    }
+
+    #[tokio::test]
+    async fn fresh_databases_get_every_migration() {
+        let conn = Connection::open_in_memory().await.unwrap();
+        migrate(&conn).await.unwrap();
+        assert_eq!(version(&conn).await, MIGRATIONS.len());
+        // running again changes nothing
+        migrate(&conn).await.unwrap();
+        assert_eq!(version(&conn).await, MIGRATIONS.len());
+    }
+    #[tokio::test]
+    async fn databases_from_before_versions_are_upgraded() {
+        let conn = Connection::open_in_memory().await.unwrap();
+        // what every start ran before migrations were numbered
+        conn.call(|conn| {
+            conn.execute_batch(include_str!("migration.sql"))?;
+            conn.execute(
+                "INSERT INTO contacts (peer_id, name) VALUES ('peer', 'alice')",
+                [],
+            )?;
+            Ok::<_, rusqlite::Error>(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(version(&conn).await, 0);
+        migrate(&conn).await.unwrap();
+        assert_eq!(version(&conn).await, MIGRATIONS.len());
+        let name = conn
+            .call(|conn| {
+                conn.query_row(
+                    "SELECT name FROM contacts WHERE peer_id = 'peer'",
+                    [],
+                    |row| row.get::<_, String>(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(name, "alice");
+        // messages gained an author, replies, edits and reactions
+        conn.call(|conn| {
+            conn.execute(
+                "INSERT INTO messages (id, content, status, contact_id, sender, reply_to, edited, deleted)
+                 VALUES ('m', 'hi', 0, 'peer', 'peer', NULL, 1, 0)",
+                [],
+            )?;
+            conn.execute(
+                "INSERT INTO reactions (message_id, sender, emoji) VALUES ('m', 'peer', '👍')",
+                [],
+            )
+        })
+        .await
+        .unwrap();
+    }
+}
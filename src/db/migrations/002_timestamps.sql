@@ -0,0 +1,7 @@
+-- Sender and receive timestamps, milliseconds since the unix epoch
+ALTER TABLE messages ADD COLUMN sent_at INTEGER;           -- claimed by the sender, NULL for old messages
+ALTER TABLE messages ADD COLUMN received_at INTEGER;       -- when we stored the message
+-- Hybrid logical clock, messages are ordered by (hlc_wall, hlc_counter)
+ALTER TABLE messages ADD COLUMN hlc_wall INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE messages ADD COLUMN hlc_counter INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS messages_order ON messages (contact_id, hlc_wall, hlc_counter);
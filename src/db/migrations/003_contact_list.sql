@@ -0,0 +1,3 @@
+-- Pinned contacts are listed before all others
+ALTER TABLE contacts ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS messages_status ON messages (contact_id, status);
@@ -0,0 +1,9 @@
+-- Addresses we have seen peers at, used to reach them again without mDNS
+CREATE TABLE IF NOT EXISTS peer_addresses (
+    peer_id TEXT NOT NULL,
+    address TEXT NOT NULL,            -- multiaddr without the /p2p suffix
+    last_seen INTEGER NOT NULL,       -- milliseconds since the unix epoch
+    successes INTEGER NOT NULL DEFAULT 0,
+    failures INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (peer_id, address)
+);
@@ -0,0 +1,8 @@
+-- What peers told us about themselves over identify
+CREATE TABLE IF NOT EXISTS peer_info (
+    peer_id TEXT PRIMARY KEY,
+    agent_version TEXT NOT NULL,
+    protocol_version TEXT NOT NULL,
+    protocols TEXT NOT NULL,          -- space separated protocol names
+    updated_at INTEGER NOT NULL       -- milliseconds since the unix epoch
+);
@@ -0,0 +1,14 @@
+-- Edits, deletions, replies and reactions.
+-- Versions before this never stored messages, so there is no author to fill in.
+ALTER TABLE messages ADD COLUMN sender TEXT NOT NULL DEFAULT '';    -- peer_id of the author
+ALTER TABLE messages ADD COLUMN reply_to TEXT;                      -- id of the message this one replies to
+ALTER TABLE messages ADD COLUMN edited INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE messages ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS reactions (
+    message_id TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    PRIMARY KEY (message_id, sender, emoji),
+    FOREIGN KEY (message_id) REFERENCES messages(id)
+);
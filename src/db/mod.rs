@@ -1 +1,549 @@
 mod migrate_db;
+pub mod models;
+
+use std::collections::HashMap;
+
+use libp2p::{Multiaddr, PeerId};
+use tokio_rusqlite::{Connection, OptionalExtension, Result, params, rusqlite};
+use uuid::Uuid;
+
+use crate::db::models::MessageStatus;
+use crate::db::models::{
+    ContactSummary, LastMessage, MessageRecord, PeerAddress, PeerInfo, Reaction,
+};
+use crate::network::clock::{Hlc, now_millis};
+use crate::settings::{SaveFile, get_config_save_file_path};
+
+#[derive(Clone)]
+pub struct Database {
+    conn: Connection,
+}
+impl Database {
+    pub async fn open() -> Result<Self> {
+        let conn = Connection::open(get_config_save_file_path(SaveFile::Database))
+            .await
+            .map_err(tokio_rusqlite::Error::Error)?;
+        migrate_db::migrate(&conn).await?;
+        Ok(Self { conn })
+    }
+    /// A fresh database that only lives as long as its connection.
+    #[cfg(test)]
+    pub(crate) async fn open_in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .await
+            .map_err(tokio_rusqlite::Error::Error)?;
+        migrate_db::migrate(&conn).await?;
+        Ok(Self { conn })
+    }
+    /// Waits for queued writes to finish and closes the database,
+    /// every clone fails afterwards.
+    pub async fn close(self) -> Result<()> {
+        self.conn.close().await
+    }
+    /// Creates the contact with a placeholder name if we haven't seen it yet.
+    pub async fn ensure_contact(&self, peer: PeerId) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR IGNORE INTO contacts (peer_id, name) VALUES (?1, ?2)",
+                    params![peer.to_string(), "Anonymous"],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Stores a contact under the name they gave us, e.g. on their contact card.
+    pub async fn name_contact(&self, peer: PeerId, name: String) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO contacts (peer_id, name) VALUES (?1, ?2)
+                     ON CONFLICT (peer_id) DO UPDATE SET name = excluded.name",
+                    params![peer.to_string(), name],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    pub async fn set_pinned(&self, peer: PeerId, pinned: bool) -> Result<()> {
+        self.ensure_contact(peer).await?;
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE contacts SET pinned = ?2 WHERE peer_id = ?1",
+                    params![peer.to_string(), pinned],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Every known contact with its unread count and latest message.
+    pub async fn contact_summaries(&self) -> Result<Vec<ContactSummary>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT c.peer_id, c.name, c.pinned,
+                        (SELECT COUNT(*) FROM messages u
+                         WHERE u.contact_id = c.peer_id AND u.status = ?1 AND u.deleted = 0),
+                        l.sender, l.content, l.deleted, COALESCE(l.sent_at, l.received_at, 0)
+                     FROM contacts c
+                     LEFT JOIN messages l ON l.id = (
+                        SELECT id FROM messages WHERE contact_id = c.peer_id
+                        ORDER BY hlc_wall DESC, hlc_counter DESC, rowid DESC LIMIT 1)",
+                )?;
+                let rows =
+                    stmt.query_map(params![i64::from(MessageStatus::ReceivedNotRead)], |row| {
+                        let last_message = match row.get::<_, Option<String>>(4)? {
+                            Some(_) => Some(LastMessage {
+                                sender: parse_peer(row, 4)?,
+                                content: row.get(5)?,
+                                deleted: row.get(6)?,
+                                time: row.get::<_, i64>(7)? as u64,
+                            }),
+                            None => None,
+                        };
+                        Ok(ContactSummary {
+                            peer_id: parse_peer(row, 0)?,
+                            name: row.get(1)?,
+                            pinned: row.get(2)?,
+                            unread: row.get(3)?,
+                            last_message,
+                        })
+                    })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await
+    }
+    /// Marks every message received from `peer` as read.
+    pub async fn mark_read(&self, peer: PeerId) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE messages SET status = ?2 WHERE contact_id = ?1 AND status = ?3",
+                    params![
+                        peer.to_string(),
+                        i64::from(MessageStatus::ReceivedRead),
+                        i64::from(MessageStatus::ReceivedNotRead),
+                    ],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    pub async fn insert_message(&self, message: MessageRecord) -> Result<()> {
+        self.ensure_contact(message.conversation).await?;
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR IGNORE INTO messages
+                        (id, content, status, contact_id, sender, reply_to, edited, deleted,
+                         sent_at, received_at, hlc_wall, hlc_counter)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                    params![
+                        message.id.to_string(),
+                        message.content,
+                        i64::from(message.status),
+                        message.conversation.to_string(),
+                        message.sender.to_string(),
+                        message.reply_to.map(|id| id.to_string()),
+                        message.edited,
+                        message.deleted,
+                        message.sent_at.map(|t| t as i64),
+                        message.received_at as i64,
+                        message.clock.wall as i64,
+                        message.clock.counter,
+                    ],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Looks up who wrote a message and in which conversation.
+    pub async fn message_origin(&self, id: Uuid) -> Result<Option<(PeerId, PeerId)>> {
+        self.conn
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT sender, contact_id FROM messages WHERE id = ?1",
+                    params![id.to_string()],
+                    |row| Ok((parse_peer(row, 0)?, parse_peer(row, 1)?)),
+                )
+                .optional()
+            })
+            .await
+    }
+    pub async fn edit_message(&self, id: Uuid, content: String) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE messages SET content = ?2, edited = 1 WHERE id = ?1 AND deleted = 0",
+                    params![id.to_string(), content],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Replaces the message with a tombstone, keeping its id so replies still resolve.
+    pub async fn delete_message(&self, id: Uuid) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute(
+                    "UPDATE messages SET content = '', deleted = 1 WHERE id = ?1",
+                    params![id.to_string()],
+                )?;
+                tx.execute(
+                    "DELETE FROM reactions WHERE message_id = ?1",
+                    params![id.to_string()],
+                )?;
+                tx.commit()
+            })
+            .await
+    }
+    pub async fn set_reaction(
+        &self,
+        message_id: Uuid,
+        sender: PeerId,
+        emoji: String,
+        remove: bool,
+    ) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                let params = params![message_id.to_string(), sender.to_string(), emoji];
+                match remove {
+                    true => conn.execute(
+                        "DELETE FROM reactions WHERE message_id = ?1 AND sender = ?2 AND emoji = ?3",
+                        params,
+                    )?,
+                    false => conn.execute(
+                        "INSERT OR IGNORE INTO reactions (message_id, sender, emoji) VALUES (?1, ?2, ?3)",
+                        params,
+                    )?,
+                };
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Remembers that `peer` was seen at `address`.
+    pub async fn record_address(&self, peer: PeerId, address: Multiaddr) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO peer_addresses (peer_id, address, last_seen) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (peer_id, address) DO UPDATE SET last_seen = excluded.last_seen",
+                    params![peer.to_string(), address.to_string(), now_millis() as i64],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Replaces what we know about the software `peer` runs.
+    pub async fn record_peer_info(&self, peer: PeerId, info: PeerInfo) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO peer_info
+                     (peer_id, agent_version, protocol_version, protocols, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        peer.to_string(),
+                        info.agent_version,
+                        info.protocol_version,
+                        info.protocols.join(" "),
+                        now_millis() as i64
+                    ],
+                )?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Counts a dial of `peer` at `address`, a successful one also counts as seeing it.
+    pub async fn record_dial(&self, peer: PeerId, address: Multiaddr, success: bool) -> Result<()> {
+        self.conn
+            .call(move |conn| {
+                let (peer, address) = (peer.to_string(), address.to_string());
+                match success {
+                    true => conn.execute(
+                        "INSERT INTO peer_addresses (peer_id, address, last_seen, successes)
+                         VALUES (?1, ?2, ?3, 1)
+                         ON CONFLICT (peer_id, address) DO UPDATE
+                         SET last_seen = excluded.last_seen, successes = successes + 1",
+                        params![peer, address, now_millis() as i64],
+                    )?,
+                    // failures of addresses we never saw aren't worth remembering
+                    false => conn.execute(
+                        "UPDATE peer_addresses SET failures = failures + 1
+                         WHERE peer_id = ?1 AND address = ?2",
+                        params![peer, address],
+                    )?,
+                };
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+    }
+    /// Addresses of our contacts worth dialing, the most reliable and recent first.
+    pub async fn contact_addresses(&self) -> Result<Vec<PeerAddress>> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT peer_id, address FROM peer_addresses
+                     WHERE peer_id IN (SELECT peer_id FROM contacts)
+                        AND failures < successes + 10
+                     ORDER BY successes - failures DESC, last_seen DESC",
+                )?;
+                let rows = stmt.query_map([], |row| {
+                    Ok(PeerAddress {
+                        peer_id: parse_peer(row, 0)?,
+                        address: row
+                            .get::<_, String>(1)?
+                            .parse()
+                            .map_err(|e| conversion_error(1, e))?,
+                    })
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await
+    }
+    /// The latest clock seen in the conversation with `peer`.
+    pub async fn latest_clock(&self, peer: PeerId) -> Result<Hlc> {
+        self.conn
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT hlc_wall, hlc_counter FROM messages WHERE contact_id = ?1
+                     ORDER BY hlc_wall DESC, hlc_counter DESC LIMIT 1",
+                    params![peer.to_string()],
+                    |row| {
+                        Ok(Hlc {
+                            wall: row.get::<_, i64>(0)? as u64,
+                            counter: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()
+                .map(Option::unwrap_or_default)
+            })
+            .await
+    }
+    /// All messages exchanged with `peer`, in causal order.
+    pub async fn conversation(&self, peer: PeerId) -> Result<Vec<MessageRecord>> {
+        self.conn
+            .call(move |conn| {
+                let peer_id = peer.to_string();
+                let mut reactions = HashMap::<Uuid, Vec<Reaction>>::new();
+                let mut stmt = conn.prepare(
+                    "SELECT r.message_id, r.sender, r.emoji FROM reactions r
+                     JOIN messages m ON m.id = r.message_id
+                     WHERE m.contact_id = ?1 ORDER BY r.rowid",
+                )?;
+                let rows = stmt.query_map(params![peer_id], |row| {
+                    Ok((
+                        parse_uuid(row, 0)?,
+                        Reaction {
+                            sender: parse_peer(row, 1)?,
+                            emoji: row.get(2)?,
+                        },
+                    ))
+                })?;
+                for row in rows {
+                    let (message_id, reaction) = row?;
+                    reactions.entry(message_id).or_default().push(reaction);
+                }
+
+                let mut stmt = conn.prepare(
+                    "SELECT id, content, status, sender, reply_to, edited, deleted,
+                        sent_at, received_at, hlc_wall, hlc_counter FROM messages
+                     WHERE contact_id = ?1 ORDER BY hlc_wall, hlc_counter, sender, rowid",
+                )?;
+                let rows = stmt.query_map(params![peer_id], |row| {
+                    let id = parse_uuid(row, 0)?;
+                    let reply_to: Option<String> = row.get(4)?;
+                    Ok(MessageRecord {
+                        id,
+                        conversation: peer,
+                        content: row.get(1)?,
+                        status: row
+                            .get::<_, i64>(2)?
+                            .try_into()
+                            .map_err(|e| conversion_error(2, e))?,
+                        sender: parse_peer(row, 3)?,
+                        reply_to: reply_to
+                            .map(|id| id.parse().map_err(|e| conversion_error(4, e)))
+                            .transpose()?,
+                        edited: row.get(5)?,
+                        deleted: row.get(6)?,
+                        sent_at: row.get::<_, Option<i64>>(7)?.map(|t| t as u64),
+                        received_at: row.get::<_, Option<i64>>(8)?.unwrap_or_default() as u64,
+                        clock: Hlc {
+                            wall: row.get::<_, i64>(9)? as u64,
+                            counter: row.get(10)?,
+                        },
+                        reactions: reactions.remove(&id).unwrap_or_default(),
+                    })
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await
+    }
+}
+
+fn conversion_error(
+    idx: usize,
+    err: impl std::error::Error + Send + Sync + 'static,
+) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(err))
+}
+fn parse_peer(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<PeerId> {
+    row.get::<_, String>(idx)?
+        .parse()
+        .map_err(|e| conversion_error(idx, e))
+}
+fn parse_uuid(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Uuid> {
+    row.get::<_, String>(idx)?
+        .parse()
+        .map_err(|e| conversion_error(idx, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(
+        conversation: PeerId,
+        sender: PeerId,
+        wall: u64,
+        status: MessageStatus,
+    ) -> MessageRecord {
+        MessageRecord {
+            id: Uuid::new_v4(),
+            conversation,
+            sender,
+            content: format!("message at {wall}"),
+            status,
+            reply_to: None,
+            sent_at: Some(wall),
+            received_at: wall,
+            clock: Hlc { wall, counter: 0 },
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+        }
+    }
+    async fn summary(db: &Database, peer: PeerId) -> ContactSummary {
+        db.contact_summaries()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.peer_id == peer)
+            .expect("contact to be listed")
+    }
+
+    #[tokio::test]
+    async fn unread_counts_only_received_messages_until_read() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (us, alice) = (PeerId::random(), PeerId::random());
+        for wall in 1..=3 {
+            db.insert_message(message(alice, alice, wall, MessageStatus::ReceivedNotRead))
+                .await
+                .unwrap();
+        }
+        db.insert_message(message(alice, us, 4, MessageStatus::SentOffNotRead))
+            .await
+            .unwrap();
+        let deleted = message(alice, alice, 5, MessageStatus::ReceivedNotRead);
+        db.insert_message(deleted.clone()).await.unwrap();
+        db.delete_message(deleted.id).await.unwrap();
+        assert_eq!(summary(&db, alice).await.unread, 3);
+
+        db.mark_read(alice).await.unwrap();
+        assert_eq!(summary(&db, alice).await.unread, 0);
+        db.insert_message(message(alice, alice, 6, MessageStatus::ReceivedNotRead))
+            .await
+            .unwrap();
+        assert_eq!(summary(&db, alice).await.unread, 1);
+    }
+    #[tokio::test]
+    async fn mark_read_leaves_other_conversations_alone() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        for peer in [alice, bob] {
+            db.insert_message(message(peer, peer, 1, MessageStatus::ReceivedNotRead))
+                .await
+                .unwrap();
+        }
+        db.mark_read(alice).await.unwrap();
+        assert_eq!(summary(&db, alice).await.unread, 0);
+        assert_eq!(summary(&db, bob).await.unread, 1);
+    }
+    #[tokio::test]
+    async fn pinning_is_stored_per_contact() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        db.ensure_contact(bob).await.unwrap();
+        // pinning someone we never talked to adds them
+        db.set_pinned(alice, true).await.unwrap();
+        assert!(summary(&db, alice).await.pinned);
+        assert!(!summary(&db, bob).await.pinned);
+        db.set_pinned(alice, false).await.unwrap();
+        assert!(!summary(&db, alice).await.pinned);
+    }
+    #[tokio::test]
+    async fn the_preview_is_the_latest_message_as_edited_or_deleted() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (us, alice) = (PeerId::random(), PeerId::random());
+        db.ensure_contact(alice).await.unwrap();
+        assert!(summary(&db, alice).await.last_message.is_none());
+
+        let latest = message(alice, us, 2, MessageStatus::SentOffNotRead);
+        // stored out of order, the clock decides which one is the latest
+        db.insert_message(latest.clone()).await.unwrap();
+        db.insert_message(message(alice, alice, 1, MessageStatus::ReceivedNotRead))
+            .await
+            .unwrap();
+        let last = summary(&db, alice).await.last_message.unwrap();
+        assert_eq!(
+            (last.sender, last.content, last.time),
+            (us, latest.content, 2)
+        );
+
+        db.edit_message(latest.id, "fixed".to_string())
+            .await
+            .unwrap();
+        let last = summary(&db, alice).await.last_message.unwrap();
+        assert_eq!(last.content, "fixed");
+        assert!(!last.deleted);
+
+        db.delete_message(latest.id).await.unwrap();
+        let last = summary(&db, alice).await.last_message.unwrap();
+        assert!(last.deleted);
+        assert_eq!(last.content, "");
+    }
+    #[tokio::test]
+    async fn contact_addresses_come_back_most_reliable_first() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (alice, stranger) = (PeerId::random(), PeerId::random());
+        db.ensure_contact(alice).await.unwrap();
+        let address =
+            |port: u16| -> Multiaddr { format!("/ip4/192.0.2.1/tcp/{port}").parse().unwrap() };
+
+        db.record_address(alice, address(1)).await.unwrap();
+        db.record_dial(alice, address(2), true).await.unwrap();
+        db.record_dial(alice, address(2), true).await.unwrap();
+        for _ in 0..11 {
+            db.record_dial(alice, address(3), false).await.unwrap();
+        }
+        db.record_address(alice, address(4)).await.unwrap();
+        for _ in 0..10 {
+            db.record_dial(alice, address(4), false).await.unwrap();
+        }
+        db.record_address(stranger, address(5)).await.unwrap();
+
+        let addresses: Vec<_> = db
+            .contact_addresses()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.peer_id, entry.address))
+            .collect();
+        // failures of addresses never seen aren't stored, ones failing too often are dropped
+        assert_eq!(addresses, [(alice, address(2)), (alice, address(1))]);
+    }
+}
@@ -1 +1,93 @@
+use libp2p::{Multiaddr, PeerId};
+use uuid::Uuid;
 
+use crate::network::clock::Hlc;
+
+#[derive(Debug, Clone)]
+pub struct MessageRecord {
+    pub id: Uuid,
+    /// The peer on the other side of the conversation
+    pub conversation: PeerId,
+    pub sender: PeerId,
+    pub content: String,
+    pub status: MessageStatus,
+    pub reply_to: Option<Uuid>,
+    /// Milliseconds since the unix epoch, as claimed by the sender
+    pub sent_at: Option<u64>,
+    /// Milliseconds since the unix epoch, when we stored the message
+    pub received_at: u64,
+    pub clock: Hlc,
+    pub edited: bool,
+    pub deleted: bool,
+    pub reactions: Vec<Reaction>,
+}
+/// A contact as shown in the contact list
+#[derive(Debug, Clone)]
+pub struct ContactSummary {
+    pub peer_id: PeerId,
+    pub name: String,
+    pub pinned: bool,
+    /// Received messages that haven't been read yet
+    pub unread: u32,
+    /// The latest message of the conversation
+    pub last_message: Option<LastMessage>,
+}
+#[derive(Debug, Clone)]
+pub struct LastMessage {
+    pub sender: PeerId,
+    pub content: String,
+    pub deleted: bool,
+    /// Milliseconds since the unix epoch, as claimed by the sender if known
+    pub time: u64,
+}
+/// An address a peer was seen at
+#[derive(Debug, Clone)]
+pub struct PeerAddress {
+    pub peer_id: PeerId,
+    pub address: Multiaddr,
+}
+/// The software a peer runs, as announced over identify
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub agent_version: String,
+    pub protocol_version: String,
+    pub protocols: Vec<String>,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reaction {
+    pub sender: PeerId,
+    pub emoji: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageStatus {
+    ReceivedNotRead,
+    ReceivedRead,
+    SentOffNotRead,
+    SentOffRead,
+}
+impl From<MessageStatus> for i64 {
+    fn from(status: MessageStatus) -> Self {
+        match status {
+            MessageStatus::ReceivedNotRead => 0,
+            MessageStatus::ReceivedRead => 1,
+            MessageStatus::SentOffNotRead => 2,
+            MessageStatus::SentOffRead => 3,
+        }
+    }
+}
+impl TryFrom<i64> for MessageStatus {
+    type Error = std::io::Error;
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MessageStatus::ReceivedNotRead),
+            1 => Ok(MessageStatus::ReceivedRead),
+            2 => Ok(MessageStatus::SentOffNotRead),
+            3 => Ok(MessageStatus::SentOffRead),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Unknown message status",
+            )),
+        }
+    }
+}
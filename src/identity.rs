@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use libp2p::identity::Keypair;
+
+use crate::settings::{SaveFile, get_config_save_file_path};
+
+/// Our persisted keypair, `None` before the first run finished.
+pub fn load() -> std::io::Result<Option<Keypair>> {
+    match std::fs::read(get_config_save_file_path(SaveFile::Identity)) {
+        Ok(bytes) => decode(&bytes).map(Some),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+/// Our persisted keypair, a new one is created and saved when there is none,
+/// for when nobody is there to go through the onboarding.
+pub fn load_or_create() -> std::io::Result<Keypair> {
+    if let Some(keys) = load()? {
+        return Ok(keys);
+    }
+    let keys = Keypair::generate_ed25519();
+    save(&keys)?;
+    tracing::info!("created identity {}", keys.public().to_peer_id());
+    Ok(keys)
+}
+/// Reads a keypair exported by another install, in libp2p's protobuf encoding.
+pub fn import(path: &Path) -> std::io::Result<Keypair> {
+    decode(&std::fs::read(path)?)
+}
+pub fn save(keys: &Keypair) -> std::io::Result<()> {
+    let bytes = keys.to_protobuf_encoding().map_err(std::io::Error::other)?;
+    write_private(&get_config_save_file_path(SaveFile::Identity), &bytes)
+}
+/// Writes a file only we can read. It's created that way next to `path` and renamed over it,
+/// so the key is never readable by others, not even while an old file is replaced.
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let tmp_path = path.with_extension("tmp");
+    let _ = std::fs::remove_file(&tmp_path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
+fn decode(bytes: &[u8]) -> std::io::Result<Keypair> {
+    Keypair::from_protobuf_encoding(bytes)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_written_only_we_can_read() {
+        let dir = std::env::temp_dir().join(format!("p2pchat-identity-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("identity");
+        std::fs::write(&path, b"old key").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        let keys = Keypair::generate_ed25519();
+        write_private(&path, &keys.to_protobuf_encoding().unwrap()).unwrap();
+        let read = decode(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(read.public(), keys.public());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+//! Peer to peer chat over libp2p, the `p2pchat` binary is its terminal interface
+//! and runs [`app::run`].
+//!
+//! The same network runs without a terminal for scripts and bots:
+//! [`node::Node`] starts it for a profile, [`network::Client`] sends commands to it
+//! and [`network::Event`]s report what peers did. [`bot::Bot`] wraps both for
+//! automated responders, see `examples/echo_bot.rs`.
+//!
+//! ```no_run
+//! use clap::Parser;
+//! use libp2p::PeerId;
+//! use p2pchat::bot::{self, Bot};
+//! use p2pchat::config::Config;
+//! use p2pchat::network::Client;
+//! use p2pchat::network::chat::{Message, MessageBody};
+//! use p2pchat::node::Node;
+//!
+//! struct Greeter;
+//! impl Bot for Greeter {
+//!     async fn on_message(&mut self, client: &mut Client, peer: PeerId, _message: &Message) {
+//!         let body = MessageBody::Text {
+//!             content: "hello!".to_string(),
+//!             reply_to: None,
+//!         };
+//!         client.send(peer, body).await;
+//!     }
+//! }
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let node = Node::open(&Config::parse()).await?;
+//! bot::run(Greeter, node).await;
+//! # Ok(())
+//! # }
+//! ```
+pub mod app;
+pub(crate) mod audio;
+pub mod bot;
+pub(crate) mod cli;
+pub mod config;
+#[cfg(unix)]
+pub(crate) mod daemon;
+pub mod db;
+pub mod identity;
+pub mod network;
+pub mod node;
+pub(crate) mod notify;
+pub(crate) mod profile;
+pub mod settings;
+pub(crate) mod tui;
@@ -1,13 +1,18 @@
 use futures::StreamExt;
 use libp2p::{
-    PeerId, StreamProtocol, Swarm,
+    Multiaddr, PeerId, StreamProtocol, Swarm, autonat,
+    core::ConnectedPoint,
+    core::transport::ListenerId,
+    dcutr, identify,
     identity::{Keypair, ed25519::PublicKey},
-    mdns, noise,
-    request_response::{self, ProtocolSupport},
-    swarm::{NetworkBehaviour, SwarmEvent},
+    mdns,
+    multiaddr::Protocol,
+    noise, relay,
+    request_response::{self, OutboundRequestId, ProtocolSupport},
+    swarm::{ConnectionId, DialError, NetworkBehaviour, SwarmEvent, behaviour::toggle::Toggle},
     tcp, yamux,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::{
     RwLock,
@@ -16,83 +21,230 @@ use tokio::sync::{
 use uuid::Uuid;
 
 use crate::{
+    config::NetworkConfig,
+    db::Database,
     network::{
-        chat::{
-            ChatCommand, DirectMessageRequest, DirectMessageResponse, Message, MessageResponse,
+        call::{
+            AUDIO_PROTOCOL, AudioEnded, Call, CallCommand, CallRequest, CallResponse, CallState,
         },
-        friends::{FriendCommand, FriendRequest, FriendResponse},
-        signable::sign,
+        chat::{ChatCommand, DirectMessageResponse, Message, MessageCodec},
+        clock::Hlc,
+        contact_card::ContactCardCommand,
+        dial::{DialCommand, DialTarget},
+        friends::{FriendCodec, FriendCommand, FriendRequest, FriendResponse},
+        nat::Route,
+        pending::Reply,
+        presence::{PresenceAck, PresenceCommand, PresenceUpdate, UserStatus},
     },
     settings::{Setting, SettingName, SettingValue},
 };
 
+mod address_book;
+pub mod call;
 pub mod chat;
+pub mod clock;
+pub mod contact_card;
+pub mod dial;
 pub mod friends;
+pub mod nat;
+pub mod peer_info;
+pub mod pending;
+pub mod presence;
+mod shutdown;
 pub mod signable;
+pub mod versioned;
 
 pub enum Command {
     ChatCommand(ChatCommand),
     FriendCommand(FriendCommand),
+    CallCommand(CallCommand),
+    PresenceCommand(PresenceCommand),
+    DialCommand(DialCommand),
+    ContactCardCommand(ContactCardCommand),
+    /// Answered once the event loop stopped
+    Shutdown(Reply<()>),
 }
-pub(crate) async fn new(
-    identities: Arc<RwLock<HashMap<PeerId, PublicKey>>>,
+/// A peer that was discovered or added, e.g. over mDNS or from a contact card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contact {
+    pub peer: PeerId,
+    /// "Anonymous" until the peer told us their name
+    pub name: String,
+}
+/// Sets up the swarm and starts listening. Nothing is answered until the event loop runs,
+/// `contacts` gets the contacts it discovers.
+pub async fn new(
     settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
-    tui_tx: UnboundedSender<crate::tui::Event>,
-) -> (EventLoop, Client, mpsc::Receiver<Event>) {
-    // TODO: Confiugre properly & handle errors
-    // Dont generate identities on every run, create a store
-    let id = Keypair::generate_ed25519();
+    contacts: UnboundedSender<Contact>,
+    db: Database,
+    id: Keypair,
+    config: NetworkConfig,
+) -> Result<(EventLoop, Client, mpsc::Receiver<Event>), NetworkError> {
+    if config.listen.is_empty() {
+        return Err(NetworkError::NoListenAddress);
+    }
+    let request_config = request_response::Config::default()
+        .with_request_timeout(config.request_timeout)
+        .with_max_concurrent_streams(config.max_streams);
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(id.clone())
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )
-        .unwrap()
-        .with_quic()
-        .with_behaviour(|key| {
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            let direct_message = libp2p::request_response::cbor::Behaviour::new(
-                [(
-                    StreamProtocol::new("/direct-message/1"),
-                    ProtocolSupport::Full,
-                )],
-                request_response::Config::default(),
+        .with_tcp(tcp::Config::default(), noise::Config::new, || {
+            let mut yamux = yamux::Config::default();
+            yamux.set_max_num_streams(config.max_streams);
+            yamux
+        })
+        .map_err(|err| NetworkError::Transport(err.to_string()))?
+        .with_quic_config(|mut quic| {
+            quic.max_idle_timeout = config.idle_timeout.as_millis() as u32;
+            quic.max_concurrent_stream_limit = config.max_streams as u32;
+            quic
+        })
+        .with_relay_client(noise::Config::new, || {
+            let mut yamux = yamux::Config::default();
+            yamux.set_max_num_streams(config.max_streams);
+            yamux
+        })
+        .map_err(|err| NetworkError::Transport(err.to_string()))?
+        .with_behaviour(|key, relay_client| {
+            let local_id = key.public().to_peer_id();
+            let mdns = match config.mdns {
+                true => Some(mdns::tokio::Behaviour::new(
+                    mdns::Config {
+                        query_interval: config.mdns_interval,
+                        ..Default::default()
+                    },
+                    local_id,
+                )?),
+                false => None,
+            };
+            let relay = match config.relay_server {
+                true => Some(relay::Behaviour::new(local_id, Default::default())),
+                false => None,
+            };
+            let direct_message = request_response::Behaviour::new(
+                chat::PROTOCOLS.map(|protocol| (protocol, ProtocolSupport::Full)),
+                request_config.clone(),
             );
-            let friends = libp2p::request_response::cbor::Behaviour::new(
-                [(StreamProtocol::new("/friends/1"), ProtocolSupport::Full)],
-                request_response::Config::default(),
+            let friends = request_response::Behaviour::new(
+                friends::PROTOCOLS.map(|protocol| (protocol, ProtocolSupport::Full)),
+                request_config.clone(),
+            );
+            let call = libp2p::request_response::cbor::Behaviour::new(
+                [(StreamProtocol::new("/call/1"), ProtocolSupport::Full)],
+                request_config.clone(),
+            );
+            let presence = libp2p::request_response::cbor::Behaviour::new(
+                [(StreamProtocol::new("/presence/1"), ProtocolSupport::Full)],
+                request_config.clone(),
             );
             Ok(Behaviour {
-                mdns,
+                mdns: mdns.into(),
                 direct_message,
                 friends,
+                call,
+                presence,
+                stream: libp2p_stream::Behaviour::new(),
+                relay_client,
+                relay: relay.into(),
+                autonat: autonat::Behaviour::new(local_id, Default::default()),
+                dcutr: dcutr::Behaviour::new(local_id),
+                identify: identify::Behaviour::new(
+                    identify::Config::new(peer_info::PROTOCOL_VERSION.to_string(), key.public())
+                        .with_agent_version(peer_info::agent_version()),
+                ),
             })
         })
-        .unwrap()
+        .map_err(|err| NetworkError::Behaviour(err.to_string()))?
+        .with_swarm_config(|swarm| swarm.with_idle_connection_timeout(config.idle_timeout))
         .build();
-    // Listen on all interfaces and whatever port the OS assigns
-    swarm
-        .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())
-        .unwrap();
-    swarm
-        .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
-        .unwrap();
+    if config.idle_timeout <= presence::HEARTBEAT {
+        tracing::warn!(
+            "idle timeout of {:?} is shorter than the presence heartbeat, contacts will disconnect",
+            config.idle_timeout
+        );
+    }
+    let mut port_listeners = HashSet::new();
+    for address in config.listen {
+        let listener = swarm
+            .listen_on(address.clone())
+            .map_err(|err| NetworkError::Listen {
+                address,
+                reason: err.to_string(),
+            })?;
+        if config.listen_from_settings {
+            port_listeners.insert(listener);
+        }
+    }
+    for address in config.external_addresses {
+        swarm.add_external_address(address);
+    }
+    // a reservation on each relay makes us reachable at its /p2p-circuit address
+    for relay in config.relays {
+        let Some(Protocol::P2p(relay_id)) = relay.iter().last() else {
+            return Err(NetworkError::Listen {
+                address: relay,
+                reason: "relay addresses need to end in /p2p/<peer id>".to_string(),
+            });
+        };
+        swarm
+            .behaviour_mut()
+            .autonat
+            .add_server(relay_id, Some(relay.clone()));
+        swarm
+            .listen_on(relay.clone().with(Protocol::P2pCircuit))
+            .map_err(|err| NetworkError::Listen {
+                address: relay,
+                reason: err.to_string(),
+            })?;
+    }
+    if config.dht {
+        tracing::info!("DHT discovery isn't supported yet, only finding peers on the LAN");
+    }
     let (command_tx, command_rx) = mpsc::channel(100);
     let (event_tx, event_rx) = mpsc::channel(100);
     let client = Client {
         settings: settings.clone(),
         command_sender: command_tx,
-        keys: id.clone(),
         id: PeerId::from_public_key(&id.public()),
+        request_timeout: config.request_timeout,
     };
-    let event_loop = EventLoop::new(swarm, command_rx, event_tx, settings, id, tui_tx);
-    (event_loop, client, event_rx)
+    let mut event_loop = EventLoop::new(swarm, command_rx, event_tx, settings, id, contacts, db);
+    event_loop.port_listeners = port_listeners;
+    Ok((event_loop, client, event_rx))
 }
+/// Why the network couldn't be started, meant to be shown to the user.
 #[derive(Debug)]
-pub(crate) enum Event {
+pub enum NetworkError {
+    /// Both transports are off and no address was given
+    NoListenAddress,
+    Transport(String),
+    Behaviour(String),
+    Listen {
+        address: Multiaddr,
+        reason: String,
+    },
+}
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkError::NoListenAddress => write!(
+                f,
+                "nothing to listen on, enable TCP or QUIC in the settings or pass --listen"
+            ),
+            NetworkError::Transport(reason) => write!(f, "failed to set up TCP: {reason}"),
+            NetworkError::Behaviour(reason) => {
+                write!(f, "failed to set up the network protocols: {reason}")
+            }
+            NetworkError::Listen { address, reason } => {
+                write!(f, "can't listen on {address}: {reason}")
+            }
+        }
+    }
+}
+impl std::error::Error for NetworkError {}
+/// What peers did, read from the receiver [`new`] returns.
+#[derive(Debug)]
+pub enum Event {
     InboundMessage {
         message: Message,
         sender: Box<PublicKey>,
@@ -103,29 +255,207 @@ pub(crate) enum Event {
     OutboundMessageInvalidSignature {
         message_id: Uuid,
     },
+    OutboundMessageRejected {
+        message_id: Uuid,
+    },
+    PresenceChanged {
+        peer: PeerId,
+        status: UserStatus,
+        message: Option<String>,
+    },
+    Typing {
+        peer: PeerId,
+        typing: bool,
+    },
+    /// A message in the conversation with `peer` was stored or changed
+    ConversationUpdated {
+        peer: PeerId,
+    },
+    Call {
+        call_id: Uuid,
+        peer: PeerId,
+        state: CallState,
+    },
+    /// A dial the user asked for connected
+    Dialed {
+        peer: PeerId,
+    },
+    DialFailed {
+        target: DialTarget,
+        reason: String,
+    },
+    /// Our contact card was exported
+    ContactCard {
+        token: String,
+    },
+    /// How we are connected to `peer` changed, `None` once disconnected
+    Route {
+        peer: PeerId,
+        route: Option<Route>,
+    },
+    /// `peer` runs a version of the app without `protocol`
+    Unsupported {
+        peer: PeerId,
+        protocol: StreamProtocol,
+    },
+    /// A message never got a response, it stays unsent in the conversation
+    MessageSendFailed {
+        message_id: Uuid,
+        peer: PeerId,
+        reason: String,
+    },
+    FriendRequestFailed {
+        peer: PeerId,
+        request: FriendRequest,
+        reason: String,
+    },
+    /// `peer` wants to be friends or answered our request
+    FriendRequest {
+        peer: PeerId,
+        request: FriendRequest,
+    },
+    /// The first connection to `peer` opened
+    PeerConnected {
+        peer: PeerId,
+        address: Multiaddr,
+    },
+    /// The last connection to `peer` closed, with the error that closed it
+    PeerDisconnected {
+        peer: PeerId,
+        reason: Option<String>,
+    },
+    /// A dial we made on our own, e.g. redialing a contact, failed
+    ConnectionFailed {
+        peer: PeerId,
+        reason: String,
+    },
+    /// A request from `peer` couldn't be answered
+    InboundFailure {
+        peer: PeerId,
+        protocol: &'static str,
+        reason: String,
+    },
+    /// We stopped listening on some addresses
+    ListenerFailed {
+        reason: String,
+    },
+}
+impl Event {
+    /// What the user should be told about this event, if anything.
+    pub fn notice(&self) -> Option<String> {
+        match self {
+            Event::Dialed { peer } => Some(format!("connected to {peer}, added to contacts")),
+            Event::DialFailed { target, reason } => {
+                Some(format!("couldn't reach {target}: {reason}"))
+            }
+            Event::Unsupported { peer, protocol } => Some(format!(
+                "{peer} doesn't support {protocol}, their app may be outdated"
+            )),
+            Event::MessageSendFailed { peer, reason, .. } => {
+                Some(format!("message to {peer} wasn't delivered: {reason}"))
+            }
+            Event::FriendRequestFailed { peer, reason, .. } => {
+                Some(format!("friend request to {peer} failed: {reason}"))
+            }
+            Event::FriendRequest { peer, request } => match request {
+                FriendRequest::AddFriend { name: Some(name) } => {
+                    Some(format!("{name} ({peer}) wants to be friends"))
+                }
+                FriendRequest::AddFriend { name: None } => {
+                    Some(format!("{peer} wants to be friends"))
+                }
+                FriendRequest::AcceptFriend { decision: true } => {
+                    Some(format!("{peer} accepted your friend request"))
+                }
+                FriendRequest::AcceptFriend { decision: false } => {
+                    Some(format!("{peer} declined your friend request"))
+                }
+                _ => None,
+            },
+            Event::ListenerFailed { reason } => Some(format!("stopped listening: {reason}")),
+            _ => None,
+        }
+    }
 }
 #[derive(NetworkBehaviour)]
 struct Behaviour {
-    mdns: mdns::tokio::Behaviour,
-    direct_message:
-        libp2p::request_response::cbor::Behaviour<DirectMessageRequest, DirectMessageResponse>,
-    friends:
-        libp2p::request_response::cbor::Behaviour<FriendRequest, signable::Signed<FriendResponse>>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    direct_message: request_response::Behaviour<MessageCodec>,
+    friends: request_response::Behaviour<FriendCodec>,
+    call: libp2p::request_response::cbor::Behaviour<CallRequest, CallResponse>,
+    presence: libp2p::request_response::cbor::Behaviour<PresenceUpdate, PresenceAck>,
+    stream: libp2p_stream::Behaviour,
+    relay_client: relay::client::Behaviour,
+    /// Only relays for others in relay server mode
+    relay: Toggle<relay::Behaviour>,
+    autonat: autonat::Behaviour,
+    dcutr: dcutr::Behaviour,
+    identify: identify::Behaviour,
 }
+/// Owns the swarm, [`EventLoop::run`] drives it until a shutdown command.
 pub struct EventLoop {
     swarm: Swarm<Behaviour>,
     command_rx: mpsc::Receiver<Command>,
     event_sender: mpsc::Sender<Event>,
     settings: Arc<tokio::sync::RwLock<HashMap<SettingName, Setting>>>,
     keys: Keypair,
-    tui_tx: UnboundedSender<crate::tui::Event>,
+    contacts: UnboundedSender<Contact>,
+    db: Database,
+    call: Option<Call>,
+    /// Latest clock of each conversation
+    clocks: HashMap<PeerId, Hlc>,
+    /// Peers we share our presence with
+    presence_peers: HashSet<PeerId>,
+    presence_heartbeat: tokio::time::Interval,
+    stream_control: libp2p_stream::Control,
+    incoming_audio: libp2p_stream::IncomingStreams,
+    /// Audio tasks report here when a call's audio stops on its own
+    audio_ended: UnboundedSender<AudioEnded>,
+    audio_ended_rx: mpsc::UnboundedReceiver<AudioEnded>,
+    /// Calls whose ring timeout ran out, ignored once they were picked up
+    call_timeouts: UnboundedSender<Uuid>,
+    call_timeouts_rx: mpsc::UnboundedReceiver<Uuid>,
+    /// Listeners on the ports from the settings, the ones that may be pinned
+    port_listeners: HashSet<ListenerId>,
+    /// Dials the user asked for, to report back how they went
+    pending_dials: HashMap<ConnectionId, DialTarget>,
+    /// Peers from imported contact cards, befriended once connected
+    pending_friend_requests: HashSet<PeerId>,
+    /// Open connections to each peer and whether they go through a relay
+    connections: HashMap<PeerId, HashMap<ConnectionId, bool>>,
+    /// Protocols each connected peer announced over identify
+    peer_protocols: HashMap<PeerId, HashSet<StreamProtocol>>,
+    /// Messages waiting for a response, to tell which one failed.
+    /// Request ids are only unique per protocol, so each has its own map.
+    outbound_messages: HashMap<OutboundRequestId, (Uuid, Reply<Uuid>)>,
+    outbound_friend_requests:
+        HashMap<OutboundRequestId, (FriendRequest, Option<Reply<FriendResponse>>)>,
 }
+/// Sends commands to the event loop, clones share it.
 #[derive(Clone)]
-pub(crate) struct Client {
+pub struct Client {
     pub command_sender: mpsc::Sender<Command>,
     settings: Arc<tokio::sync::RwLock<HashMap<SettingName, Setting>>>,
-    keys: Keypair,
     pub id: PeerId,
+    request_timeout: std::time::Duration,
+}
+impl Client {
+    /// A client whose commands are handled elsewhere than by a local event loop,
+    /// e.g. by the daemon we are attached to.
+    pub(crate) fn forwarding(
+        id: PeerId,
+        settings: Arc<tokio::sync::RwLock<HashMap<SettingName, Setting>>>,
+        request_timeout: std::time::Duration,
+    ) -> (Client, mpsc::Receiver<Command>) {
+        let (command_tx, command_rx) = mpsc::channel(100);
+        let client = Client {
+            command_sender: command_tx,
+            settings,
+            id,
+            request_timeout,
+        };
+        (client, command_rx)
+    }
 }
 impl EventLoop {
     fn new(
@@ -134,18 +464,78 @@ impl EventLoop {
         event_sender: mpsc::Sender<Event>,
         settings: Arc<tokio::sync::RwLock<HashMap<SettingName, Setting>>>,
         keys: Keypair,
-        tui_tx: UnboundedSender<crate::tui::Event>,
+        contacts: UnboundedSender<Contact>,
+        db: Database,
     ) -> Self {
+        let mut stream_control = swarm.behaviour().stream.new_control();
+        let incoming_audio = stream_control
+            .accept(AUDIO_PROTOCOL)
+            .expect("audio protocol to only be registered once");
+        let (audio_ended, audio_ended_rx) = mpsc::unbounded_channel();
+        let (call_timeouts, call_timeouts_rx) = mpsc::unbounded_channel();
         EventLoop {
             swarm,
             command_rx,
             event_sender,
             settings,
             keys,
-            tui_tx,
+            contacts,
+            db,
+            call: None,
+            clocks: HashMap::new(),
+            presence_peers: HashSet::new(),
+            presence_heartbeat: tokio::time::interval(presence::HEARTBEAT),
+            stream_control,
+            incoming_audio,
+            audio_ended,
+            audio_ended_rx,
+            call_timeouts,
+            call_timeouts_rx,
+            port_listeners: HashSet::new(),
+            pending_dials: HashMap::new(),
+            pending_friend_requests: HashSet::new(),
+            connections: HashMap::new(),
+            peer_protocols: HashMap::new(),
+            outbound_messages: HashMap::new(),
+            outbound_friend_requests: HashMap::new(),
+        }
+    }
+    async fn send_event(&mut self, event: Event) {
+        self.event_sender
+            .send(event)
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+    /// Our display name, `None` until one is set.
+    async fn own_name(&mut self) -> Option<String> {
+        let name = SettingName::Name;
+        match self.settings.read().await.get(&name).map(|s| s.get_value()) {
+            Some(SettingValue::String(name)) => name.clone(),
+            // settings handed to `Node::start` may leave it out
+            None => match &name.spec().default {
+                SettingValue::String(name) => name.clone(),
+                _ => None,
+            },
+            _ => None,
         }
     }
+    /// A request from `peer` failed before we could answer it.
+    async fn handle_inbound_failure(
+        &mut self,
+        peer: PeerId,
+        protocol: &'static str,
+        error: request_response::InboundFailure,
+    ) {
+        tracing::info!("{protocol} request from {peer} failed: {error}");
+        self.send_event(Event::InboundFailure {
+            peer,
+            protocol,
+            reason: error.to_string(),
+        })
+        .await;
+    }
     pub async fn run(mut self) {
+        self.redial_contacts().await;
         loop {
             tokio::select! {
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
@@ -153,8 +543,21 @@ impl EventLoop {
                     match command {
                         Command::ChatCommand(chat) => self.handle_chat_command(chat).await,
                         Command::FriendCommand(friend) => self.handle_friend_command(friend).await,
+                        Command::CallCommand(call) => self.handle_call_command(call).await,
+                        Command::PresenceCommand(presence) => self.handle_presence_command(presence).await,
+                        Command::DialCommand(dial) => self.handle_dial_command(dial).await,
+                        Command::ContactCardCommand(card) => self.handle_contact_card_command(card).await,
+                        Command::Shutdown(reply) => {
+                            self.shutdown().await;
+                            let _ = reply.send(Ok(()));
+                            return;
+                        }
                     }
                 },
+                Some((peer, stream)) = self.incoming_audio.next() => self.handle_audio_stream(peer, stream),
+                Some(ended) = self.audio_ended_rx.recv() => self.handle_audio_ended(ended).await,
+                Some(call_id) = self.call_timeouts_rx.recv() => self.handle_call_timeout(call_id).await,
+                _ = self.presence_heartbeat.tick() => self.broadcast_presence().await,
             }
         }
     }
@@ -162,159 +565,403 @@ impl EventLoop {
         match event {
             SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                 let mut known = Vec::<PeerId>::new();
-                for (peer_id, _multiaddr) in list {
+                for (peer_id, multiaddr) in list {
                     tracing::info!("{peer_id} peer connected!");
+                    self.record_address(peer_id, multiaddr).await;
                     if !known.contains(&peer_id) {
-                        let _ = self.tui_tx.send(crate::tui::Event::AddContact(
-                            crate::tui::types::Contact {
-                                peer_id,
-                                name: "Anonymous".to_string(),
-                            },
-                        ));
+                        let _ = self.contacts.send(Contact {
+                            peer: peer_id,
+                            name: "Anonymous".to_string(),
+                        });
                         known.push(peer_id);
+                        if self.presence_peers.insert(peer_id) {
+                            self.send_presence(peer_id).await;
+                        }
                     }
                 }
             }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                num_established,
+                endpoint,
+                ..
+            } => {
+                self.handle_connection_opened(peer_id, connection_id, &endpoint)
+                    .await;
+                if num_established.get() == 1 {
+                    self.send_event(Event::PeerConnected {
+                        peer: peer_id,
+                        address: endpoint.get_remote_address().clone(),
+                    })
+                    .await;
+                    self.handle_connection_change(peer_id, true).await;
+                }
+                // only dialed addresses are ones the peer listens on
+                if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                    self.record_dial(peer_id, address, true).await;
+                }
+                self.handle_dial_result(connection_id, Ok(peer_id)).await;
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                if let (Some(peer_id), DialError::Transport(errors)) = (peer_id, &error) {
+                    for (address, error) in errors {
+                        tracing::info!("failed to dial {peer_id} at {address}: {error}");
+                        self.record_dial(peer_id, address.clone(), false).await;
+                    }
+                }
+                // dials the user asked for are reported on their own
+                if let Some(peer) = peer_id
+                    && !self.pending_dials.contains_key(&connection_id)
+                {
+                    self.send_event(Event::ConnectionFailed {
+                        peer,
+                        reason: error.to_string(),
+                    })
+                    .await;
+                }
+                self.handle_dial_result(connection_id, Err(error.to_string()))
+                    .await;
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => {
+                tracing::info!("incoming connection from {send_back_addr} failed: {error}");
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                cause,
+                ..
+            } => {
+                self.handle_connection_closed(peer_id, connection_id).await;
+                if num_established == 0 {
+                    // they may come back with another version
+                    self.peer_protocols.remove(&peer_id);
+                    self.send_event(Event::PeerDisconnected {
+                        peer: peer_id,
+                        reason: cause.map(|err| err.to_string()),
+                    })
+                    .await;
+                    self.handle_connection_change(peer_id, false).await;
+                    self.handle_call_disconnect(peer_id).await;
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => {
+                self.handle_relay_client_event(event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => self.handle_relay_event(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(event)) => {
+                self.handle_autonat_event(event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => self.handle_dcutr_event(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
+                self.handle_identify_event(event).await
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                for (peer_id, _multiaddr) in list {
-                    tracing::info!("{peer_id} discovered via mDNS");
+                for (peer_id, multiaddr) in list {
+                    tracing::info!("{peer_id} no longer seen via mDNS at {multiaddr}");
                 }
             }
-            SwarmEvent::NewListenAddr { address, .. } => {
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
                 tracing::info!("Local node is listening on {address}");
+                self.pin_listen_port(listener_id, &address).await;
+                self.advertise_relay_address(address);
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                tracing::info!("no longer listening on {address}");
+            }
+            SwarmEvent::ListenerError { error, .. } => {
+                tracing::error!("listener failed: {error}");
+                self.send_event(Event::ListenerFailed {
+                    reason: error.to_string(),
+                })
+                .await;
+            }
+            SwarmEvent::ListenerClosed {
+                addresses,
+                reason: Err(error),
+                ..
+            } => {
+                tracing::error!("stopped listening on {addresses:?}: {error}");
+                self.send_event(Event::ListenerFailed {
+                    reason: error.to_string(),
+                })
+                .await;
+            }
+            SwarmEvent::NewExternalAddrCandidate { address } => {
+                tracing::debug!("peers see us at {address}");
+            }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                tracing::info!("reachable at {address}");
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                tracing::info!("no longer reachable at {address}");
             }
 
             SwarmEvent::Behaviour(BehaviourEvent::DirectMessage(
-                request_response::Event::Message { message, .. },
+                request_response::Event::Message { peer, message, .. },
             )) => match message {
                 request_response::Message::Request {
                     request, channel, ..
                 } => {
-                    // TODO: remove this unwrap
-                    let (message, sender) = request.0.verify().expect("to be verified");
-                    // if message is valid, send
-                    self.swarm
+                    let response = self.handle_inbound_message(peer, request).await;
+                    if self
+                        .swarm
                         .behaviour_mut()
                         .direct_message
-                        .send_response(
-                            channel,
-                            DirectMessageResponse(MessageResponse::ACK {
-                                message_id: message.id,
-                            }),
-                        )
-                        .expect("to be sent");
-                    self.event_sender
-                        .send(Event::InboundMessage {
-                            message,
-                            sender: Box::new(sender),
-                        })
-                        .await
-                        .expect("Event receiver not to be dropped.");
-                }
-                request_response::Message::Response { response, .. } => match response {
-                    DirectMessageResponse(MessageResponse::ACK { message_id }) => {
-                        self.event_sender
-                            .send(Event::OutboundMessageReceived { message_id })
-                            .await
-                            .expect("Event receiver not to be dropped.");
-                    }
-                    DirectMessageResponse(MessageResponse::InvalidSignature { message_id }) => {
-                        self.event_sender
-                            .send(Event::OutboundMessageInvalidSignature { message_id })
-                            .await
-                            .expect("Event receiver not to be dropped");
+                        .send_response(channel, DirectMessageResponse(response))
+                        .is_err()
+                    {
+                        tracing::info!("{peer} stopped waiting for message ack");
                     }
-                },
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => self.handle_message_response(request_id, response).await,
             },
             SwarmEvent::Behaviour(BehaviourEvent::Friends(request_response::Event::Message {
                 peer,
-                connection_id,
                 message,
+                ..
             })) => match message {
                 request_response::Message::Request {
-                    request_id,
-                    request,
-                    channel,
+                    request, channel, ..
                 } => match request {
                     FriendRequest::RequestName => {
-                        let lock = self.settings.read().await;
-                        let name = lock.get(&SettingName::Name);
-                        self.swarm
-                            .behaviour_mut()
-                            .friends
-                            .send_response(
-                                channel,
-                                sign(
-                                    FriendResponse::RequestName {
-                                        name: match name.unwrap().get_value() {
-                                            SettingValue::String(val) => {
-                                                val.clone().unwrap_or("Anonymous".to_string())
-                                            }
-                                            _ => unimplemented!("undefined behaviour"),
-                                        },
-                                    },
-                                    &self.keys,
-                                ),
-                            )
-                            .expect("On Name request to be sent");
+                        let name = self.own_name().await.unwrap_or("Anonymous".to_string());
+                        self.answer_friend_request(
+                            peer,
+                            channel,
+                            FriendResponse::RequestName { name },
+                        );
                     }
                     FriendRequest::VerifyName { name } => {
-                        let lock = self.settings.read().await;
-                        let SettingValue::String(Some(curr_name)) = lock
-                            .get(&SettingName::Name)
-                            .expect("name opt to exist")
-                            .get_value()
-                        else {
-                            unimplemented!("");
-                        };
-                        self.swarm
-                            .behaviour_mut()
-                            .friends
-                            .send_response(
-                                channel,
-                                sign(
-                                    FriendResponse::VerifyName(match name == *curr_name {
-                                        true => None,
-                                        false => Some(curr_name.clone()),
-                                    }),
-                                    &self.keys,
-                                ),
-                            )
-                            .expect("to send res");
+                        let curr_name = self.own_name().await.unwrap_or("Anonymous".to_string());
+                        self.answer_friend_request(
+                            peer,
+                            channel,
+                            FriendResponse::VerifyName(match name == curr_name {
+                                true => None,
+                                false => Some(curr_name),
+                            }),
+                        );
                     }
                     FriendRequest::AcceptFriend { decision } => {
-                        self.swarm
-                            .behaviour_mut()
-                            .friends
-                            .send_response(
-                                channel,
-                                sign(FriendResponse::AcceptFriendAck, &self.keys),
-                            )
-                            .expect("to send res");
+                        self.answer_friend_request(peer, channel, FriendResponse::AcceptFriendAck);
+                        self.send_event(Event::FriendRequest {
+                            peer,
+                            request: FriendRequest::AcceptFriend { decision },
+                        })
+                        .await;
+                    }
+                    FriendRequest::AddFriend { name } => {
+                        self.answer_friend_request(peer, channel, FriendResponse::AddFriendAck);
+                        self.send_event(Event::FriendRequest {
+                            peer,
+                            request: FriendRequest::AddFriend { name },
+                        })
+                        .await;
                     }
-                    FriendRequest::AddFriend => self
-                        .swarm
-                        .behaviour_mut()
-                        .friends
-                        .send_response(channel, sign(FriendResponse::AddFriendAck, &self.keys))
-                        .expect("to send res"),
                 },
 
                 request_response::Message::Response {
                     request_id,
                     response,
+                } => self.handle_friend_response(peer, request_id, response),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Call(request_response::Event::Message {
+                peer,
+                message,
+                ..
+            })) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
                 } => {
-                    if let Some((resp, sender)) = response.verify() {
-                        match resp {
-                            FriendResponse::RequestName { name } => {}
-                            FriendResponse::VerifyName(name) => {}
-                            FriendResponse::AddFriendAck => {}
-                            FriendResponse::AcceptFriendAck => {}
-                        }
+                    let ack = self.handle_call_request(peer, request).await;
+                    if self
+                        .swarm
+                        .behaviour_mut()
+                        .call
+                        .send_response(channel, CallResponse(ack))
+                        .is_err()
+                    {
+                        tracing::info!("{peer} stopped waiting for call ack");
                     }
                 }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => self.handle_call_response(request_id, response).await,
             },
-            _ => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Presence(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            })) => {
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .presence
+                    .send_response(channel, PresenceAck);
+                self.handle_presence_update(peer, request).await;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Call(
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                    ..
+                },
+            )) => {
+                tracing::error!("call signal to {peer} failed: {error}");
+                self.handle_call_failure(request_id).await;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::DirectMessage(
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                    ..
+                },
+            )) => self.handle_message_failure(peer, request_id, error).await,
+            SwarmEvent::Behaviour(BehaviourEvent::Friends(
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                    ..
+                },
+            )) => self.handle_friend_failure(peer, request_id, error).await,
+            SwarmEvent::Behaviour(BehaviourEvent::Presence(
+                request_response::Event::OutboundFailure { peer, error, .. },
+            )) => tracing::debug!("presence update to {peer} failed: {error}"),
+            // acks carry nothing
+            SwarmEvent::Behaviour(BehaviourEvent::Presence(request_response::Event::Message {
+                message: request_response::Message::Response { .. },
+                ..
+            })) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::DirectMessage(
+                request_response::Event::InboundFailure { peer, error, .. },
+            )) => {
+                self.handle_inbound_failure(peer, "direct message", error)
+                    .await
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Friends(
+                request_response::Event::InboundFailure { peer, error, .. },
+            )) => self.handle_inbound_failure(peer, "friends", error).await,
+            SwarmEvent::Behaviour(BehaviourEvent::Call(
+                request_response::Event::InboundFailure { peer, error, .. },
+            )) => self.handle_inbound_failure(peer, "call", error).await,
+            SwarmEvent::Behaviour(BehaviourEvent::Presence(
+                request_response::Event::InboundFailure { peer, error, .. },
+            )) => self.handle_inbound_failure(peer, "presence", error).await,
+            SwarmEvent::Behaviour(
+                BehaviourEvent::DirectMessage(request_response::Event::ResponseSent {
+                    peer, ..
+                })
+                | BehaviourEvent::Friends(request_response::Event::ResponseSent { peer, .. })
+                | BehaviourEvent::Call(request_response::Event::ResponseSent { peer, .. })
+                | BehaviourEvent::Presence(request_response::Event::ResponseSent { peer, .. }),
+            ) => tracing::trace!("responded to {peer}"),
+            SwarmEvent::Dialing {
+                peer_id: Some(peer),
+                ..
+            } => tracing::debug!("dialing {peer}"),
+            SwarmEvent::Dialing { peer_id: None, .. } => tracing::debug!("dialing an address"),
+            SwarmEvent::IncomingConnection { send_back_addr, .. } => {
+                tracing::debug!("incoming connection from {send_back_addr}")
+            }
+            SwarmEvent::ListenerClosed {
+                addresses,
+                reason: Ok(()),
+                ..
+            } => tracing::info!("stopped listening on {addresses:?}"),
+            // identify already records what peers tell us about themselves
+            SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
+                tracing::trace!("{peer_id} is reachable at {address}")
+            }
+            // streams are handed over through `stream_control`, not as events
+            SwarmEvent::Behaviour(BehaviourEvent::Stream(())) => {}
+            // SwarmEvent is non_exhaustive, this only catches variants added upstream
+            event => tracing::debug!("unhandled swarm event {event:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::settings::registry;
+    use clap::Parser;
+
+    fn config(listen: &[&str], relays: &[&str]) -> NetworkConfig {
+        let parse = |addresses: &[&str]| addresses.iter().map(|a| a.parse().unwrap()).collect();
+        NetworkConfig {
+            relays: parse(relays),
+            ..NetworkConfig::local(parse(listen))
         }
     }
+    async fn start(config: NetworkConfig) -> Result<(), NetworkError> {
+        let (contacts, _) = mpsc::unbounded_channel();
+        let db = Database::open_in_memory().await.unwrap();
+        let keys = Keypair::generate_ed25519();
+        new(Default::default(), contacts, db, keys, config)
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn turning_both_transports_off_leaves_nothing_to_listen_on() {
+        let mut settings: HashMap<_, _> = registry()
+            .iter()
+            .map(|spec| (spec.name, spec.default_setting()))
+            .collect();
+        for name in [SettingName::ListenTcp, SettingName::ListenQuic] {
+            settings
+                .get_mut(&name)
+                .unwrap()
+                .set_value(name, SettingValue::Bool(false))
+                .unwrap();
+        }
+        let network = Config::try_parse_from(["p2pchat"])
+            .unwrap()
+            .network(&settings);
+        assert!(matches!(
+            start(network).await,
+            Err(NetworkError::NoListenAddress)
+        ));
+    }
+    #[tokio::test]
+    async fn unusable_addresses_are_reported_with_the_address() {
+        let result = start(config(&["/ip4/127.0.0.1/udp/0"], &[])).await;
+        assert!(matches!(
+            result,
+            Err(NetworkError::Listen { address, .. }) if address.to_string() == "/ip4/127.0.0.1/udp/0"
+        ));
+
+        let relay = "/ip4/127.0.0.1/tcp/4001";
+        let result = start(config(&["/ip4/127.0.0.1/tcp/0"], &[relay])).await;
+        assert!(matches!(
+            result,
+            Err(NetworkError::Listen { address, .. }) if address.to_string() == relay
+        ));
+
+        assert!(start(config(&["/ip4/127.0.0.1/tcp/0"], &[])).await.is_ok());
+    }
 }
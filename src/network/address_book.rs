@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::{Multiaddr, PeerId};
+
+use crate::network::EventLoop;
+use crate::settings::{Setting, SettingName, SettingValue, Settings};
+
+// Addresses tried per contact when redialing on startup
+const REDIAL_ADDRESSES: usize = 5;
+
+impl EventLoop {
+    /// Dials every contact at the addresses we last reached them on.
+    pub(crate) async fn redial_contacts(&mut self) {
+        let addresses = match self.db.contact_addresses().await {
+            Ok(addresses) => addresses,
+            Err(err) => {
+                tracing::error!("failed to load the address book: {err}");
+                return;
+            }
+        };
+        // addresses come sorted best first
+        let mut peers = HashMap::<PeerId, Vec<Multiaddr>>::new();
+        for entry in addresses {
+            let known = peers.entry(entry.peer_id).or_default();
+            if known.len() < REDIAL_ADDRESSES {
+                known.push(entry.address);
+            }
+        }
+        let local_id = *self.swarm.local_peer_id();
+        for (peer, addresses) in peers {
+            if peer == local_id || self.swarm.is_connected(&peer) {
+                continue;
+            }
+            tracing::info!("redialing {peer} at {} known addresses", addresses.len());
+            let opts = DialOpts::peer_id(peer).addresses(addresses).build();
+            if let Err(err) = self.swarm.dial(opts) {
+                tracing::info!("failed to redial {peer}: {err}");
+            }
+        }
+    }
+    pub(crate) async fn record_address(&mut self, peer: PeerId, address: Multiaddr) {
+        if let Err(err) = self.db.record_address(peer, without_peer(address)).await {
+            tracing::error!("failed to store address of {peer}: {err}");
+        }
+    }
+    pub(crate) async fn record_dial(&mut self, peer: PeerId, address: Multiaddr, success: bool) {
+        if let Err(err) = self
+            .db
+            .record_dial(peer, without_peer(address), success)
+            .await
+        {
+            tracing::error!("failed to store dial of {peer}: {err}");
+        }
+    }
+    /// Saves ports the OS picked for us, so peers find us on the same ones after a restart.
+    /// Only for listeners started from the settings, addresses given on the command line
+    /// aren't written to them.
+    pub(crate) async fn pin_listen_port(&mut self, listener: ListenerId, address: &Multiaddr) {
+        if !self.port_listeners.contains(&listener) {
+            return;
+        }
+        let mut settings = self.settings.write().await;
+        let Some((name, port)) = port_to_pin(&settings, address) else {
+            return;
+        };
+        let Some(setting) = settings.get_mut(&name) else {
+            return;
+        };
+        match setting.set_value(name, SettingValue::Int(port.into())) {
+            Ok(()) => {
+                tracing::info!("pinning {name:?} to {port}");
+                if let Err(err) = Settings::save(&settings).await {
+                    tracing::error!("failed to save the pinned port: {err}");
+                }
+            }
+            Err(err) => tracing::error!("failed to pin {name:?} to {port}: {err}"),
+        }
+    }
+}
+/// The port setting to save `address` to, if pinning is on and the OS picked the port.
+fn port_to_pin(
+    settings: &HashMap<SettingName, Setting>,
+    address: &Multiaddr,
+) -> Option<(SettingName, u16)> {
+    let value = |name| settings.get(&name).map(|s| s.get_value());
+    if value(SettingName::PinPorts) != Some(&SettingValue::Bool(true)) {
+        return None;
+    }
+    listen_port(address).filter(|(name, _)| value(*name) == Some(&SettingValue::Int(0)))
+}
+/// The setting holding the port of a listen address, relayed addresses have none of ours.
+fn listen_port(address: &Multiaddr) -> Option<(SettingName, u16)> {
+    if address
+        .iter()
+        .any(|protocol| protocol == Protocol::P2pCircuit)
+    {
+        return None;
+    }
+    address.iter().fold(None, |port, protocol| match protocol {
+        Protocol::Tcp(port) => Some((SettingName::TcpPort, port)),
+        Protocol::Udp(port) => Some((SettingName::QuicPort, port)),
+        _ => port,
+    })
+}
+/// The address book stores peer ids separately
+fn without_peer(mut address: Multiaddr) -> Multiaddr {
+    if let Some(Protocol::P2p(_)) = address.iter().last() {
+        address.pop();
+    }
+    address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(address: &str) -> Multiaddr {
+        address.parse().unwrap()
+    }
+
+    fn settings(pin: bool, tcp_port: i32) -> HashMap<SettingName, Setting> {
+        let mut settings: HashMap<_, _> = crate::settings::registry()
+            .iter()
+            .map(|spec| (spec.name, spec.default_setting()))
+            .collect();
+        for (name, value) in [
+            (SettingName::PinPorts, SettingValue::Bool(pin)),
+            (SettingName::TcpPort, SettingValue::Int(tcp_port)),
+            (SettingName::QuicPort, SettingValue::Int(0)),
+        ] {
+            settings
+                .get_mut(&name)
+                .unwrap()
+                .set_value(name, value)
+                .unwrap();
+        }
+        settings
+    }
+
+    #[test]
+    fn listen_ports_map_to_their_transport_setting() {
+        assert_eq!(
+            listen_port(&addr("/ip4/0.0.0.0/tcp/4001")),
+            Some((SettingName::TcpPort, 4001))
+        );
+        assert_eq!(
+            listen_port(&addr("/ip6/::/udp/4002/quic-v1")),
+            Some((SettingName::QuicPort, 4002))
+        );
+        assert_eq!(listen_port(&addr("/ip4/127.0.0.1")), None);
+    }
+    #[test]
+    fn relayed_listen_addresses_have_no_port_of_ours() {
+        let relay = "12D3KooWGknJjAcsrsRmuUqedcENqav41nvuxcdLR8mon5UWqqT8";
+        let circuit = addr(&format!(
+            "/ip4/203.0.113.7/tcp/4001/p2p/{relay}/p2p-circuit"
+        ));
+        assert_eq!(listen_port(&circuit), None);
+    }
+    #[test]
+    fn only_ports_the_os_picked_are_pinned() {
+        let tcp = addr("/ip4/0.0.0.0/tcp/4001");
+        assert_eq!(
+            port_to_pin(&settings(true, 0), &tcp),
+            Some((SettingName::TcpPort, 4001))
+        );
+        assert_eq!(port_to_pin(&settings(false, 0), &tcp), None);
+        // a port the user chose stays
+        assert_eq!(port_to_pin(&settings(true, 5000), &tcp), None);
+        assert_eq!(
+            port_to_pin(
+                &settings(true, 5000),
+                &addr("/ip4/0.0.0.0/udp/4002/quic-v1")
+            ),
+            Some((SettingName::QuicPort, 4002))
+        );
+        let relay = "12D3KooWGknJjAcsrsRmuUqedcENqav41nvuxcdLR8mon5UWqqT8";
+        let circuit = addr(&format!(
+            "/ip4/203.0.113.7/tcp/4001/p2p/{relay}/p2p-circuit"
+        ));
+        assert_eq!(port_to_pin(&settings(true, 0), &circuit), None);
+    }
+    #[test]
+    fn addresses_are_stored_without_the_peer_id() {
+        let peer = PeerId::random();
+        let address = addr("/ip4/192.168.1.20/tcp/4001");
+        assert_eq!(
+            without_peer(address.clone().with_p2p(peer).unwrap()),
+            address
+        );
+        assert_eq!(without_peer(address.clone()), address);
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::HashSet;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::OutboundRequestId;
+use libp2p::{PeerId, StreamProtocol, identity};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::audio::{self, AudioInput, AudioOutput, Codec, CodecKind};
+use crate::network::signable::{Signed, sign};
+use crate::network::{Client, Command, Event, EventLoop};
+
+pub const AUDIO_PROTOCOL: StreamProtocol = StreamProtocol::new("/call-audio/1");
+// How long a call rings before it counts as unanswered
+const RING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(45);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CallRequest(pub Signed<CallSignal>);
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CallResponse(pub CallAck);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CallSignal {
+    Offer {
+        call_id: Uuid,
+        codecs: Vec<CodecKind>,
+    },
+    Accept {
+        call_id: Uuid,
+        codec: CodecKind,
+    },
+    Reject {
+        call_id: Uuid,
+    },
+    Hangup {
+        call_id: Uuid,
+    },
+}
+impl CallSignal {
+    fn call_id(&self) -> Uuid {
+        match self {
+            CallSignal::Offer { call_id, .. }
+            | CallSignal::Accept { call_id, .. }
+            | CallSignal::Reject { call_id }
+            | CallSignal::Hangup { call_id } => *call_id,
+        }
+    }
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CallAck {
+    Ack {
+        call_id: Uuid,
+    },
+    Busy {
+        call_id: Uuid,
+    },
+    InvalidSignature {
+        call_id: Uuid,
+    },
+    /// None of the offered codecs can be decoded
+    NoCommonCodec {
+        call_id: Uuid,
+    },
+    /// The callee was built without audio devices
+    NoAudio {
+        call_id: Uuid,
+    },
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallState {
+    /// We offered a call and wait for the peer to pick up
+    Dialing,
+    /// The peer offered a call and waits for us to pick up
+    Ringing,
+    Active,
+    Ended(CallEnd),
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEnd {
+    HungUp,
+    Rejected,
+    Busy,
+    Failed,
+    /// Nobody picked up before the call stopped ringing
+    NoAnswer,
+    NoCommonCodec,
+    /// This node or the peer can't play or record audio
+    NoAudio,
+}
+pub enum CallCommand {
+    Offer { peer: PeerId },
+    Accept { call_id: Uuid },
+    Reject { call_id: Uuid },
+    Hangup { call_id: Uuid },
+}
+pub(crate) struct Call {
+    id: Uuid,
+    peer: PeerId,
+    state: CallState,
+    codec: Option<CodecKind>,
+    audio: Option<CancellationToken>,
+    /// Signals for this call still waiting for their ack
+    signals: HashSet<OutboundRequestId>,
+}
+impl Call {
+    fn stop_audio(&mut self) {
+        if let Some(token) = self.audio.take() {
+            token.cancel();
+        }
+    }
+}
+impl EventLoop {
+    pub async fn handle_call_command(&mut self, command: CallCommand) {
+        match command {
+            CallCommand::Offer { peer } => {
+                if self.call.is_some() {
+                    self.emit_call(Uuid::new_v4(), peer, CallState::Ended(CallEnd::Busy))
+                        .await;
+                    return;
+                }
+                let call_id = Uuid::new_v4();
+                if !audio::available() {
+                    self.emit_call(call_id, peer, CallState::Ended(CallEnd::NoAudio))
+                        .await;
+                    return;
+                }
+                self.call = Some(Call {
+                    id: call_id,
+                    peer,
+                    state: CallState::Dialing,
+                    codec: None,
+                    audio: None,
+                    signals: HashSet::new(),
+                });
+                self.send_call_signal(
+                    peer,
+                    CallSignal::Offer {
+                        call_id,
+                        codecs: CodecKind::supported(),
+                    },
+                );
+                self.ring_timeout(call_id);
+                self.emit_call(call_id, peer, CallState::Dialing).await;
+            }
+            CallCommand::Accept { call_id } => {
+                let Some(call) = self.call.as_mut().filter(|c| c.id == call_id) else {
+                    return;
+                };
+                let (Some(codec), CallState::Ringing) = (call.codec, call.state) else {
+                    return;
+                };
+                call.state = CallState::Active;
+                let peer = call.peer;
+                // The caller opens the audio stream once it sees our accept
+                self.send_call_signal(peer, CallSignal::Accept { call_id, codec });
+                self.emit_call(call_id, peer, CallState::Active).await;
+            }
+            CallCommand::Reject { call_id } => {
+                self.end_call(call_id, CallSignal::Reject { call_id }, CallEnd::Rejected)
+                    .await;
+            }
+            CallCommand::Hangup { call_id } => {
+                self.end_call(call_id, CallSignal::Hangup { call_id }, CallEnd::HungUp)
+                    .await;
+            }
+        }
+    }
+    async fn end_call(&mut self, call_id: Uuid, signal: CallSignal, reason: CallEnd) {
+        let Some(mut call) = self.call.take_if(|c| c.id == call_id) else {
+            return;
+        };
+        call.stop_audio();
+        self.send_call_signal(call.peer, signal);
+        self.emit_call(call_id, call.peer, CallState::Ended(reason))
+            .await;
+    }
+    fn send_call_signal(&mut self, peer: PeerId, signal: CallSignal) {
+        let call_id = signal.call_id();
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .call
+            .send_request(&peer, CallRequest(sign(signal, &self.keys)));
+        if let Some(call) = self.call.as_mut().filter(|c| c.id == call_id) {
+            call.signals.insert(request_id);
+        }
+    }
+    async fn emit_call(&mut self, call_id: Uuid, peer: PeerId, state: CallState) {
+        self.event_sender
+            .send(Event::Call {
+                call_id,
+                peer,
+                state,
+            })
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+    /// Handles a signal sent to us by `peer`, returning the acknowledgement to respond with.
+    pub(crate) async fn handle_call_request(
+        &mut self,
+        peer: PeerId,
+        request: CallRequest,
+    ) -> CallAck {
+        let call_id = request.0.content().call_id();
+        let Some((signal, key)) = request.0.verify() else {
+            return CallAck::InvalidSignature { call_id };
+        };
+        if PeerId::from_public_key(&identity::PublicKey::from(key)) != peer {
+            return CallAck::InvalidSignature { call_id };
+        }
+        let ours = self
+            .call
+            .as_ref()
+            .is_some_and(|c| c.id == call_id && c.peer == peer);
+        match signal {
+            CallSignal::Offer { codecs, .. } => {
+                if self.call.is_some() {
+                    return CallAck::Busy { call_id };
+                }
+                if !audio::available() {
+                    return CallAck::NoAudio { call_id };
+                }
+                let Some(codec) = CodecKind::negotiate(&codecs) else {
+                    return CallAck::NoCommonCodec { call_id };
+                };
+                self.call = Some(Call {
+                    id: call_id,
+                    peer,
+                    state: CallState::Ringing,
+                    codec: Some(codec),
+                    audio: None,
+                    signals: HashSet::new(),
+                });
+                self.ring_timeout(call_id);
+                self.emit_call(call_id, peer, CallState::Ringing).await;
+            }
+            CallSignal::Accept { codec, .. } if ours => {
+                let call = self.call.as_mut().expect("call to exist");
+                if call.state != CallState::Dialing {
+                    return CallAck::Ack { call_id };
+                }
+                call.state = CallState::Active;
+                call.codec = Some(codec);
+                let token = CancellationToken::new();
+                call.audio = Some(token.clone());
+                let mut control = self.stream_control.clone();
+                let ended = self.audio_ended.clone();
+                tokio::spawn(async move {
+                    let stream = control
+                        .open_stream(peer, AUDIO_PROTOCOL)
+                        .await
+                        .map_err(std::io::Error::other);
+                    stream_audio(stream, call_id, codec, token, ended).await
+                });
+                self.emit_call(call_id, peer, CallState::Active).await;
+            }
+            CallSignal::Reject { .. } if ours => {
+                self.call = None;
+                self.emit_call(call_id, peer, CallState::Ended(CallEnd::Rejected))
+                    .await;
+            }
+            CallSignal::Hangup { .. } if ours => {
+                if let Some(mut call) = self.call.take() {
+                    call.stop_audio();
+                }
+                self.emit_call(call_id, peer, CallState::Ended(CallEnd::HungUp))
+                    .await;
+            }
+            _ => tracing::info!("ignoring call signal for unknown call {call_id}"),
+        }
+        CallAck::Ack { call_id }
+    }
+    pub(crate) async fn handle_call_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        response: CallResponse,
+    ) {
+        if let Some(call) = self.call.as_mut() {
+            call.signals.remove(&request_id);
+        }
+        match response.0 {
+            CallAck::Ack { .. } => {}
+            CallAck::Busy { call_id } => self.drop_call(call_id, CallEnd::Busy).await,
+            CallAck::InvalidSignature { call_id } => self.drop_call(call_id, CallEnd::Failed).await,
+            CallAck::NoCommonCodec { call_id } => {
+                self.drop_call(call_id, CallEnd::NoCommonCodec).await
+            }
+            CallAck::NoAudio { call_id } => self.drop_call(call_id, CallEnd::NoAudio).await,
+        }
+    }
+    /// The peer is gone, so the call can't go on.
+    pub(crate) async fn handle_call_disconnect(&mut self, peer: PeerId) {
+        if let Some(call_id) = self.call.as_ref().filter(|c| c.peer == peer).map(|c| c.id) {
+            self.drop_call(call_id, CallEnd::Failed).await;
+        }
+    }
+    fn ring_timeout(&self, call_id: Uuid) {
+        let timeouts = self.call_timeouts.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RING_TIMEOUT).await;
+            let _ = timeouts.send(call_id);
+        });
+    }
+    /// Ends the call if it is still ringing, the peer is told so it stops ringing too.
+    pub(crate) async fn handle_call_timeout(&mut self, call_id: Uuid) {
+        let signal = match self.call.as_ref().filter(|c| c.id == call_id) {
+            Some(call) if call.state == CallState::Dialing => CallSignal::Hangup { call_id },
+            Some(call) if call.state == CallState::Ringing => CallSignal::Reject { call_id },
+            _ => return,
+        };
+        self.end_call(call_id, signal, CallEnd::NoAnswer).await;
+    }
+    /// A signal didn't reach the peer, which only ends the call it was sent for.
+    pub(crate) async fn handle_call_failure(&mut self, request_id: OutboundRequestId) {
+        let failed = self
+            .call
+            .as_ref()
+            .filter(|c| c.signals.contains(&request_id))
+            .map(|c| c.id);
+        if let Some(call_id) = failed {
+            self.drop_call(call_id, CallEnd::Failed).await;
+        }
+    }
+    /// Ends a call locally without notifying the peer, used when signaling failed.
+    async fn drop_call(&mut self, call_id: Uuid, reason: CallEnd) {
+        if let Some(mut call) = self.call.take_if(|c| c.id == call_id) {
+            call.stop_audio();
+            self.emit_call(call_id, call.peer, CallState::Ended(reason))
+                .await;
+        }
+    }
+    pub(crate) fn handle_audio_stream(&mut self, peer: PeerId, stream: libp2p::Stream) {
+        let Some(call) = self
+            .call
+            .as_mut()
+            .filter(|c| c.peer == peer && c.state == CallState::Active && c.audio.is_none())
+        else {
+            tracing::info!("{peer} opened an audio stream outside of a call");
+            return;
+        };
+        let codec = call.codec.expect("active call to have a codec");
+        let token = CancellationToken::new();
+        call.audio = Some(token.clone());
+        let ended = self.audio_ended.clone();
+        tokio::spawn(stream_audio(Ok(stream), call.id, codec, token, ended));
+    }
+    /// The audio of a call stopped without either side ending the call.
+    pub(crate) async fn handle_audio_ended(&mut self, (call_id, result): AudioEnded) {
+        match result {
+            // the peer closed the stream, its hangup ends the call
+            Ok(()) => tracing::info!("the peer stopped sending audio for call {call_id}"),
+            Err(err) => {
+                tracing::error!("call audio failed: {err}");
+                let signal = CallSignal::Hangup { call_id };
+                self.end_call(call_id, signal, CallEnd::Failed).await;
+            }
+        }
+    }
+}
+
+/// How a call's audio stopped when it wasn't cancelled, reported to the event loop.
+pub(crate) type AudioEnded = (Uuid, std::io::Result<()>);
+
+/// Runs the audio of a call on `stream` until it is cancelled or stops on its own.
+async fn stream_audio<S>(
+    stream: std::io::Result<S>,
+    call_id: Uuid,
+    codec: CodecKind,
+    token: CancellationToken,
+    ended: mpsc::UnboundedSender<AudioEnded>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let devices = audio::open_input()
+        .and_then(|input| Ok((input, audio::open_output()?)))
+        .and_then(|(input, output)| Ok((input, output, codec.build()?, codec.build()?)));
+    let result = match (stream, devices) {
+        (Ok(stream), Ok((input, output, encoder, decoder))) => {
+            exchange_audio(stream, input, output, encoder, decoder, &token).await
+        }
+        (Err(err), _) | (_, Err(err)) => Err(err),
+    };
+    if !token.is_cancelled() {
+        let _ = ended.send((call_id, result));
+    }
+}
+/// Sends our input and plays what the peer sends. Once our input runs out we keep
+/// listening, it ends when the peer closes the stream.
+async fn exchange_audio<S>(
+    stream: S,
+    input: Box<dyn AudioInput>,
+    output: Box<dyn AudioOutput>,
+    mut encoder: Box<dyn Codec>,
+    mut decoder: Box<dyn Codec>,
+    token: &CancellationToken,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = stream.split();
+    let sending = async {
+        send_audio(&mut writer, capture(input), encoder.as_mut()).await?;
+        std::future::pending().await
+    };
+    tokio::select! {
+        _ = token.cancelled() => Ok(()),
+        res = sending => res,
+        res = receive_audio(&mut reader, playback(output), decoder.as_mut()) => res,
+    }
+}
+// Devices block until a frame is captured or played, so each gets a thread of its own
+// that stops once the call drops its end of the channel.
+fn capture(mut input: Box<dyn AudioInput>) -> mpsc::Receiver<std::io::Result<Vec<i16>>> {
+    let (tx, rx) = mpsc::channel(4);
+    std::thread::spawn(move || {
+        loop {
+            let mut frame = vec![0i16; audio::FRAME_SAMPLES];
+            let read = match input.read_frame(&mut frame) {
+                Ok(true) => Ok(frame),
+                Ok(false) => break,
+                Err(err) => Err(err),
+            };
+            let failed = read.is_err();
+            if tx.blocking_send(read).is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
+fn playback(mut output: Box<dyn AudioOutput>) -> mpsc::Sender<Vec<i16>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<i16>>(4);
+    std::thread::spawn(move || {
+        while let Some(frame) = rx.blocking_recv() {
+            if let Err(err) = output.write_frame(&frame) {
+                tracing::error!("failed to play call audio: {err}");
+                break;
+            }
+        }
+    });
+    tx
+}
+// Audio is framed as a big endian u16 length followed by the encoded packet
+async fn send_audio<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut frames: mpsc::Receiver<std::io::Result<Vec<i16>>>,
+    encoder: &mut dyn Codec,
+) -> std::io::Result<()> {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs_f64(
+        audio::FRAME_SAMPLES as f64 / audio::SAMPLE_RATE as f64,
+    ));
+    loop {
+        interval.tick().await;
+        let Some(frame) = frames.recv().await else {
+            return Ok(());
+        };
+        let packet = encoder.encode(&frame?)?;
+        writer
+            .write_all(&(packet.len() as u16).to_be_bytes())
+            .await?;
+        writer.write_all(&packet).await?;
+        writer.flush().await?;
+    }
+}
+async fn receive_audio<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    playback: mpsc::Sender<Vec<i16>>,
+    decoder: &mut dyn Codec,
+) -> std::io::Result<()> {
+    let mut len = [0u8; 2];
+    loop {
+        match reader.read_exact(&mut len).await {
+            // the peer closed the stream
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            read => read?,
+        }
+        let mut packet = vec![0u8; u16::from_be_bytes(len) as usize];
+        reader.read_exact(&mut packet).await?;
+        let frame = decoder.decode(&packet)?;
+        if playback.send(frame).await.is_err() {
+            return Err(std::io::Error::other("audio output stopped"));
+        }
+    }
+}
+impl Client {
+    pub async fn start_call(&mut self, peer: PeerId) {
+        self.send_call_command(CallCommand::Offer { peer }).await;
+    }
+    pub async fn accept_call(&mut self, call_id: Uuid) {
+        self.send_call_command(CallCommand::Accept { call_id })
+            .await;
+    }
+    pub async fn reject_call(&mut self, call_id: Uuid) {
+        self.send_call_command(CallCommand::Reject { call_id })
+            .await;
+    }
+    pub async fn hangup(&mut self, call_id: Uuid) {
+        self.send_call_command(CallCommand::Hangup { call_id })
+            .await;
+    }
+    async fn send_call_command(&mut self, command: CallCommand) {
+        self.command_sender
+            .send(Command::CallCommand(command))
+            .await
+            .expect("to send call command");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+    use crate::audio::{FRAME_SAMPLES, FileInput, FileOutput};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2pchat-audio-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    fn tone(frames: usize, step: i16) -> Vec<i16> {
+        (0..frames * FRAME_SAMPLES)
+            .map(|i| (i as i16).wrapping_mul(step))
+            .collect()
+    }
+    fn write_pcm(path: &Path, samples: &[i16]) {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        std::fs::write(path, bytes).unwrap();
+    }
+    fn read_pcm(path: &Path) -> Vec<i16> {
+        std::fs::read(path)
+            .unwrap_or_default()
+            .chunks_exact(2)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn audio_loops_back_between_file_devices() {
+        let dir = temp_dir();
+        // the short side keeps hearing the other after its own input ran out
+        let inputs = [tone(2, 3), tone(6, 7)];
+        let (left, right) = tokio::io::duplex(64 * 1024);
+        let token = CancellationToken::new();
+        let mut ends = Vec::new();
+        for (i, stream) in [left.compat(), right.compat()].into_iter().enumerate() {
+            write_pcm(&dir.join(format!("{i}.in")), &inputs[i]);
+            let input = Box::new(FileInput::open(dir.join(format!("{i}.in"))).unwrap());
+            let output = Box::new(FileOutput::create(dir.join(format!("{i}.out"))).unwrap());
+            let (encoder, decoder) = (
+                CodecKind::Pcm.build().unwrap(),
+                CodecKind::Pcm.build().unwrap(),
+            );
+            let token = token.clone();
+            ends.push(tokio::spawn(async move {
+                exchange_audio(stream, input, output, encoder, decoder, &token).await
+            }));
+        }
+        let heard = |i: usize| read_pcm(&dir.join(format!("{i}.out")));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while heard(0).len() < inputs[1].len() || heard(1).len() < inputs[0].len() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("both ends to hear each other");
+        token.cancel();
+        for end in ends {
+            end.await.unwrap().unwrap();
+        }
+        assert_eq!(heard(0), inputs[1]);
+        assert_eq!(heard(1), inputs[0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn audio_ends_when_the_peer_closes_the_stream() {
+        let dir = temp_dir();
+        write_pcm(&dir.join("in"), &tone(1, 5));
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        drop(theirs);
+        let input = Box::new(FileInput::open(dir.join("in")).unwrap());
+        let output = Box::new(FileOutput::create(dir.join("out")).unwrap());
+        let (encoder, decoder) = (
+            CodecKind::Pcm.build().unwrap(),
+            CodecKind::Pcm.build().unwrap(),
+        );
+        let token = CancellationToken::new();
+        let ended = exchange_audio(ours.compat(), input, output, encoder, decoder, &token);
+        // closing is a clean end, the hangup signal says why
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), ended)
+                .await
+                .unwrap()
+                .is_ok()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_audio_is_reported_to_the_event_loop() {
+        let (ended, mut reports) = mpsc::unbounded_channel();
+        let call_id = Uuid::new_v4();
+        let stream = Err::<tokio_util::compat::Compat<tokio::io::DuplexStream>, _>(
+            std::io::Error::other("no stream"),
+        );
+        stream_audio(
+            stream,
+            call_id,
+            CodecKind::Pcm,
+            CancellationToken::new(),
+            ended,
+        )
+        .await;
+        let (reported, result) = reports.recv().await.unwrap();
+        assert_eq!(reported, call_id);
+        assert!(result.is_err());
+    }
+}
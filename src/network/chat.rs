@@ -1,66 +1,649 @@
-use crate::network::Command;
+use crate::db::models::MessageRecord;
+use crate::db::models::MessageStatus;
+use crate::network::clock::{Hlc, now_millis};
+use crate::network::pending::{NetError, Pending, Reply};
 use crate::network::signable::{Signed, sign};
+use crate::network::versioned::{Versioned, VersionedCodec};
 use crate::network::{Client, EventLoop};
-use libp2p::PeerId;
+use crate::network::{Command, Event};
+use libp2p::identity::{Keypair, ed25519::PublicKey};
+use libp2p::request_response::{OutboundFailure, OutboundRequestId};
+use libp2p::{PeerId, StreamProtocol, identity};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DirectMessageRequest(pub Signed<Message>);
+// Peers on /direct-message/1 only understand `LegacyMessage`, on /2 `Message::V1`
+const V1: StreamProtocol = StreamProtocol::new("/direct-message/1");
+const V2: StreamProtocol = StreamProtocol::new("/direct-message/2");
+/// Every version we speak, newest first. A new version goes in front
+/// whenever a peer needs to know more to understand what we send.
+pub const PROTOCOLS: [StreamProtocol; 3] = [StreamProtocol::new("/direct-message/3"), V2, V1];
+
+/// A message signed in the shape of every version that can express it, the codec
+/// writes the one the peer negotiated. Inbound requests hold the shape that arrived.
+#[derive(Debug)]
+pub struct DirectMessageRequest {
+    versions: Vec<(StreamProtocol, Signed<Payload>)>,
+}
+impl DirectMessageRequest {
+    pub fn new(message: Message, keys: &Keypair) -> Self {
+        let versions = PROTOCOLS
+            .iter()
+            .filter_map(|protocol| {
+                let payload = message.clone().for_protocol(protocol)?;
+                Some((protocol.clone(), sign(payload, keys)))
+            })
+            .collect();
+        DirectMessageRequest { versions }
+    }
+    /// The id of the message, before its signature has been checked.
+    pub fn id(&self) -> Option<Uuid> {
+        self.versions
+            .first()
+            .map(|(_, payload)| payload.content().id())
+    }
+    /// Checks the signature, messages in an older shape come back as a [`Message`].
+    pub fn verify(self) -> Option<(Message, PublicKey)> {
+        let (_, payload) = self.versions.into_iter().next()?;
+        let (payload, key) = payload.verify()?;
+        Some((payload.into_message(), key))
+    }
+}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DirectMessageResponse(pub MessageResponse);
+pub type MessageCodec = VersionedCodec<DirectMessageRequest, DirectMessageResponse>;
 
+/// A message in the shape one version of the protocol carries. Untagged, so each
+/// is signed and sent exactly like the version it stands for.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Payload {
+    Message(Message),
+    Legacy(LegacyMessage),
+}
+impl Payload {
+    fn id(&self) -> Uuid {
+        match self {
+            Payload::Message(message) => message.id(),
+            Payload::Legacy(message) => message.id,
+        }
+    }
+    fn into_message(self) -> Message {
+        match self {
+            Payload::Message(message) => message,
+            Payload::Legacy(LegacyMessage { content, id }) => Message::V1 {
+                id,
+                body: MessageBody::Text {
+                    content,
+                    reply_to: None,
+                },
+            },
+        }
+    }
+}
+/// What /direct-message/1 carries, plain text from before messages had versions.
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Message {
+pub struct LegacyMessage {
     pub content: String,
     pub id: Uuid,
 }
+impl Versioned for DirectMessageRequest {
+    type Wire = Signed<Payload>;
+    fn into_wire(self, protocol: &StreamProtocol) -> Option<Signed<Payload>> {
+        self.versions
+            .into_iter()
+            .find(|(version, _)| version == protocol)
+            .map(|(_, payload)| payload)
+    }
+    fn from_wire(payload: Signed<Payload>, protocol: &StreamProtocol) -> Self {
+        DirectMessageRequest {
+            versions: vec![(protocol.clone(), payload)],
+        }
+    }
+}
+impl Versioned for DirectMessageResponse {
+    type Wire = MessageResponse;
+    fn into_wire(self, protocol: &StreamProtocol) -> Option<MessageResponse> {
+        Some(match self.0 {
+            // the closest to a refusal that peers from before rejections understand
+            MessageResponse::Rejected { message_id } if *protocol == V1 => {
+                MessageResponse::InvalidSignature { message_id }
+            }
+            response => response,
+        })
+    }
+    fn from_wire(response: MessageResponse, _: &StreamProtocol) -> Self {
+        DirectMessageResponse(response)
+    }
+}
+// How far ahead of ours a sender's clock may be before we reject the message
+const MAX_CLOCK_DRIFT: u64 = 5 * 60 * 1000;
+fn too_far_ahead(clock: Hlc, now: u64) -> bool {
+    clock.wall > now + MAX_CLOCK_DRIFT
+}
+
+/// A message as sent over the wire, new versions get a new variant
+/// so older messages keep deserializing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Message {
+    V1 {
+        id: Uuid,
+        body: MessageBody,
+    },
+    V2 {
+        id: Uuid,
+        body: MessageBody,
+        /// Milliseconds since the unix epoch
+        sent_at: u64,
+        /// The sender's clock for this conversation
+        clock: Hlc,
+    },
+}
+impl Message {
+    pub fn id(&self) -> Uuid {
+        match self {
+            Message::V1 { id, .. } | Message::V2 { id, .. } => *id,
+        }
+    }
+    pub fn body(&self) -> &MessageBody {
+        match self {
+            Message::V1 { body, .. } | Message::V2 { body, .. } => body,
+        }
+    }
+    pub fn sent_at(&self) -> Option<u64> {
+        match self {
+            Message::V1 { .. } => None,
+            Message::V2 { sent_at, .. } => Some(*sent_at),
+        }
+    }
+    pub fn clock(&self) -> Option<Hlc> {
+        match self {
+            Message::V1 { .. } => None,
+            Message::V2 { clock, .. } => Some(*clock),
+        }
+    }
+    /// The message as a peer speaking `protocol` understands it,
+    /// `None` if that version can't express it, like an edit on /direct-message/1.
+    pub fn for_protocol(self, protocol: &StreamProtocol) -> Option<Payload> {
+        match self {
+            message if *protocol == V1 => match message.body() {
+                MessageBody::Text { content, .. } => Some(Payload::Legacy(LegacyMessage {
+                    content: content.clone(),
+                    id: message.id(),
+                })),
+                _ => None,
+            },
+            Message::V2 { id, body, .. } if *protocol == V2 => {
+                Some(Payload::Message(Message::V1 { id, body }))
+            }
+            message => Some(Payload::Message(message)),
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MessageBody {
+    Text {
+        content: String,
+        reply_to: Option<Uuid>,
+    },
+    /// Replaces the content of an earlier message by the same author
+    Edit { target: Uuid, content: String },
+    /// Deletes an earlier message by the same author for everyone
+    Delete { target: Uuid },
+    React {
+        target: Uuid,
+        emoji: String,
+        remove: bool,
+    },
+}
+/// Older peers can't parse variants added later, those are mapped to
+/// one they know when answering an older version.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageResponse {
     ACK { message_id: Uuid },
     InvalidSignature { message_id: Uuid },
+    Rejected { message_id: Uuid },
+}
+#[derive(Debug)]
+pub enum MessageError {
+    /// The referenced message is not part of this conversation
+    UnknownTarget(Uuid),
+    /// Only the author of a message may edit or delete it
+    NotAuthor(Uuid),
+    Database(tokio_rusqlite::Error),
+}
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::UnknownTarget(id) => write!(f, "message {id} is not known"),
+            MessageError::NotAuthor(id) => write!(f, "not the author of message {id}"),
+            MessageError::Database(err) => write!(f, "database error: {err}"),
+        }
+    }
+}
+impl From<tokio_rusqlite::Error> for MessageError {
+    fn from(err: tokio_rusqlite::Error) -> Self {
+        MessageError::Database(err)
+    }
 }
 pub enum ChatCommand {
+    /// The event loop timestamps and signs the message before sending it
     SendMessage {
         receiver: PeerId,
-        message: Signed<Message>,
-    },
-    ReadMessage {
-        receiver: PeerId,
+        id: Uuid,
+        body: MessageBody,
+        reply: Reply<Uuid>,
     },
 }
 impl EventLoop {
     pub async fn handle_chat_command(&mut self, command: ChatCommand) {
         match command {
-            ChatCommand::SendMessage { receiver, message } => {
-                self.swarm
-                    .behaviour_mut()
-                    .direct_message
-                    .send_request(&receiver, DirectMessageRequest(message));
+            ChatCommand::SendMessage {
+                receiver,
+                id,
+                body,
+                reply,
+            } => {
+                let local_id = *self.swarm.local_peer_id();
+                let mut clock = self.conversation_clock(receiver).await;
+                let message = Message::V2 {
+                    id,
+                    body,
+                    sent_at: now_millis(),
+                    clock: clock.tick(),
+                };
+                self.clocks.insert(receiver, clock);
+                let stored = self
+                    .store_message(
+                        receiver,
+                        local_id,
+                        &message,
+                        clock,
+                        MessageStatus::SentOffNotRead,
+                    )
+                    .await;
+                if let Err(err) = stored {
+                    tracing::error!("not sending message {id}: {err}");
+                    let _ = reply.send(Err(NetError::Failed(err.to_string())));
+                    return;
+                }
+                // stays unsent in the conversation if the peer can't take it
+                let protocol = self.negotiate(receiver, &PROTOCOLS).await;
+                match protocol.and_then(|protocol| message.clone().for_protocol(&protocol)) {
+                    Some(_) => {
+                        let request = DirectMessageRequest::new(message, &self.keys);
+                        let request_id = self
+                            .swarm
+                            .behaviour_mut()
+                            .direct_message
+                            .send_request(&receiver, request);
+                        self.outbound_messages.insert(request_id, (id, reply));
+                    }
+                    None => {
+                        let _ = reply.send(Err(NetError::Unsupported));
+                    }
+                }
+                self.event_sender
+                    .send(Event::ConversationUpdated { peer: receiver })
+                    .await
+                    .expect("Event receiver not to be dropped.");
             }
-            ChatCommand::ReadMessage { receiver } => {
-                todo!()
-                // self.swarm
-                //     .behaviour_mut()
-                //     .direct_message
-                //     .send_request(&receiver, DirectMessageRequest(1));
+        }
+    }
+    pub(crate) async fn handle_message_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        response: DirectMessageResponse,
+    ) {
+        let (event, result) = match response.0 {
+            MessageResponse::ACK { message_id } => (
+                Event::OutboundMessageReceived { message_id },
+                Ok(message_id),
+            ),
+            MessageResponse::InvalidSignature { message_id } => (
+                Event::OutboundMessageInvalidSignature { message_id },
+                Err(NetError::InvalidSignature),
+            ),
+            MessageResponse::Rejected { message_id } => (
+                Event::OutboundMessageRejected { message_id },
+                Err(NetError::Rejected),
+            ),
+        };
+        if let Some((_, reply)) = self.outbound_messages.remove(&request_id) {
+            let _ = reply.send(result);
+        }
+        self.send_event(event).await;
+    }
+    pub(crate) async fn handle_message_failure(
+        &mut self,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        error: OutboundFailure,
+    ) {
+        if let OutboundFailure::UnsupportedProtocols = error {
+            self.handle_unsupported(peer, &PROTOCOLS).await;
+        }
+        let Some((message_id, reply)) = self.outbound_messages.remove(&request_id) else {
+            return;
+        };
+        tracing::info!("message {message_id} to {peer} failed: {error}");
+        let _ = reply.send(Err(NetError::from(&error)));
+        self.send_event(Event::MessageSendFailed {
+            message_id,
+            peer,
+            reason: error.to_string(),
+        })
+        .await;
+    }
+    /// Verifies and stores a message sent to us by `peer`.
+    pub(crate) async fn handle_inbound_message(
+        &mut self,
+        peer: PeerId,
+        request: DirectMessageRequest,
+    ) -> MessageResponse {
+        let message_id = request.id().unwrap_or_default();
+        let Some((message, sender)) = request.verify() else {
+            return MessageResponse::InvalidSignature { message_id };
+        };
+        if PeerId::from_public_key(&identity::PublicKey::from(sender.clone())) != peer {
+            return MessageResponse::InvalidSignature { message_id };
+        }
+        let mut clock = self.conversation_clock(peer).await;
+        // Messages sort by the sender's clock so both sides agree on the order,
+        // old messages without one are placed at the time we received them
+        let now = now_millis();
+        let order = match message.clock() {
+            Some(remote) if too_far_ahead(remote, now) => {
+                let drift = remote.wall - now;
+                tracing::info!("rejecting message {message_id} from {peer}: clock {drift}ms ahead");
+                return MessageResponse::Rejected { message_id };
+            }
+            Some(remote) => {
+                clock.receive(remote);
+                remote
+            }
+            None => clock.tick(),
+        };
+        self.clocks.insert(peer, clock);
+        if let Err(err) = self
+            .store_message(peer, peer, &message, order, MessageStatus::ReceivedNotRead)
+            .await
+        {
+            tracing::info!("rejecting message {message_id} from {peer}: {err}");
+            return MessageResponse::Rejected { message_id };
+        }
+        self.event_sender
+            .send(Event::InboundMessage {
+                message,
+                sender: Box::new(sender),
+            })
+            .await
+            .expect("Event receiver not to be dropped.");
+        self.event_sender
+            .send(Event::ConversationUpdated { peer })
+            .await
+            .expect("Event receiver not to be dropped.");
+        MessageResponse::ACK { message_id }
+    }
+    /// Validates `message` written by `author` in the conversation with `conversation`
+    /// and applies it to the message store.
+    async fn store_message(
+        &mut self,
+        conversation: PeerId,
+        author: PeerId,
+        message: &Message,
+        clock: Hlc,
+        status: MessageStatus,
+    ) -> Result<(), MessageError> {
+        match message.body() {
+            MessageBody::Text { content, reply_to } => {
+                self.db
+                    .insert_message(MessageRecord {
+                        id: message.id(),
+                        conversation,
+                        sender: author,
+                        content: content.clone(),
+                        status,
+                        reply_to: *reply_to,
+                        sent_at: message.sent_at(),
+                        received_at: now_millis(),
+                        clock,
+                        edited: false,
+                        deleted: false,
+                        reactions: Vec::new(),
+                    })
+                    .await?;
+            }
+            MessageBody::Edit { target, content } => {
+                self.check_target(conversation, *target, Some(author))
+                    .await?;
+                self.db.edit_message(*target, content.clone()).await?;
+            }
+            MessageBody::Delete { target } => {
+                self.check_target(conversation, *target, Some(author))
+                    .await?;
+                self.db.delete_message(*target).await?;
+            }
+            MessageBody::React {
+                target,
+                emoji,
+                remove,
+            } => {
+                self.check_target(conversation, *target, None).await?;
+                self.db
+                    .set_reaction(*target, author, emoji.clone(), *remove)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    /// The latest clock of the conversation with `peer`, loaded from the store on first use.
+    async fn conversation_clock(&mut self, peer: PeerId) -> Hlc {
+        if let Some(clock) = self.clocks.get(&peer) {
+            return *clock;
+        }
+        let clock = match self.db.latest_clock(peer).await {
+            Ok(clock) => clock,
+            Err(err) => {
+                tracing::error!("failed to load clock for {peer}: {err}");
+                Hlc::default()
+            }
+        };
+        self.clocks.insert(peer, clock);
+        clock
+    }
+    async fn check_target(
+        &mut self,
+        conversation: PeerId,
+        target: Uuid,
+        author: Option<PeerId>,
+    ) -> Result<(), MessageError> {
+        match self.db.message_origin(target).await? {
+            Some((_, origin)) if origin != conversation => Err(MessageError::UnknownTarget(target)),
+            Some((sender, _)) if author.is_some_and(|a| a != sender) => {
+                Err(MessageError::NotAuthor(target))
             }
+            Some(_) => Ok(()),
+            None => Err(MessageError::UnknownTarget(target)),
         }
     }
 }
 impl Client {
-    pub async fn send_message(&mut self, receiver: PeerId, message: String) {
-        let message = Message {
-            content: message,
-            id: uuid::Uuid::new_v4(),
-        };
-        let signed_message = sign(message, &self.keys);
-        self.command_sender
-            .send(Command::ChatCommand(ChatCommand::SendMessage {
+    /// Queues a message, it resolves to the message id once the receiver acknowledged it.
+    pub async fn send(&mut self, receiver: PeerId, body: MessageBody) -> Pending<Uuid> {
+        let id = Uuid::new_v4();
+        self.queue(|reply| {
+            Command::ChatCommand(ChatCommand::SendMessage {
                 receiver,
-                message: signed_message,
-            }))
+                id,
+                body,
+                reply,
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use libp2p::request_response::{Codec, cbor};
+
+    use super::*;
+
+    fn text(content: &str) -> MessageBody {
+        MessageBody::Text {
+            content: content.to_string(),
+            reply_to: None,
+        }
+    }
+    fn v2(body: MessageBody) -> Message {
+        Message::V2 {
+            id: Uuid::new_v4(),
+            body,
+            sent_at: 1_000,
+            clock: Hlc {
+                wall: 1_000,
+                counter: 2,
+            },
+        }
+    }
+    /// Writes `request` the way it goes out on `protocol` and reads it back on the other end.
+    async fn round_trip(request: DirectMessageRequest, protocol: &StreamProtocol) -> Message {
+        let mut codec = MessageCodec::default();
+        let mut buffer = Cursor::new(Vec::new());
+        codec
+            .write_request(protocol, &mut buffer, request)
+            .await
+            .unwrap();
+        buffer.set_position(0);
+        let request = codec.read_request(protocol, &mut buffer).await.unwrap();
+        request.verify().expect("a valid signature").0
+    }
+
+    #[tokio::test]
+    async fn newest_version_keeps_the_clock() {
+        let keys = Keypair::generate_ed25519();
+        let message = v2(text("hi"));
+        let received = round_trip(
+            DirectMessageRequest::new(message.clone(), &keys),
+            &PROTOCOLS[0],
+        )
+        .await;
+        assert_eq!(received.id(), message.id());
+        assert_eq!(received.sent_at(), Some(1_000));
+        assert_eq!(received.clock(), message.clock());
+    }
+    #[tokio::test]
+    async fn v2_peers_get_a_v1_message() {
+        let keys = Keypair::generate_ed25519();
+        let message = v2(text("hi"));
+        let received = round_trip(DirectMessageRequest::new(message.clone(), &keys), &V2).await;
+        assert!(matches!(received, Message::V1 { .. }));
+        assert_eq!(received.id(), message.id());
+        assert!(matches!(received.body(), MessageBody::Text { content, .. } if content == "hi"));
+    }
+    #[tokio::test]
+    async fn v1_peers_get_the_original_struct() {
+        let keys = Keypair::generate_ed25519();
+        let message = v2(text("hi"));
+        let mut codec = MessageCodec::default();
+        let mut buffer = Cursor::new(Vec::new());
+        codec
+            .write_request(
+                &V1,
+                &mut buffer,
+                DirectMessageRequest::new(message.clone(), &keys),
+            )
             .await
-            .expect("To send message");
+            .unwrap();
+        buffer.set_position(0);
+        // what a node from before versioned messages reads
+        let mut legacy = cbor::codec::Codec::<Signed<LegacyMessage>, MessageResponse>::default();
+        let (received, _) = legacy
+            .read_request(&V1, &mut buffer)
+            .await
+            .unwrap()
+            .verify()
+            .expect("a valid signature");
+        assert_eq!(received.id, message.id());
+        assert_eq!(received.content, "hi");
+    }
+    #[tokio::test]
+    async fn the_original_struct_decodes_as_a_v1_message() {
+        let keys = Keypair::generate_ed25519();
+        let legacy = LegacyMessage {
+            content: "hi".to_string(),
+            id: Uuid::new_v4(),
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        cbor::codec::Codec::<Signed<LegacyMessage>, MessageResponse>::default()
+            .write_request(&V1, &mut buffer, sign(legacy.clone(), &keys))
+            .await
+            .unwrap();
+        buffer.set_position(0);
+        let request = MessageCodec::default()
+            .read_request(&V1, &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(request.id(), Some(legacy.id));
+        let (received, sender) = request.verify().expect("a valid signature");
+        assert_eq!(sender, keys.public().try_into_ed25519().unwrap());
+        assert!(matches!(received, Message::V1 { .. }));
+        assert_eq!(received.id(), legacy.id);
+        assert!(matches!(received.body(), MessageBody::Text { content, .. } if content == "hi"));
+    }
+    #[test]
+    fn for_protocol_downgrades_to_what_the_version_expresses() {
+        let message = v2(text("hi"));
+        assert!(matches!(
+            message.clone().for_protocol(&PROTOCOLS[0]),
+            Some(Payload::Message(Message::V2 { .. }))
+        ));
+        assert!(matches!(
+            message.clone().for_protocol(&V2),
+            Some(Payload::Message(Message::V1 { .. }))
+        ));
+        assert!(matches!(
+            message.for_protocol(&V1),
+            Some(Payload::Legacy(LegacyMessage { content, .. })) if content == "hi"
+        ));
+        let edit = v2(MessageBody::Edit {
+            target: Uuid::new_v4(),
+            content: "hello".to_string(),
+        });
+        assert!(edit.clone().for_protocol(&V1).is_none());
+        assert!(edit.for_protocol(&V2).is_some());
+    }
+    #[test]
+    fn deletions_are_not_sent_to_v1_peers() {
+        let keys = Keypair::generate_ed25519();
+        let edit = v2(MessageBody::Delete {
+            target: Uuid::new_v4(),
+        });
+        let request = DirectMessageRequest::new(edit, &keys);
+        assert!(request.into_wire(&V1).is_none());
+    }
+    #[test]
+    fn clocks_may_only_drift_so_far_ahead() {
+        let now = 1_000_000;
+        let at = |wall| Hlc { wall, counter: 0 };
+        assert!(!too_far_ahead(at(0), now));
+        assert!(!too_far_ahead(at(now), now));
+        assert!(!too_far_ahead(at(now + MAX_CLOCK_DRIFT), now));
+        assert!(too_far_ahead(at(now + MAX_CLOCK_DRIFT + 1), now));
+    }
+    #[test]
+    fn rejections_reach_v1_peers_as_invalid_signatures() {
+        let message_id = Uuid::new_v4();
+        let rejected = || DirectMessageResponse(MessageResponse::Rejected { message_id });
+        assert!(matches!(
+            rejected().into_wire(&V1),
+            Some(MessageResponse::InvalidSignature { message_id: id }) if id == message_id
+        ));
+        assert!(matches!(
+            rejected().into_wire(&V2),
+            Some(MessageResponse::Rejected { .. })
+        ));
     }
 }
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+/// Hybrid logical clock timestamp, orders messages consistently on every device
+/// even when the senders' wall clocks disagree or messages arrive late.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hlc {
+    /// Milliseconds since the unix epoch
+    pub wall: u64,
+    /// Orders events that share the same `wall`
+    pub counter: u32,
+}
+impl Hlc {
+    /// Advances the clock for a message we are about to send.
+    pub fn tick(&mut self) -> Hlc {
+        let now = now_millis();
+        *self = match now > self.wall {
+            true => Hlc {
+                wall: now,
+                counter: 0,
+            },
+            false => Hlc {
+                wall: self.wall,
+                counter: self.counter + 1,
+            },
+        };
+        *self
+    }
+    /// Merges the clock of a message we received, so anything we send afterwards sorts after it.
+    pub fn receive(&mut self, remote: Hlc) -> Hlc {
+        let now = now_millis();
+        let wall = now.max(self.wall).max(remote.wall);
+        let counter = match (wall == self.wall, wall == remote.wall) {
+            (true, true) => self.counter.max(remote.counter) + 1,
+            (true, false) => self.counter + 1,
+            (false, true) => remote.counter + 1,
+            (false, false) => 0,
+        };
+        *self = Hlc { wall, counter };
+        *self
+    }
+}
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("system time to be after the unix epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an hour ahead, so the wall clock can't catch up while a test runs
+    fn ahead() -> u64 {
+        now_millis() + 60 * 60 * 1000
+    }
+
+    #[test]
+    fn tick_follows_the_wall_clock() {
+        let before = now_millis();
+        let mut clock = Hlc::default();
+        let tick = clock.tick();
+        assert!(tick.wall >= before);
+        assert_eq!(tick.counter, 0);
+        assert_eq!(clock, tick);
+    }
+    #[test]
+    fn tick_counts_while_the_wall_clock_is_behind() {
+        let wall = ahead();
+        let mut clock = Hlc { wall, counter: 4 };
+        assert_eq!(clock.tick(), Hlc { wall, counter: 5 });
+        assert_eq!(clock.tick(), Hlc { wall, counter: 6 });
+    }
+    #[test]
+    fn receive_catches_up_with_a_clock_ahead_of_ours() {
+        let remote = Hlc {
+            wall: ahead(),
+            counter: 7,
+        };
+        let mut clock = Hlc::default();
+        let merged = clock.receive(remote);
+        assert_eq!(
+            merged,
+            Hlc {
+                wall: remote.wall,
+                counter: 8
+            }
+        );
+        // what we send next sorts after what we received
+        assert!(clock.tick() > remote);
+    }
+    #[test]
+    fn receive_keeps_our_clock_when_it_is_ahead() {
+        let wall = ahead();
+        let mut clock = Hlc { wall, counter: 2 };
+        let merged = clock.receive(Hlc {
+            wall: wall - 1,
+            counter: 9,
+        });
+        assert_eq!(merged, Hlc { wall, counter: 3 });
+    }
+    #[test]
+    fn receive_counts_past_both_on_the_same_wall() {
+        let wall = ahead();
+        let mut clock = Hlc { wall, counter: 2 };
+        let merged = clock.receive(Hlc { wall, counter: 5 });
+        assert_eq!(merged, Hlc { wall, counter: 6 });
+    }
+    #[test]
+    fn receive_of_old_clocks_follows_the_wall_clock() {
+        let before = now_millis();
+        let mut clock = Hlc {
+            wall: 1,
+            counter: 3,
+        };
+        let merged = clock.receive(Hlc {
+            wall: 2,
+            counter: 8,
+        });
+        assert!(merged.wall >= before);
+        assert_eq!(merged.counter, 0);
+    }
+}
@@ -0,0 +1,294 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::network::dial::{DialCommand, DialTarget};
+use crate::network::{Client, Command, Contact, Event, EventLoop, clock};
+
+// How long an exported card can be imported for
+const VALIDITY: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+// Kept short so the QR code still fits in a terminal
+const MAX_ADDRESSES: usize = 4;
+const PREFIX: &str = "p2pchat:";
+
+/// Everything needed to reach someone without meeting on the LAN first.
+/// Shared as `p2pchat:<card>.<public key>.<signature>`, the peer id is derived
+/// from the key that signed it so a card can't be forged for someone else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactCard {
+    pub peer: PeerId,
+    pub name: Option<String>,
+    pub addresses: Vec<Multiaddr>,
+    /// Unix time in milliseconds after which the card is rejected
+    pub expires: u64,
+}
+/// What gets signed, with short keys to keep tokens compact
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    #[serde(rename = "n")]
+    name: Option<String>,
+    #[serde(rename = "a")]
+    addresses: Vec<String>,
+    #[serde(rename = "e")]
+    expires: u64,
+}
+impl ContactCard {
+    pub fn encode(&self, keys: &Keypair) -> String {
+        let payload = serde_json::to_vec(&Payload {
+            name: self.name.clone(),
+            addresses: self.addresses.iter().map(|a| a.to_string()).collect(),
+            expires: self.expires,
+        })
+        .expect("Failed to serialize contact card");
+        let sig = keys.sign(&payload).expect("Failed to sign");
+        format!(
+            "{PREFIX}{}.{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(keys.public().encode_protobuf()),
+            URL_SAFE_NO_PAD.encode(sig)
+        )
+    }
+    pub fn dial_target(&self) -> DialTarget {
+        DialTarget::Contact {
+            peer: self.peer,
+            addresses: self.addresses.clone(),
+        }
+    }
+}
+impl std::str::FromStr for ContactCard {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let token = s.trim().strip_prefix(PREFIX).ok_or("not a contact card")?;
+        let mut parts = token.split('.').map(|part| URL_SAFE_NO_PAD.decode(part));
+        let (Some(Ok(payload)), Some(Ok(key)), Some(Ok(sig)), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed contact card".to_string());
+        };
+        let key = PublicKey::try_decode_protobuf(&key)
+            .map_err(|err| format!("invalid key in contact card: {err}"))?;
+        if !key.verify(&payload, &sig) {
+            return Err("contact card has an invalid signature".to_string());
+        }
+        let payload: Payload = serde_json::from_slice(&payload)
+            .map_err(|err| format!("malformed contact card: {err}"))?;
+        if payload.expires < clock::now_millis() {
+            return Err("contact card has expired, ask for a new one".to_string());
+        }
+        let addresses = payload
+            .addresses
+            .iter()
+            .map(|address| {
+                address
+                    .parse()
+                    .map_err(|err| format!("invalid address {address}: {err}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ContactCard {
+            peer: key.to_peer_id(),
+            name: payload.name,
+            addresses,
+            expires: payload.expires,
+        })
+    }
+}
+pub enum ContactCardCommand {
+    Export,
+    /// Dial the card's peer and send them a friend request
+    Import {
+        card: ContactCard,
+    },
+}
+impl EventLoop {
+    pub async fn handle_contact_card_command(&mut self, command: ContactCardCommand) {
+        match command {
+            ContactCardCommand::Export => {
+                let token = self.contact_card().await.encode(&self.keys);
+                self.event_sender
+                    .send(Event::ContactCard { token })
+                    .await
+                    .expect("Event receiver not to be dropped.");
+            }
+            ContactCardCommand::Import { card } => {
+                let peer = card.peer;
+                if let Some(name) = &card.name
+                    && let Err(err) = self.db.name_contact(peer, name.clone()).await
+                {
+                    tracing::error!("failed to store contact {peer}: {err}");
+                }
+                for address in &card.addresses {
+                    self.record_address(peer, address.clone()).await;
+                }
+                let _ = self.contacts.send(Contact {
+                    peer,
+                    name: card.name.clone().unwrap_or("Anonymous".to_string()),
+                });
+                if self.swarm.is_connected(&peer) {
+                    let request = self.add_friend().await;
+                    self.send_friend_request(peer, request, None).await;
+                    return;
+                }
+                // the friend request goes out once the dial connected
+                self.pending_friend_requests.insert(peer);
+                self.handle_dial_command(DialCommand::Dial {
+                    target: card.dial_target(),
+                })
+                .await;
+            }
+        }
+    }
+    /// Our own card, with the addresses others are most likely to reach us on first.
+    async fn contact_card(&mut self) -> ContactCard {
+        let name = self.own_name().await;
+        let mut addresses: Vec<Multiaddr> = self
+            .swarm
+            .external_addresses()
+            .chain(self.swarm.listeners())
+            .filter(|address| {
+                !address.iter().any(|protocol| match protocol {
+                    Protocol::Ip4(ip) => ip.is_unspecified(),
+                    Protocol::Ip6(ip) => ip.is_unspecified(),
+                    _ => false,
+                })
+            })
+            .cloned()
+            .collect();
+        addresses.dedup();
+        // loopback only helps when both ends are on this machine
+        addresses.sort_by_key(|address| {
+            address.iter().any(|protocol| match protocol {
+                Protocol::Ip4(ip) => ip.is_loopback(),
+                Protocol::Ip6(ip) => ip.is_loopback(),
+                _ => false,
+            })
+        });
+        addresses.truncate(MAX_ADDRESSES);
+        ContactCard {
+            peer: *self.swarm.local_peer_id(),
+            name,
+            addresses,
+            expires: clock::now_millis() + VALIDITY.as_millis() as u64,
+        }
+    }
+}
+impl Client {
+    /// Asks for our contact card, it arrives as [`Event::ContactCard`].
+    pub async fn export_contact_card(&mut self) {
+        self.command_sender
+            .send(Command::ContactCardCommand(ContactCardCommand::Export))
+            .await
+            .expect("to send contact card");
+    }
+    pub async fn import_contact_card(&mut self, card: ContactCard) {
+        self.command_sender
+            .send(Command::ContactCardCommand(ContactCardCommand::Import {
+                card,
+            }))
+            .await
+            .expect("to send contact card");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(keys: &Keypair) -> ContactCard {
+        ContactCard {
+            peer: keys.public().to_peer_id(),
+            name: Some("alice".to_string()),
+            addresses: vec![
+                "/ip4/203.0.113.7/tcp/4001".parse().unwrap(),
+                "/ip6/::1/udp/4001/quic-v1".parse().unwrap(),
+            ],
+            expires: clock::now_millis() + VALIDITY.as_millis() as u64,
+        }
+    }
+    fn parts(token: &str) -> Vec<&str> {
+        token.strip_prefix(PREFIX).unwrap().split('.').collect()
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let keys = Keypair::generate_ed25519();
+        let card = card(&keys);
+        let token = card.encode(&keys);
+        assert!(token.starts_with(PREFIX));
+        assert_eq!(token.parse::<ContactCard>().unwrap(), card);
+        // pasting tends to pick up whitespace
+        assert_eq!(format!(" {token}\n").parse::<ContactCard>().unwrap(), card);
+    }
+    #[test]
+    fn the_peer_comes_from_the_signing_key() {
+        let keys = Keypair::generate_ed25519();
+        let mut card = card(&keys);
+        card.peer = Keypair::generate_ed25519().public().to_peer_id();
+        let parsed: ContactCard = card.encode(&keys).parse().unwrap();
+        assert_eq!(parsed.peer, keys.public().to_peer_id());
+    }
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let keys = Keypair::generate_ed25519();
+        let token = card(&keys).encode(&keys);
+        let parts = parts(&token);
+
+        let mut other = card(&keys);
+        other.addresses = vec!["/ip4/198.51.100.1/tcp/4001".parse().unwrap()];
+        let other = other.encode(&keys);
+        let changed = format!(
+            "{PREFIX}{}.{}.{}",
+            self::parts(&other)[0],
+            parts[1],
+            parts[2]
+        );
+        assert_eq!(
+            changed.parse::<ContactCard>(),
+            Err("contact card has an invalid signature".to_string())
+        );
+
+        // someone else's key over our card
+        let mallory = Keypair::generate_ed25519();
+        let mallory = URL_SAFE_NO_PAD.encode(mallory.public().encode_protobuf());
+        let claimed = format!("{PREFIX}{}.{mallory}.{}", parts[0], parts[2]);
+        assert_eq!(
+            claimed.parse::<ContactCard>(),
+            Err("contact card has an invalid signature".to_string())
+        );
+    }
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let keys = Keypair::generate_ed25519();
+        let token = card(&keys).encode(&keys);
+        let parts = parts(&token);
+        assert!("".parse::<ContactCard>().is_err());
+        assert!(token[PREFIX.len()..].parse::<ContactCard>().is_err());
+        assert!(
+            format!("{PREFIX}{}.{}", parts[0], parts[1])
+                .parse::<ContactCard>()
+                .is_err()
+        );
+        assert!(
+            format!("{token}.{}", parts[2])
+                .parse::<ContactCard>()
+                .is_err()
+        );
+        assert!(
+            format!("{PREFIX}{}.!!.{}", parts[0], parts[2])
+                .parse::<ContactCard>()
+                .is_err()
+        );
+    }
+    #[test]
+    fn expired_cards_are_rejected() {
+        let keys = Keypair::generate_ed25519();
+        let mut card = card(&keys);
+        card.expires = clock::now_millis() - 1;
+        assert_eq!(
+            card.encode(&keys).parse::<ContactCard>(),
+            Err("contact card has expired, ask for a new one".to_string())
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::ConnectionId;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::{Multiaddr, PeerId};
+
+use crate::network::{Client, Command, Contact, Event, EventLoop};
+
+/// Who to dial, either a bare address or a peer with the addresses it can be reached on.
+/// Contacts are shared as `<peer id>@<address>[,<address>...]`, an address ending
+/// in `/p2p/<peer id>` works too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialTarget {
+    Address(Multiaddr),
+    Contact {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+}
+impl std::str::FromStr for DialTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some((peer, addresses)) = s.split_once('@') {
+            let peer = peer
+                .parse()
+                .map_err(|err| format!("invalid peer id {peer}: {err}"))?;
+            let addresses = addresses
+                .split(',')
+                .map(parse_address)
+                .collect::<Result<Vec<Multiaddr>, _>>()?;
+            return Ok(DialTarget::Contact { peer, addresses });
+        }
+        let mut address = parse_address(s)?;
+        match address.iter().last() {
+            Some(Protocol::P2p(peer)) => {
+                address.pop();
+                Ok(DialTarget::Contact {
+                    peer,
+                    addresses: vec![address],
+                })
+            }
+            _ => Ok(DialTarget::Address(address)),
+        }
+    }
+}
+fn parse_address(address: &str) -> Result<Multiaddr, String> {
+    let address = address.trim();
+    if address.is_empty() {
+        return Err("missing address".to_string());
+    }
+    address
+        .parse()
+        .map_err(|err| format!("invalid address {address}: {err}"))
+}
+impl std::fmt::Display for DialTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DialTarget::Address(address) => write!(f, "{address}"),
+            DialTarget::Contact { peer, .. } => write!(f, "{peer}"),
+        }
+    }
+}
+pub enum DialCommand {
+    Dial { target: DialTarget },
+}
+impl EventLoop {
+    pub async fn handle_dial_command(&mut self, command: DialCommand) {
+        match command {
+            DialCommand::Dial { target } => {
+                let opts = match &target {
+                    DialTarget::Address(address) => {
+                        DialOpts::unknown_peer_id().address(address.clone()).build()
+                    }
+                    DialTarget::Contact { peer, addresses } => DialOpts::peer_id(*peer)
+                        .addresses(addresses.clone())
+                        .build(),
+                };
+                let connection_id = opts.connection_id();
+                tracing::info!("dialing {target}");
+                match self.swarm.dial(opts) {
+                    Ok(()) => {
+                        self.pending_dials.insert(connection_id, target);
+                    }
+                    Err(err) => {
+                        self.event_sender
+                            .send(Event::DialFailed {
+                                target,
+                                reason: err.to_string(),
+                            })
+                            .await
+                            .expect("Event receiver not to be dropped.");
+                    }
+                }
+            }
+        }
+    }
+    /// Reports how a dial the user asked for went, a reached peer becomes a contact candidate.
+    pub(crate) async fn handle_dial_result(
+        &mut self,
+        connection_id: ConnectionId,
+        result: Result<PeerId, String>,
+    ) {
+        let Some(target) = self.pending_dials.remove(&connection_id) else {
+            return;
+        };
+        let event = match result {
+            Ok(peer) => {
+                let _ = self.contacts.send(Contact {
+                    peer,
+                    name: "Anonymous".to_string(),
+                });
+                if self.pending_friend_requests.remove(&peer) {
+                    let request = self.add_friend().await;
+                    self.send_friend_request(peer, request, None).await;
+                }
+                Event::Dialed { peer }
+            }
+            Err(reason) => {
+                if let DialTarget::Contact { peer, .. } = &target {
+                    self.pending_friend_requests.remove(peer);
+                }
+                Event::DialFailed { target, reason }
+            }
+        };
+        self.event_sender
+            .send(event)
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+}
+impl Client {
+    /// Connects to a peer we couldn't discover, the outcome arrives as
+    /// [`Event::Dialed`] or [`Event::DialFailed`].
+    pub async fn dial(&mut self, target: DialTarget) {
+        self.command_sender
+            .send(Command::DialCommand(DialCommand::Dial { target }))
+            .await
+            .expect("to send dial");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerId {
+        libp2p::identity::Keypair::generate_ed25519()
+            .public()
+            .to_peer_id()
+    }
+
+    #[test]
+    fn bare_addresses_dial_any_peer() {
+        let target: DialTarget = " /ip4/127.0.0.1/tcp/4001 ".parse().unwrap();
+        assert_eq!(
+            target,
+            DialTarget::Address("/ip4/127.0.0.1/tcp/4001".parse().unwrap())
+        );
+    }
+    #[test]
+    fn addresses_ending_in_a_peer_id_dial_that_peer() {
+        let peer = peer();
+        let target: DialTarget = format!("/ip4/127.0.0.1/tcp/4001/p2p/{peer}")
+            .parse()
+            .unwrap();
+        assert_eq!(
+            target,
+            DialTarget::Contact {
+                peer,
+                addresses: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+            }
+        );
+    }
+    #[test]
+    fn contacts_list_every_address() {
+        let peer = peer();
+        let target: DialTarget =
+            format!("{peer}@/ip4/10.0.0.2/tcp/4001, /ip6/::1/udp/4001/quic-v1")
+                .parse()
+                .unwrap();
+        assert_eq!(
+            target,
+            DialTarget::Contact {
+                peer,
+                addresses: vec![
+                    "/ip4/10.0.0.2/tcp/4001".parse().unwrap(),
+                    "/ip6/::1/udp/4001/quic-v1".parse().unwrap(),
+                ],
+            }
+        );
+        assert_eq!(target.to_string(), peer.to_string());
+    }
+    #[test]
+    fn malformed_targets_are_rejected() {
+        let peer = peer();
+        for target in [
+            "",
+            "not an address",
+            "/ip4/300.0.0.1/tcp/4001",
+            "nobody@/ip4/127.0.0.1/tcp/4001",
+            &format!("{peer}@"),
+            &format!("{peer}@/ip4/127.0.0.1/tcp/4001,nowhere"),
+        ] {
+            assert!(
+                target.parse::<DialTarget>().is_err(),
+                "{target:?} should not parse"
+            );
+        }
+    }
+}
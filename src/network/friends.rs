@@ -1,15 +1,89 @@
-use libp2p::PeerId;
+use libp2p::request_response::{OutboundFailure, OutboundRequestId, ResponseChannel};
+use libp2p::{PeerId, StreamProtocol, identity};
 use serde::{Deserialize, Serialize};
 
-use crate::network::{Client, EventLoop, signable::Signed};
-#[derive(Debug, Serialize, Deserialize)]
+use crate::network::pending::{NetError, Reply};
+use crate::network::signable::{Signed, sign};
+use crate::network::versioned::{Versioned, VersionedCodec};
+use crate::network::{Client, Command, Event, EventLoop};
+
+/// Peers that only speak this get a [`LegacyFriendRequest`]
+const V1: StreamProtocol = StreamProtocol::new("/friends/1");
+/// Every version we speak, newest first
+pub const PROTOCOLS: [StreamProtocol; 2] = [StreamProtocol::new("/friends/2"), V1];
+/// Fields added later need `#[serde(default)]`, anything older peers
+/// can't parse goes out on a new version in [`PROTOCOLS`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FriendRequest {
+    RequestName,
+    VerifyName {
+        name: String,
+    },
+    /// `name` is the requester's display name, `/friends/1` peers don't send it
+    AddFriend {
+        name: Option<String>,
+    },
+    AcceptFriend {
+        decision: bool,
+    },
+}
+/// [`FriendRequest`] as `/friends/1` carries it
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LegacyFriendRequest {
     RequestName,
     VerifyName { name: String },
     AddFriend,
     AcceptFriend { decision: bool },
 }
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FriendWire {
+    Current(FriendRequest),
+    Legacy(LegacyFriendRequest),
+}
+impl Versioned for FriendRequest {
+    type Wire = FriendWire;
+    fn into_wire(self, protocol: &StreamProtocol) -> Option<FriendWire> {
+        if *protocol != V1 {
+            return Some(FriendWire::Current(self));
+        }
+        Some(FriendWire::Legacy(match self {
+            FriendRequest::RequestName => LegacyFriendRequest::RequestName,
+            FriendRequest::VerifyName { name } => LegacyFriendRequest::VerifyName { name },
+            FriendRequest::AddFriend { .. } => LegacyFriendRequest::AddFriend,
+            FriendRequest::AcceptFriend { decision } => {
+                LegacyFriendRequest::AcceptFriend { decision }
+            }
+        }))
+    }
+    fn from_wire(wire: FriendWire, _protocol: &StreamProtocol) -> Self {
+        match wire {
+            FriendWire::Current(request) => request,
+            FriendWire::Legacy(LegacyFriendRequest::RequestName) => FriendRequest::RequestName,
+            FriendWire::Legacy(LegacyFriendRequest::VerifyName { name }) => {
+                FriendRequest::VerifyName { name }
+            }
+            FriendWire::Legacy(LegacyFriendRequest::AddFriend) => {
+                FriendRequest::AddFriend { name: None }
+            }
+            FriendWire::Legacy(LegacyFriendRequest::AcceptFriend { decision }) => {
+                FriendRequest::AcceptFriend { decision }
+            }
+        }
+    }
+}
+/// Responses haven't changed since `/friends/1`
+impl Versioned for Signed<FriendResponse> {
+    type Wire = Self;
+    fn into_wire(self, _protocol: &StreamProtocol) -> Option<Self> {
+        Some(self)
+    }
+    fn from_wire(wire: Self, _protocol: &StreamProtocol) -> Self {
+        wire
+    }
+}
+pub type FriendCodec = VersionedCodec<FriendRequest, Signed<FriendResponse>>;
+#[derive(Debug, Serialize, Deserialize)]
 pub enum FriendResponse {
     RequestName { name: String },
     VerifyName(Option<String>),
@@ -17,82 +91,194 @@ pub enum FriendResponse {
     AcceptFriendAck,
 }
 pub enum FriendCommand {
-    RequestName { peer: PeerId },
-    VerifyName { name: String, peer: PeerId },
-    AddFriend { peer: PeerId },
-    AcceptFriend { peer: PeerId, decision: bool },
+    RequestName {
+        peer: PeerId,
+        reply: Reply<FriendResponse>,
+    },
+    VerifyName {
+        name: String,
+        peer: PeerId,
+        reply: Reply<FriendResponse>,
+    },
+    AddFriend {
+        peer: PeerId,
+        reply: Reply<FriendResponse>,
+    },
+    AcceptFriend {
+        peer: PeerId,
+        decision: bool,
+        reply: Reply<FriendResponse>,
+    },
 }
 impl EventLoop {
     pub async fn handle_friend_command(&mut self, command: FriendCommand) {
         // TODO: Add everything to sqlite
         // Send re-render of contact list to tui
-        match command {
-            FriendCommand::RequestName { peer } => self
-                .swarm
-                .behaviour_mut()
-                .friends
-                .send_request(&peer, FriendRequest::RequestName),
-            FriendCommand::VerifyName { peer, name } => self
-                .swarm
-                .behaviour_mut()
-                .friends
-                .send_request(&peer, FriendRequest::VerifyName { name }),
-            FriendCommand::AddFriend { peer } => self
-                .swarm
-                .behaviour_mut()
-                .friends
-                .send_request(&peer, FriendRequest::AddFriend),
-            FriendCommand::AcceptFriend { peer, decision } => self
-                .swarm
-                .behaviour_mut()
-                .friends
-                .send_request(&peer, FriendRequest::AcceptFriend { decision }),
+        let (peer, request, reply) = match command {
+            FriendCommand::RequestName { peer, reply } => (peer, FriendRequest::RequestName, reply),
+            FriendCommand::VerifyName { peer, name, reply } => {
+                (peer, FriendRequest::VerifyName { name }, reply)
+            }
+            FriendCommand::AddFriend { peer, reply } => (peer, self.add_friend().await, reply),
+            FriendCommand::AcceptFriend {
+                peer,
+                decision,
+                reply,
+            } => (peer, FriendRequest::AcceptFriend { decision }, reply),
+        };
+        self.send_friend_request(peer, request, Some(reply)).await;
+    }
+    /// A friend request carrying our display name, if we have one.
+    pub(crate) async fn add_friend(&mut self) -> FriendRequest {
+        FriendRequest::AddFriend {
+            name: self.own_name().await,
+        }
+    }
+    /// Sends `request` if `peer` speaks the friends protocol, remembering it until answered.
+    pub(crate) async fn send_friend_request(
+        &mut self,
+        peer: PeerId,
+        request: FriendRequest,
+        reply: Option<Reply<FriendResponse>>,
+    ) {
+        if self.negotiate(peer, &PROTOCOLS).await.is_none() {
+            if let Some(reply) = reply {
+                let _ = reply.send(Err(NetError::Unsupported));
+            }
+            return;
+        }
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .friends
+            .send_request(&peer, request.clone());
+        self.outbound_friend_requests
+            .insert(request_id, (request, reply));
+    }
+    /// Answers a request from `peer`, who may have given up waiting by now.
+    pub(crate) fn answer_friend_request(
+        &mut self,
+        peer: PeerId,
+        channel: ResponseChannel<Signed<FriendResponse>>,
+        response: FriendResponse,
+    ) {
+        let response = sign(response, &self.keys);
+        if self
+            .swarm
+            .behaviour_mut()
+            .friends
+            .send_response(channel, response)
+            .is_err()
+        {
+            tracing::info!("{peer} stopped waiting for friend response");
+        }
+    }
+    /// Hands the verified response to whoever is waiting for it.
+    pub(crate) fn handle_friend_response(
+        &mut self,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        response: Signed<FriendResponse>,
+    ) {
+        let Some((_, Some(reply))) = self.outbound_friend_requests.remove(&request_id) else {
+            return;
+        };
+        let result = match response.verify() {
+            Some((response, sender))
+                if PeerId::from_public_key(&identity::PublicKey::from(sender.clone())) == peer =>
+            {
+                Ok(response)
+            }
+            _ => Err(NetError::InvalidSignature),
+        };
+        let _ = reply.send(result);
+    }
+    pub(crate) async fn handle_friend_failure(
+        &mut self,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        error: OutboundFailure,
+    ) {
+        if let OutboundFailure::UnsupportedProtocols = error {
+            self.handle_unsupported(peer, &PROTOCOLS).await;
+        }
+        let Some((request, reply)) = self.outbound_friend_requests.remove(&request_id) else {
+            return;
         };
+        tracing::info!("{request:?} to {peer} failed: {error}");
+        if let Some(reply) = reply {
+            let _ = reply.send(Err(NetError::from(&error)));
+        }
+        self.send_event(Event::FriendRequestFailed {
+            peer,
+            request,
+            reason: error.to_string(),
+        })
+        .await;
     }
 }
 impl Client {
-    async fn request_name(&mut self, peer: PeerId) {
-        self.command_sender
-            .send(super::Command::FriendCommand(FriendCommand::RequestName {
-                peer,
-            }))
+    /// Asks `peer` for their display name.
+    pub async fn request_name(&mut self, peer: PeerId) -> Result<String, NetError> {
+        let response = self
+            .queue(|reply| Command::FriendCommand(FriendCommand::RequestName { peer, reply }))
             .await
-            .expect("to send request");
+            .result()
+            .await?;
+        match response {
+            FriendResponse::RequestName { name } => Ok(name),
+            _ => Err(NetError::UnexpectedResponse),
+        }
     }
-    async fn verify_name(&mut self, peer: PeerId, name: String) {
-        self.command_sender
-            .send(super::Command::FriendCommand(FriendCommand::VerifyName {
-                name,
-                peer,
-            }))
+    /// Checks that `peer` still goes by `name`, resolving to their new name if not.
+    pub async fn verify_name(
+        &mut self,
+        peer: PeerId,
+        name: String,
+    ) -> Result<Option<String>, NetError> {
+        let response = self
+            .queue(|reply| Command::FriendCommand(FriendCommand::VerifyName { name, peer, reply }))
             .await
-            .expect("to send request");
+            .result()
+            .await?;
+        match response {
+            FriendResponse::VerifyName(name) => Ok(name),
+            _ => Err(NetError::UnexpectedResponse),
+        }
     }
-    async fn send_friend_request(&mut self, peer: PeerId) {
-        self.command_sender
-            .send(super::Command::FriendCommand(FriendCommand::AddFriend {
-                peer,
-            }))
+    pub async fn send_friend_request(&mut self, peer: PeerId) -> Result<(), NetError> {
+        let response = self
+            .queue(|reply| Command::FriendCommand(FriendCommand::AddFriend { peer, reply }))
             .await
-            .expect("to send request");
+            .result()
+            .await?;
+        match response {
+            FriendResponse::AddFriendAck => Ok(()),
+            _ => Err(NetError::UnexpectedResponse),
+        }
     }
-    async fn accept_friend_req(&mut self, peer: PeerId) {
-        self.command_sender
-            .send(super::Command::FriendCommand(FriendCommand::AcceptFriend {
-                peer,
-                decision: true,
-            }))
-            .await
-            .expect("to send request");
+    pub async fn accept_friend_req(&mut self, peer: PeerId) -> Result<(), NetError> {
+        self.answer_friend_req(peer, true).await
     }
-    async fn deny_friend_req(&mut self, peer: PeerId) {
-        self.command_sender
-            .send(super::Command::FriendCommand(FriendCommand::AcceptFriend {
-                peer,
-                decision: false,
-            }))
+    pub async fn deny_friend_req(&mut self, peer: PeerId) -> Result<(), NetError> {
+        self.answer_friend_req(peer, false).await
+    }
+    async fn answer_friend_req(&mut self, peer: PeerId, decision: bool) -> Result<(), NetError> {
+        let response = self
+            .queue(|reply| {
+                Command::FriendCommand(FriendCommand::AcceptFriend {
+                    peer,
+                    decision,
+                    reply,
+                })
+            })
             .await
-            .expect("to send request");
+            .result()
+            .await?;
+        match response {
+            FriendResponse::AcceptFriendAck => Ok(()),
+            _ => Err(NetError::UnexpectedResponse),
+        }
     }
 }
 
@@ -112,3 +298,64 @@ impl Client {
 // AcceptFriendRequest
 // I want / dont want to be ur friend
 // acknowledged
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use libp2p::request_response::{Codec, cbor};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn v1_peers_get_a_friend_request_without_the_name() {
+        let request = FriendRequest::AddFriend {
+            name: Some("alice".to_string()),
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        FriendCodec::default()
+            .write_request(&V1, &mut buffer, request)
+            .await
+            .unwrap();
+        buffer.set_position(0);
+        // what a node from before names were sent reads
+        let received = cbor::codec::Codec::<LegacyFriendRequest, Signed<FriendResponse>>::default()
+            .read_request(&V1, &mut buffer)
+            .await
+            .unwrap();
+        assert!(matches!(received, LegacyFriendRequest::AddFriend));
+    }
+    #[tokio::test]
+    async fn v1_friend_requests_decode_without_a_name() {
+        let mut buffer = Cursor::new(Vec::new());
+        cbor::codec::Codec::<LegacyFriendRequest, Signed<FriendResponse>>::default()
+            .write_request(&V1, &mut buffer, LegacyFriendRequest::AddFriend)
+            .await
+            .unwrap();
+        buffer.set_position(0);
+        let received = FriendCodec::default()
+            .read_request(&V1, &mut buffer)
+            .await
+            .unwrap();
+        assert!(matches!(received, FriendRequest::AddFriend { name: None }));
+    }
+    #[tokio::test]
+    async fn v2_friend_requests_carry_the_name() {
+        let request = FriendRequest::AddFriend {
+            name: Some("alice".to_string()),
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        let mut codec = FriendCodec::default();
+        codec
+            .write_request(&PROTOCOLS[0], &mut buffer, request)
+            .await
+            .unwrap();
+        buffer.set_position(0);
+        let received = codec
+            .read_request(&PROTOCOLS[0], &mut buffer)
+            .await
+            .unwrap();
+        assert!(
+            matches!(received, FriendRequest::AddFriend { name: Some(name) } if name == "alice")
+        );
+    }
+}
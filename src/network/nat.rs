@@ -0,0 +1,262 @@
+use libp2p::core::ConnectedPoint;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::ConnectionId;
+use libp2p::{Multiaddr, PeerId, autonat, dcutr, relay};
+use serde::{Deserialize, Serialize};
+
+use crate::network::{Event, EventLoop};
+
+/// How we are connected to a peer, relayed connections are upgraded
+/// to direct ones by hole punching where the NATs allow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Route {
+    Direct,
+    Relayed,
+}
+impl Route {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Route::Direct => "direct",
+            Route::Relayed => "relayed",
+        }
+    }
+}
+impl EventLoop {
+    pub(crate) async fn handle_connection_opened(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.connections
+            .entry(peer)
+            .or_default()
+            .insert(connection_id, endpoint.is_relayed());
+        self.send_route(peer).await;
+    }
+    pub(crate) async fn handle_connection_closed(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+    ) {
+        if let Some(connections) = self.connections.get_mut(&peer) {
+            connections.remove(&connection_id);
+            if connections.is_empty() {
+                self.connections.remove(&peer);
+            }
+        }
+        self.send_route(peer).await;
+    }
+    /// The best route to `peer`, any direct connection beats a relayed one
+    fn route(&self, peer: &PeerId) -> Option<Route> {
+        let connections = self.connections.get(peer)?;
+        match connections.values().all(|relayed| *relayed) {
+            true => Some(Route::Relayed),
+            false => Some(Route::Direct),
+        }
+    }
+    async fn send_route(&mut self, peer: PeerId) {
+        let route = self.route(&peer);
+        self.event_sender
+            .send(Event::Route { peer, route })
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+    /// Relays only hand out reservations on addresses they know to be reachable,
+    /// as a relay server we are run where public addresses we listen on can be reached.
+    /// Others, e.g. behind a port forward, are given with `--external-address`.
+    pub(crate) fn advertise_relay_address(&mut self, address: Multiaddr) {
+        if !self.swarm.behaviour().relay.is_enabled() || !is_public(&address) {
+            return;
+        }
+        tracing::info!("relaying on {address}");
+        self.swarm.add_external_address(address);
+    }
+    pub(crate) fn handle_relay_client_event(&mut self, event: relay::client::Event) {
+        match event {
+            relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal: false,
+                ..
+            } => tracing::info!("reachable through relay {relay_peer_id}"),
+            relay::client::Event::ReservationReqAccepted { .. } => {}
+            relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                tracing::info!("connected to a peer through relay {relay_peer_id}")
+            }
+            relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                tracing::info!("{src_peer_id} connected to us through a relay")
+            }
+        }
+    }
+    pub(crate) fn handle_relay_event(&mut self, event: relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed: false,
+            } => tracing::info!("relaying for {src_peer_id}"),
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => tracing::debug!("relaying from {src_peer_id} to {dst_peer_id}"),
+            event => tracing::debug!("relay: {event:?}"),
+        }
+    }
+    pub(crate) fn handle_autonat_event(&mut self, event: autonat::Event) {
+        if let autonat::Event::StatusChanged { new, .. } = event {
+            match new {
+                autonat::NatStatus::Public(address) => {
+                    tracing::info!("publicly reachable at {address}")
+                }
+                autonat::NatStatus::Private => {
+                    tracing::info!("behind NAT, peers can only reach us through a relay")
+                }
+                autonat::NatStatus::Unknown => {}
+            }
+        }
+    }
+    pub(crate) fn handle_dcutr_event(&mut self, event: dcutr::Event) {
+        match event.result {
+            Ok(_) => tracing::info!("hole punched to {}", event.remote_peer_id),
+            Err(err) => tracing::info!("staying relayed to {}: {err}", event.remote_peer_id),
+        }
+    }
+}
+
+/// Whether others can reach `address` from anywhere, as far as the address itself tells.
+fn is_public(address: &Multiaddr) -> bool {
+    address.iter().all(|protocol| match protocol {
+        Protocol::Ip4(ip) => {
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local())
+        }
+        Protocol::Ip6(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+        Protocol::P2pCircuit => false,
+        _ => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::db::Database;
+    use crate::network::dial::DialTarget;
+    use crate::network::{self, Client};
+    use libp2p::identity::Keypair;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    async fn node(
+        keys: Keypair,
+        listen: Multiaddr,
+        relays: Vec<Multiaddr>,
+        relay_server: bool,
+        external_addresses: Vec<Multiaddr>,
+    ) -> (Client, mpsc::Receiver<Event>) {
+        let config = NetworkConfig {
+            relays,
+            relay_server,
+            external_addresses,
+            ..NetworkConfig::local(vec![listen])
+        };
+        let (contacts, _) = mpsc::unbounded_channel();
+        let db = Database::open_in_memory().await.unwrap();
+        let (event_loop, client, events) =
+            network::new(Default::default(), contacts, db, keys, config)
+                .await
+                .unwrap();
+        tokio::spawn(event_loop.run());
+        (client, events)
+    }
+    fn drain(mut events: mpsc::Receiver<Event>) {
+        tokio::spawn(async move { while events.recv().await.is_some() {} });
+    }
+
+    #[test]
+    fn only_public_addresses_are_advertised() {
+        for address in [
+            "/ip4/203.0.113.7/tcp/4001",
+            "/ip6/2001:db8::1/udp/4001/quic-v1",
+            "/dns4/relay.example.com/tcp/4001",
+        ] {
+            assert!(is_public(&address.parse().unwrap()), "{address}");
+        }
+        for address in [
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/192.168.1.20/tcp/4001",
+            "/ip4/10.0.0.2/udp/4001/quic-v1",
+            "/ip4/169.254.3.4/tcp/4001",
+            "/ip4/0.0.0.0/tcp/4001",
+            "/ip6/::1/tcp/4001",
+            "/ip6/fd00::2/tcp/4001",
+            "/ip6/fe80::1/tcp/4001",
+            "/ip4/203.0.113.7/tcp/4001/p2p-circuit",
+        ] {
+            assert!(!is_public(&address.parse().unwrap()), "{address}");
+        }
+    }
+    #[tokio::test]
+    async fn peers_behind_a_relay_are_reached_through_it_then_directly() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let relay_keys = Keypair::generate_ed25519();
+        let listen: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let relay = listen
+            .clone()
+            .with(Protocol::P2p(relay_keys.public().to_peer_id()));
+        // loopback isn't advertised on its own, so it's given like a port forward would be
+        let (_relay, events) = node(relay_keys, listen.clone(), vec![], true, vec![listen]).await;
+        drain(events);
+
+        let loopback: Multiaddr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        let alice_keys = Keypair::generate_ed25519();
+        let alice = alice_keys.public().to_peer_id();
+        let (_alice, events) = node(
+            alice_keys,
+            loopback.clone(),
+            vec![relay.clone()],
+            false,
+            vec![],
+        )
+        .await;
+        drain(events);
+
+        let (mut bob, mut events) =
+            node(Keypair::generate_ed25519(), loopback, vec![], false, vec![]).await;
+        let target = DialTarget::Contact {
+            peer: alice,
+            addresses: vec![relay.with(Protocol::P2pCircuit)],
+        };
+        let mut routes = Vec::new();
+        let punched = timeout(Duration::from_secs(20), async {
+            bob.dial(target.clone()).await;
+            while let Some(event) = events.recv().await {
+                match event {
+                    // alice may not hold her reservation yet
+                    Event::DialFailed { .. } => {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        bob.dial(target.clone()).await;
+                    }
+                    Event::Route { peer, route } if peer == alice => {
+                        routes.push(route);
+                        if route == Some(Route::Direct) {
+                            return;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await;
+        assert!(punched.is_ok(), "no direct connection, routes: {routes:?}");
+        assert_eq!(routes, [Some(Route::Relayed), Some(Route::Direct)]);
+    }
+}
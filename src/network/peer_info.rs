@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+
+use libp2p::{PeerId, StreamProtocol, identify};
+
+use crate::db::models::PeerInfo;
+use crate::network::{Event, EventLoop};
+
+/// Announced over identify, peers on a different major version can't talk to us
+pub const PROTOCOL_VERSION: &str = "/p2pchat/1";
+
+pub fn agent_version() -> String {
+    format!("p2pchat/{}", env!("CARGO_PKG_VERSION"))
+}
+impl EventLoop {
+    pub(crate) async fn handle_identify_event(&mut self, event: identify::Event) {
+        let identify::Event::Received { peer_id, info, .. } = event else {
+            return;
+        };
+        tracing::info!(
+            "{peer_id} runs {} ({}) with {} protocols",
+            info.agent_version,
+            info.protocol_version,
+            info.protocols.len()
+        );
+        if info.protocol_version != PROTOCOL_VERSION {
+            tracing::info!(
+                "{peer_id} speaks {} instead of {PROTOCOL_VERSION}",
+                info.protocol_version
+            );
+        }
+        for address in info.listen_addrs {
+            self.record_address(peer_id, address).await;
+        }
+        let record = PeerInfo {
+            agent_version: info.agent_version,
+            protocol_version: info.protocol_version,
+            protocols: info.protocols.iter().map(|p| p.to_string()).collect(),
+        };
+        if let Err(err) = self.db.record_peer_info(peer_id, record).await {
+            tracing::error!("failed to store info of {peer_id}: {err}");
+        }
+        self.peer_protocols
+            .insert(peer_id, info.protocols.into_iter().collect());
+    }
+    /// A request failed because `peer` turned out not to speak any of `versions`.
+    pub(crate) async fn handle_unsupported(&mut self, peer: PeerId, versions: &[StreamProtocol]) {
+        if let Some(protocols) = self.peer_protocols.get_mut(&peer) {
+            protocols.retain(|protocol| !versions.contains(protocol));
+        }
+        // the oldest version is the least they would need
+        let protocol = versions
+            .last()
+            .expect("a protocol to have versions")
+            .clone();
+        tracing::info!("{peer} doesn't support {protocol}");
+        self.event_sender
+            .send(Event::Unsupported { peer, protocol })
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+    /// The newest of `versions` (newest first) that `peer` speaks, `None` if it speaks none.
+    /// Peers that haven't identified themselves yet get the newest, the swarm still
+    /// settles on one both sides speak and reports `UnsupportedProtocols` if there's none.
+    pub(crate) async fn negotiate(
+        &mut self,
+        peer: PeerId,
+        versions: &[StreamProtocol],
+    ) -> Option<StreamProtocol> {
+        let protocol = newest_known(self.peer_protocols.get(&peer), versions);
+        if protocol.is_none() {
+            self.handle_unsupported(peer, versions).await;
+        }
+        protocol
+    }
+}
+/// The newest of `versions` in what a peer announced, the newest at all if it didn't yet.
+fn newest_known(
+    protocols: Option<&HashSet<StreamProtocol>>,
+    versions: &[StreamProtocol],
+) -> Option<StreamProtocol> {
+    match protocols {
+        Some(protocols) => versions.iter().find(|v| protocols.contains(v)).cloned(),
+        None => versions.first().cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V2: StreamProtocol = StreamProtocol::new("/test/2");
+    const V1: StreamProtocol = StreamProtocol::new("/test/1");
+
+    #[test]
+    fn the_newest_version_both_speak_is_used() {
+        let versions = [V2, V1];
+        let old = HashSet::from([V1]);
+        assert_eq!(newest_known(Some(&old), &versions), Some(V1));
+        let both = HashSet::from([V1, V2]);
+        assert_eq!(newest_known(Some(&both), &versions), Some(V2));
+    }
+    #[test]
+    fn protocols_a_peer_did_not_announce_are_skipped() {
+        let other = HashSet::from([StreamProtocol::new("/other/1")]);
+        assert_eq!(newest_known(Some(&other), &[V2, V1]), None);
+        assert_eq!(newest_known(Some(&HashSet::new()), &[V2, V1]), None);
+    }
+    #[test]
+    fn unidentified_peers_are_offered_the_newest() {
+        assert_eq!(newest_known(None, &[V2, V1]), Some(V2));
+    }
+}
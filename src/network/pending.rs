@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use libp2p::request_response::OutboundFailure;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::network::{Client, Command};
+
+/// Why a request to a peer didn't get the answer we asked for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetError {
+    /// The peer doesn't speak the protocol
+    Unsupported,
+    /// The peer refused the request
+    Rejected,
+    /// The response wasn't signed by the peer we asked
+    InvalidSignature,
+    /// The peer answered something other than what was asked
+    UnexpectedResponse,
+    Timeout,
+    /// The request couldn't be delivered
+    Failed(String),
+    /// The network stopped before answering
+    Closed,
+}
+impl std::fmt::Display for NetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetError::Unsupported => write!(f, "the peer doesn't support this"),
+            NetError::Rejected => write!(f, "the peer rejected the request"),
+            NetError::InvalidSignature => write!(f, "the response has an invalid signature"),
+            NetError::UnexpectedResponse => write!(f, "the peer sent an unexpected response"),
+            NetError::Timeout => write!(f, "the peer didn't answer in time"),
+            NetError::Failed(reason) => write!(f, "{reason}"),
+            NetError::Closed => write!(f, "the network stopped"),
+        }
+    }
+}
+impl std::error::Error for NetError {}
+impl From<&OutboundFailure> for NetError {
+    fn from(failure: &OutboundFailure) -> Self {
+        match failure {
+            OutboundFailure::Timeout => NetError::Timeout,
+            OutboundFailure::UnsupportedProtocols => NetError::Unsupported,
+            failure => NetError::Failed(failure.to_string()),
+        }
+    }
+}
+/// Where the event loop answers a command, kept next to the request id until the peer responds.
+pub type Reply<T> = oneshot::Sender<Result<T, NetError>>;
+
+/// The answer to a queued command. Dropping it stops waiting,
+/// the command itself still goes out.
+pub struct Pending<T> {
+    reply: oneshot::Receiver<Result<T, NetError>>,
+    timeout: Duration,
+}
+impl<T> Pending<T> {
+    pub async fn result(self) -> Result<T, NetError> {
+        match tokio::time::timeout(self.timeout, self.reply).await {
+            Ok(Ok(result)) => result,
+            // the event loop dropped the reply
+            Ok(Err(_)) => Err(NetError::Closed),
+            Err(_) => Err(NetError::Timeout),
+        }
+    }
+}
+impl Client {
+    /// Queues the command built around a reply channel, without waiting for the answer.
+    pub(crate) async fn queue<T>(
+        &mut self,
+        command: impl FnOnce(Reply<T>) -> Command,
+    ) -> Pending<T> {
+        let (tx, rx) = oneshot::channel();
+        // a failed send drops the reply with the command, which reads as closed
+        let _ = self.command_sender.send(command(tx)).await;
+        Pending {
+            reply: rx,
+            // leaves the peer time to be dialed before its request timeout starts
+            timeout: self.request_timeout * 2,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::friends::{FriendCommand, FriendResponse};
+    use libp2p::PeerId;
+    use tokio::sync::mpsc;
+
+    fn client(request_timeout: Duration) -> (Client, mpsc::Receiver<Command>) {
+        Client::forwarding(PeerId::random(), Default::default(), request_timeout)
+    }
+    async fn name_request(
+        commands: &mut mpsc::Receiver<Command>,
+    ) -> (PeerId, Reply<FriendResponse>) {
+        match commands.recv().await {
+            Some(Command::FriendCommand(FriendCommand::RequestName { peer, reply })) => {
+                (peer, reply)
+            }
+            _ => panic!("expected a name request"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_reach_the_request_they_answer() {
+        let (client, mut commands) = client(Duration::from_secs(5));
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let ask = |peer| {
+            let mut client = client.clone();
+            tokio::spawn(async move { client.request_name(peer).await })
+        };
+        let asked_alice = ask(alice);
+        let first = name_request(&mut commands).await;
+        let asked_bob = ask(bob);
+        let second = name_request(&mut commands).await;
+        // answered in the opposite order they were asked
+        for (peer, reply) in [second, first] {
+            let name = match peer == alice {
+                true => "alice",
+                false => "bob",
+            };
+            let _ = reply.send(Ok(FriendResponse::RequestName {
+                name: name.to_string(),
+            }));
+        }
+        assert_eq!(asked_alice.await.unwrap().unwrap(), "alice");
+        assert_eq!(asked_bob.await.unwrap().unwrap(), "bob");
+    }
+    #[tokio::test]
+    async fn dropped_replies_read_as_closed() {
+        let (mut client, mut commands) = client(Duration::from_secs(5));
+        let asked = tokio::spawn(async move { client.request_name(PeerId::random()).await });
+        drop(name_request(&mut commands).await);
+        assert_eq!(asked.await.unwrap(), Err(NetError::Closed));
+
+        // without an event loop the command is dropped before it is sent
+        let (mut client, commands) = self::client(Duration::from_secs(5));
+        drop(commands);
+        let result = client.request_name(PeerId::random()).await;
+        assert_eq!(result, Err(NetError::Closed));
+    }
+    #[tokio::test]
+    async fn unanswered_requests_time_out() {
+        let (mut client, mut commands) = client(Duration::from_millis(50));
+        let asked = tokio::spawn(async move { client.request_name(PeerId::random()).await });
+        let (_, reply) = name_request(&mut commands).await;
+        assert_eq!(asked.await.unwrap(), Err(NetError::Timeout));
+        // the event loop may still answer, nobody is listening anymore
+        assert!(reply.send(Err(NetError::Rejected)).is_err());
+    }
+}
@@ -0,0 +1,413 @@
+use libp2p::PeerId;
+use libp2p::request_response::OutboundRequestId;
+use serde::{Deserialize, Serialize};
+
+use crate::network::{Client, Command, Event, EventLoop};
+use crate::settings::{SettingError, SettingName, SettingValue, Settings};
+
+// Presence is resent on this interval, which also keeps idle connections to contacts open
+pub const HEARTBEAT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Ephemeral notifications, these aren't signed or stored since
+/// the connection itself already authenticates the sender.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum PresenceUpdate {
+    Status {
+        status: UserStatus,
+        message: Option<String>,
+    },
+    Typing {
+        typing: bool,
+    },
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PresenceAck;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UserStatus {
+    #[default]
+    Online,
+    Away,
+    Offline,
+}
+impl UserStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Online => "online",
+            UserStatus::Away => "away",
+            UserStatus::Offline => "offline",
+        }
+    }
+}
+impl std::str::FromStr for UserStatus {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(UserStatus::Online),
+            "away" => Ok(UserStatus::Away),
+            "offline" => Ok(UserStatus::Offline),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Unknown status",
+            )),
+        }
+    }
+}
+pub enum PresenceCommand {
+    /// Our own status changed, tell everyone
+    Broadcast,
+    Typing {
+        peer: PeerId,
+        typing: bool,
+    },
+}
+impl EventLoop {
+    pub async fn handle_presence_command(&mut self, command: PresenceCommand) {
+        match command {
+            PresenceCommand::Broadcast => self.broadcast_presence().await,
+            PresenceCommand::Typing { peer, typing } => {
+                if self
+                    .setting_enabled(SettingName::SendTypingIndicators)
+                    .await
+                {
+                    self.swarm
+                        .behaviour_mut()
+                        .presence
+                        .send_request(&peer, PresenceUpdate::Typing { typing });
+                }
+            }
+        }
+    }
+    pub(crate) async fn broadcast_presence(&mut self) {
+        if !self.setting_enabled(SettingName::SharePresence).await {
+            return;
+        }
+        let update = self.own_status().await;
+        let peers: Vec<PeerId> = self.presence_peers.iter().copied().collect();
+        for peer in peers {
+            self.swarm
+                .behaviour_mut()
+                .presence
+                .send_request(&peer, update.clone());
+        }
+    }
+    /// Tells connected peers we went offline, returning the requests still on their way.
+    pub(crate) async fn say_goodbye(&mut self) -> Vec<(PeerId, OutboundRequestId)> {
+        if !self.setting_enabled(SettingName::SharePresence).await {
+            return Vec::new();
+        }
+        let goodbye = PresenceUpdate::Status {
+            status: UserStatus::Offline,
+            message: None,
+        };
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        peers
+            .into_iter()
+            .filter(|peer| self.presence_peers.contains(peer))
+            .map(|peer| {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .presence
+                    .send_request(&peer, goodbye.clone());
+                (peer, request_id)
+            })
+            .collect()
+    }
+    pub(crate) async fn send_presence(&mut self, peer: PeerId) {
+        if !self.setting_enabled(SettingName::SharePresence).await {
+            return;
+        }
+        let update = self.own_status().await;
+        self.swarm
+            .behaviour_mut()
+            .presence
+            .send_request(&peer, update);
+    }
+    pub(crate) async fn handle_presence_update(&mut self, peer: PeerId, update: PresenceUpdate) {
+        let event = match update {
+            PresenceUpdate::Status { status, message } => Event::PresenceChanged {
+                peer,
+                status,
+                message,
+            },
+            PresenceUpdate::Typing { typing } => Event::Typing { peer, typing },
+        };
+        self.event_sender
+            .send(event)
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+    /// Presence derived from the connection, used until the peer tells us its status.
+    pub(crate) async fn handle_connection_change(&mut self, peer: PeerId, connected: bool) {
+        let status = match connected {
+            true => {
+                self.presence_peers.insert(peer);
+                self.send_presence(peer).await;
+                UserStatus::Online
+            }
+            false => UserStatus::Offline,
+        };
+        self.event_sender
+            .send(Event::PresenceChanged {
+                peer,
+                status,
+                message: None,
+            })
+            .await
+            .expect("Event receiver not to be dropped.");
+    }
+    async fn own_status(&mut self) -> PresenceUpdate {
+        let settings = self.settings.read().await;
+        let status = match settings.get(&SettingName::Status).map(|s| s.get_value()) {
+            Some(SettingValue::String(Some(status))) => status.parse().unwrap_or_default(),
+            _ => UserStatus::default(),
+        };
+        let message = match settings
+            .get(&SettingName::StatusMessage)
+            .map(|s| s.get_value())
+        {
+            Some(SettingValue::String(message)) => message.clone(),
+            _ => None,
+        };
+        PresenceUpdate::Status { status, message }
+    }
+    async fn setting_enabled(&mut self, name: SettingName) -> bool {
+        let settings = self.settings.read().await;
+        matches!(
+            settings.get(&name).map(|s| s.get_value()),
+            Some(SettingValue::Bool(true))
+        )
+    }
+}
+impl Client {
+    /// Sets and persists our status, then lets connected peers know.
+    pub async fn set_status(
+        &mut self,
+        status: UserStatus,
+        message: Option<String>,
+    ) -> Result<(), SettingError> {
+        let message = SettingValue::String(message);
+        SettingName::StatusMessage.spec().validate(&message)?;
+        let status = SettingValue::String(Some(status.as_str().to_string()));
+        self.set_setting(SettingName::Status, status).await?;
+        self.set_setting(SettingName::StatusMessage, message).await
+    }
+    /// Sets and persists a setting, the event loop picks it up on next use.
+    /// Connected peers are told if it changes what they see of us.
+    pub async fn set_setting(
+        &mut self,
+        name: SettingName,
+        value: SettingValue,
+    ) -> Result<(), SettingError> {
+        {
+            let mut settings = self.settings.write().await;
+            settings
+                .entry(name)
+                .or_insert_with(|| name.spec().default_setting())
+                .set_value(name, value)?;
+            Settings::save(&settings).await?;
+        }
+        if matches!(
+            name,
+            SettingName::SharePresence | SettingName::Status | SettingName::StatusMessage
+        ) {
+            self.broadcast_presence().await;
+        }
+        Ok(())
+    }
+    /// Tells connected peers our current status.
+    pub async fn broadcast_presence(&mut self) {
+        self.command_sender
+            .send(Command::PresenceCommand(PresenceCommand::Broadcast))
+            .await
+            .expect("to send presence");
+    }
+    pub async fn set_typing(&mut self, peer: PeerId, typing: bool) {
+        self.command_sender
+            .send(Command::PresenceCommand(PresenceCommand::Typing {
+                peer,
+                typing,
+            }))
+            .await
+            .expect("to send presence");
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::db::Database;
+    use crate::network::dial::DialTarget;
+    use crate::settings::{Setting, registry};
+    use libp2p::Multiaddr;
+    use libp2p::identity::Keypair;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{RwLock, mpsc};
+    use tokio::time::timeout;
+
+    type SharedSettings = Arc<RwLock<HashMap<SettingName, Setting>>>;
+
+    fn settings(overrides: &[(SettingName, SettingValue)]) -> SharedSettings {
+        let mut settings: HashMap<_, _> = registry()
+            .iter()
+            .map(|spec| (spec.name, spec.default_setting()))
+            .collect();
+        for (name, value) in overrides {
+            settings
+                .get_mut(name)
+                .unwrap()
+                .set_value(*name, value.clone())
+                .unwrap();
+        }
+        Arc::new(RwLock::new(settings))
+    }
+    async fn node(
+        keys: Keypair,
+        settings: SharedSettings,
+    ) -> (Client, mpsc::Receiver<Event>, Multiaddr) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listen: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let config = NetworkConfig::local(vec![listen.clone()]);
+        let (contacts, _) = mpsc::unbounded_channel();
+        let db = Database::open_in_memory().await.unwrap();
+        let (event_loop, client, events) =
+            crate::network::new(settings, contacts, db, keys, config)
+                .await
+                .unwrap();
+        tokio::spawn(event_loop.run());
+        (client, events, listen)
+    }
+    /// Starts alice and bob and has bob dial alice, returning once they are connected.
+    async fn connected(
+        alice_settings: SharedSettings,
+    ) -> (PeerId, Client, PeerId, Client, mpsc::Receiver<Event>) {
+        let alice_keys = Keypair::generate_ed25519();
+        let alice = alice_keys.public().to_peer_id();
+        let (alice_client, mut alice_events, listen) = node(alice_keys, alice_settings).await;
+        let bob_keys = Keypair::generate_ed25519();
+        let bob = bob_keys.public().to_peer_id();
+        let (mut bob_client, mut bob_events, _) = node(bob_keys, settings(&[])).await;
+        bob_client
+            .dial(DialTarget::Contact {
+                peer: alice,
+                addresses: vec![listen],
+            })
+            .await;
+        next(
+            &mut bob_events,
+            |event| matches!(event, Event::PeerConnected { peer, .. } if *peer == alice),
+        )
+        .await;
+        // alice only reaches bob once she has seen the connection too
+        next(
+            &mut alice_events,
+            |event| matches!(event, Event::PeerConnected { peer, .. } if *peer == bob),
+        )
+        .await;
+        tokio::spawn(async move { while alice_events.recv().await.is_some() {} });
+        (alice, alice_client, bob, bob_client, bob_events)
+    }
+    async fn next(events: &mut mpsc::Receiver<Event>, wanted: impl Fn(&Event) -> bool) -> Event {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let event = events.recv().await.expect("the event loop to run");
+                if wanted(&event) {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("the event to arrive")
+    }
+    fn status_from(peer: PeerId) -> impl Fn(&Event) -> bool {
+        move |event| matches!(event, Event::PresenceChanged { peer: from, message: Some(_), .. } if *from == peer)
+    }
+
+    #[tokio::test]
+    async fn status_is_sent_on_connect_and_broadcast_on_change() {
+        let alice_settings = settings(&[
+            (
+                SettingName::Status,
+                SettingValue::String(Some("away".into())),
+            ),
+            (
+                SettingName::StatusMessage,
+                SettingValue::String(Some("lunch".into())),
+            ),
+        ]);
+        let (alice, mut alice_client, _, _bob_client, mut bob_events) =
+            connected(alice_settings.clone()).await;
+
+        let Event::PresenceChanged {
+            status, message, ..
+        } = next(&mut bob_events, status_from(alice)).await
+        else {
+            unreachable!()
+        };
+        assert_eq!(status, UserStatus::Away);
+        assert_eq!(message.as_deref(), Some("lunch"));
+
+        {
+            let mut settings = alice_settings.write().await;
+            for (name, value) in [
+                (SettingName::Status, Some("online")),
+                (SettingName::StatusMessage, Some("back")),
+            ] {
+                let value = SettingValue::String(value.map(str::to_string));
+                settings
+                    .get_mut(&name)
+                    .unwrap()
+                    .set_value(name, value)
+                    .unwrap();
+            }
+        }
+        alice_client.broadcast_presence().await;
+        let Event::PresenceChanged {
+            status, message, ..
+        } = next(&mut bob_events, status_from(alice)).await
+        else {
+            unreachable!()
+        };
+        assert_eq!(status, UserStatus::Online);
+        assert_eq!(message.as_deref(), Some("back"));
+    }
+    #[tokio::test]
+    async fn typing_is_only_sent_when_enabled() {
+        let alice_settings = settings(&[]);
+        let (alice, mut alice_client, bob, _bob_client, mut bob_events) =
+            connected(alice_settings.clone()).await;
+        let typing_from_alice =
+            |event: &Event| matches!(event, Event::Typing { peer, .. } if *peer == alice);
+
+        alice_client.set_typing(bob, true).await;
+        let Event::Typing { typing, .. } = next(&mut bob_events, typing_from_alice).await else {
+            unreachable!()
+        };
+        assert!(typing);
+
+        alice_settings
+            .write()
+            .await
+            .get_mut(&SettingName::SendTypingIndicators)
+            .unwrap()
+            .set_value(SettingName::SendTypingIndicators, SettingValue::Bool(false))
+            .unwrap();
+        alice_client.set_typing(bob, false).await;
+        let sent = timeout(Duration::from_secs(1), async {
+            while let Some(event) = bob_events.recv().await {
+                if typing_from_alice(&event) {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(sent.is_err(), "typing was sent while disabled");
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use crate::network::pending::NetError;
+use crate::network::{Client, Command, EventLoop};
+
+// How long peers get to take our goodbye and close their side of the connection
+const GRACE: Duration = Duration::from_secs(2);
+
+impl EventLoop {
+    /// Says goodbye to connected peers and closes every connection.
+    pub(crate) async fn shutdown(&mut self) {
+        let goodbyes = self.say_goodbye().await;
+        let deadline = Instant::now() + GRACE;
+        // requests only go out while the swarm is polled
+        while goodbyes.iter().any(|(peer, request_id)| {
+            self.swarm
+                .behaviour()
+                .presence
+                .is_pending_outbound(peer, request_id)
+        }) {
+            if !self.poll_until(deadline).await {
+                tracing::info!("not every peer got our goodbye");
+                break;
+            }
+        }
+        let peers: Vec<_> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        while self.swarm.network_info().num_peers() > 0 {
+            if !self.poll_until(deadline).await {
+                break;
+            }
+        }
+        tracing::info!("network stopped");
+    }
+    /// Handles the next swarm event, `false` once `deadline` passed.
+    async fn poll_until(&mut self, deadline: Instant) -> bool {
+        match tokio::time::timeout_at(deadline, self.swarm.select_next_some()).await {
+            Ok(event) => {
+                self.handle_event(event).await;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+impl Client {
+    /// Stops the event loop, resolves once peers were told we went offline
+    /// and the connections are closed.
+    pub async fn shutdown(&mut self) -> Result<(), NetError> {
+        self.queue(Command::Shutdown).await.result().await
+    }
+}
@@ -30,6 +30,10 @@ impl<T> Signed<T>
 where
     T: Serialize,
 {
+    /// The signed value, before its signature has been checked.
+    pub fn content(&self) -> &T {
+        &self.content
+    }
     pub fn verify(self) -> Option<(T, PublicKey)> {
         let pk = PublicKey::try_from_bytes(&self.pub_key).unwrap();
         let serialized = to_vec(&self.content).expect("Failed to serialize content");
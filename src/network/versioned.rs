@@ -0,0 +1,106 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::StreamProtocol;
+use libp2p::request_response::{self, cbor};
+use serde::{Serialize, de::DeserializeOwned};
+
+/// A request or response whose shape depends on the protocol version it goes out on.
+/// Older versions keep the shape they were released with, so their peers keep working.
+pub trait Versioned: Sized + Send {
+    /// What goes over the wire, cbor encoded
+    type Wire: Serialize + DeserializeOwned + Send;
+    /// `self` as a peer on `protocol` understands it, `None` if that version can't express it
+    fn into_wire(self, protocol: &StreamProtocol) -> Option<Self::Wire>;
+    fn from_wire(wire: Self::Wire, protocol: &StreamProtocol) -> Self;
+}
+/// Writes requests and responses in the shape of the version the peer negotiated,
+/// which a plain cbor codec can't since the swarm picks the version after we queue them.
+pub struct VersionedCodec<Req: Versioned, Resp: Versioned> {
+    cbor: cbor::codec::Codec<Req::Wire, Resp::Wire>,
+    versioned: PhantomData<(Req, Resp)>,
+}
+impl<Req: Versioned, Resp: Versioned> Default for VersionedCodec<Req, Resp> {
+    fn default() -> Self {
+        VersionedCodec {
+            cbor: Default::default(),
+            versioned: PhantomData,
+        }
+    }
+}
+impl<Req: Versioned, Resp: Versioned> Clone for VersionedCodec<Req, Resp> {
+    fn clone(&self) -> Self {
+        VersionedCodec {
+            cbor: self.cbor.clone(),
+            versioned: PhantomData,
+        }
+    }
+}
+fn unsupported(protocol: &StreamProtocol) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{protocol} can't express this"),
+    )
+}
+#[async_trait]
+impl<Req, Resp> request_response::Codec for VersionedCodec<Req, Resp>
+where
+    Req: Versioned + 'static,
+    Resp: Versioned + 'static,
+{
+    type Protocol = StreamProtocol;
+    type Request = Req;
+    type Response = Resp;
+
+    async fn read_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+    ) -> std::io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let wire = self.cbor.read_request(protocol, io).await?;
+        Ok(Req::from_wire(wire, protocol))
+    }
+    async fn read_response<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+    ) -> std::io::Result<Resp>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let wire = self.cbor.read_response(protocol, io).await?;
+        Ok(Resp::from_wire(wire, protocol))
+    }
+    async fn write_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        request: Req,
+    ) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let wire = request
+            .into_wire(protocol)
+            .ok_or_else(|| unsupported(protocol))?;
+        self.cbor.write_request(protocol, io, wire).await
+    }
+    async fn write_response<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        response: Resp,
+    ) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let wire = response
+            .into_wire(protocol)
+            .ok_or_else(|| unsupported(protocol))?;
+        self.cbor.write_response(protocol, io, wire).await
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use libp2p::identity::Keypair;
+use tokio::sync::RwLock;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
+
+use crate::config::{Config, NetworkConfig};
+use crate::db::Database;
+use crate::identity;
+use crate::network::{self, Client, Contact, Event, NetworkError};
+use crate::settings::{self, Setting, SettingName, Settings};
+
+/// A running network without a terminal, for the daemon, commands and bots.
+pub struct Node {
+    pub client: Client,
+    /// What peers did, has to be read or the event loop stalls
+    pub events: mpsc::Receiver<Event>,
+    /// Peers that were discovered or added, e.g. to show in a contact list
+    pub contacts: UnboundedReceiver<Contact>,
+    pub settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
+    pub db: Database,
+    network_task: JoinHandle<()>,
+}
+impl Node {
+    /// Opens the profile `config` points at and starts its network, creating an identity
+    /// if the profile has none yet. Sets the directories, so it fails if called twice.
+    pub async fn open(config: &Config) -> Result<Node, Box<dyn Error>> {
+        settings::set_dirs(config.dirs())?;
+        settings::create_config_path()?;
+        let settings = Settings::load().await;
+        let db = Database::open().await?;
+        let keys = identity::load_or_create()?;
+        Ok(Node::start(config.network(&settings), settings, db, keys).await?)
+    }
+    /// Starts the network with stores that are already open.
+    pub async fn start(
+        network_config: NetworkConfig,
+        settings: HashMap<SettingName, Setting>,
+        db: Database,
+        keys: Keypair,
+    ) -> Result<Node, NetworkError> {
+        let (contacts_tx, contacts) = mpsc::unbounded_channel();
+        let settings = Arc::new(RwLock::new(settings));
+        let (event_loop, client, events) = network::new(
+            settings.clone(),
+            contacts_tx,
+            db.clone(),
+            keys,
+            network_config,
+        )
+        .await?;
+        Ok(Node {
+            client,
+            events,
+            contacts,
+            settings,
+            db,
+            network_task: tokio::spawn(event_loop.run()),
+        })
+    }
+    /// Says goodbye to peers and flushes the database, `false` if either didn't stop cleanly.
+    pub async fn stop(mut self) -> bool {
+        // the event loop still reports what happens while it says goodbye
+        tokio::spawn(async move { while self.events.recv().await.is_some() {} });
+        shutdown(&mut self.client, self.network_task, self.db).await
+    }
+}
+/// Stops the network and flushes the database once nothing uses them anymore,
+/// `false` if either didn't stop cleanly.
+pub async fn shutdown(client: &mut Client, network_task: JoinHandle<()>, db: Database) -> bool {
+    let mut clean = true;
+    if let Err(err) = client.shutdown().await {
+        tracing::error!("failed to stop the network: {err}");
+        network_task.abort();
+        clean = false;
+    } else if let Err(err) = network_task.await {
+        tracing::error!("the network stopped: {err}");
+        clean = false;
+    }
+    // the event loop and interface are done with it, so this closes the last connection
+    if let Err(err) = db.close().await {
+        tracing::error!("failed to flush the database: {err}");
+        clean = false;
+    }
+    clean
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+// Longest message preview shown in a notification
+const PREVIEW_CHARS: usize = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotificationLevel {
+    Off,
+    /// Only say who sent a message
+    SenderOnly,
+    #[default]
+    FullPreview,
+}
+impl std::str::FromStr for NotificationLevel {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(NotificationLevel::Off),
+            "sender" => Ok(NotificationLevel::SenderOnly),
+            "full" => Ok(NotificationLevel::FullPreview),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Unknown notification level",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub summary: String,
+    pub body: Option<String>,
+}
+pub trait NotificationBackend: Send + Sync {
+    fn notify(&self, notification: Notification) -> BoxFuture<'_, std::io::Result<()>>;
+}
+
+/// Sends notifications to the `org.freedesktop.Notifications` service on a D-Bus connection.
+pub struct DbusBackend {
+    conn: zbus::Connection,
+}
+impl DbusBackend {
+    pub async fn session() -> zbus::Result<Self> {
+        Ok(Self::with_connection(zbus::Connection::session().await?))
+    }
+    /// Uses an existing connection, e.g. to a private bus.
+    pub fn with_connection(conn: zbus::Connection) -> Self {
+        Self { conn }
+    }
+}
+impl NotificationBackend for DbusBackend {
+    fn notify(&self, notification: Notification) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(async move {
+            let hints = HashMap::<&str, zbus::zvariant::Value>::new();
+            self.conn
+                .call_method(
+                    Some("org.freedesktop.Notifications"),
+                    "/org/freedesktop/Notifications",
+                    Some("org.freedesktop.Notifications"),
+                    "Notify",
+                    &(
+                        "p2pchat",
+                        0u32,
+                        "",
+                        notification.summary.as_str(),
+                        notification.body.as_deref().unwrap_or(""),
+                        Vec::<&str>::new(),
+                        hints,
+                        -1i32,
+                    ),
+                )
+                .await
+                .map_err(std::io::Error::other)?;
+            Ok(())
+        })
+    }
+}
+
+/// Rings the terminal bell, used when there is no notification daemon.
+pub struct BellBackend;
+impl NotificationBackend for BellBackend {
+    fn notify(&self, _notification: Notification) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(async {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(b"\x07")?;
+            stdout.flush()
+        })
+    }
+}
+
+/// Cheap to clone, so notifications can be sent without waiting on the daemon.
+#[derive(Clone)]
+pub struct Notifier {
+    backend: Arc<dyn NotificationBackend>,
+}
+impl Notifier {
+    pub fn new(backend: impl NotificationBackend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
+        }
+    }
+    /// Uses the desktop notification daemon if there is a session bus, the bell otherwise.
+    pub async fn connect() -> Self {
+        Self::or_else(DbusBackend::session().await, BellBackend)
+    }
+    fn or_else(
+        session: zbus::Result<DbusBackend>,
+        fallback: impl NotificationBackend + 'static,
+    ) -> Self {
+        match session {
+            Ok(backend) => Self::new(backend),
+            Err(err) => {
+                tracing::info!("no session bus, using the fallback notifications: {err}");
+                Self::new(fallback)
+            }
+        }
+    }
+    pub async fn message_received(&self, level: NotificationLevel, sender: &str, content: &str) {
+        let notification = match level {
+            NotificationLevel::Off => return,
+            NotificationLevel::SenderOnly => Notification {
+                summary: format!("New message from {sender}"),
+                body: None,
+            },
+            NotificationLevel::FullPreview => Notification {
+                summary: sender.to_string(),
+                body: Some(preview(content)),
+            },
+        };
+        if let Err(err) = self.backend.notify(notification.clone()).await {
+            tracing::error!("failed to send notification, ringing the bell instead: {err}");
+            let _ = BellBackend.notify(notification).await;
+        }
+    }
+}
+fn preview(content: &str) -> String {
+    match content.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &content[..end]),
+        None => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// Remembers what it was asked to show, or fails every time.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        shown: Arc<Mutex<Vec<Notification>>>,
+        fail: bool,
+    }
+    impl NotificationBackend for Recorder {
+        fn notify(&self, notification: Notification) -> BoxFuture<'_, std::io::Result<()>> {
+            self.shown.lock().unwrap().push(notification);
+            let fail = self.fail;
+            Box::pin(async move {
+                match fail {
+                    true => Err(std::io::Error::other("no daemon")),
+                    false => Ok(()),
+                }
+            })
+        }
+    }
+    async fn shown(level: NotificationLevel, content: &str) -> Vec<Notification> {
+        let recorder = Recorder::default();
+        Notifier::new(recorder.clone())
+            .message_received(level, "alice", content)
+            .await;
+        recorder.shown.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn nothing_is_shown_when_off() {
+        assert!(shown(NotificationLevel::Off, "hi").await.is_empty());
+    }
+    #[tokio::test]
+    async fn sender_only_hides_the_content() {
+        let shown = shown(NotificationLevel::SenderOnly, "hi").await;
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].summary, "New message from alice");
+        assert_eq!(shown[0].body, None);
+    }
+    #[tokio::test]
+    async fn full_preview_shows_the_content() {
+        let shown = shown(NotificationLevel::FullPreview, "hi").await;
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].summary, "alice");
+        assert_eq!(shown[0].body.as_deref(), Some("hi"));
+    }
+    #[tokio::test]
+    async fn long_previews_are_cut() {
+        let content = "a".repeat(PREVIEW_CHARS * 2);
+        let shown = shown(NotificationLevel::FullPreview, &content).await;
+        let body = shown[0].body.as_deref().unwrap();
+        assert_eq!(body.chars().count(), PREVIEW_CHARS + 1);
+        assert!(body.ends_with('…'));
+    }
+    #[tokio::test]
+    async fn failed_notifications_fall_back_without_retrying() {
+        let recorder = Recorder {
+            fail: true,
+            ..Default::default()
+        };
+        Notifier::new(recorder.clone())
+            .message_received(NotificationLevel::FullPreview, "alice", "hi")
+            .await;
+        assert_eq!(recorder.shown.lock().unwrap().len(), 1);
+    }
+    #[tokio::test]
+    async fn without_a_session_bus_the_fallback_is_used() {
+        let recorder = Recorder::default();
+        let no_bus = Err(zbus::Error::Address("no bus".to_string()));
+        Notifier::or_else(no_bus, recorder.clone())
+            .message_received(NotificationLevel::SenderOnly, "alice", "hi")
+            .await;
+        assert_eq!(recorder.shown.lock().unwrap().len(), 1);
+    }
+
+    /// The parts of `org.freedesktop.Notifications` we call.
+    struct NotificationDaemon(mpsc::UnboundedSender<(String, String, String)>);
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl NotificationDaemon {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: String,
+            _replaces_id: u32,
+            _app_icon: String,
+            summary: String,
+            body: String,
+            _actions: Vec<String>,
+            _hints: HashMap<String, zbus::zvariant::OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let _ = self.0.send((app_name, summary, body));
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn dbus_backend_calls_the_notification_daemon() {
+        let (daemon_end, our_end) = UnixStream::pair().unwrap();
+        let (shown_tx, mut shown_rx) = mpsc::unbounded_channel();
+        let daemon = zbus::connection::Builder::unix_stream(daemon_end)
+            .server(zbus::Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at(
+                "/org/freedesktop/Notifications",
+                NotificationDaemon(shown_tx),
+            )
+            .unwrap()
+            .build();
+        let ours = zbus::connection::Builder::unix_stream(our_end)
+            .p2p()
+            .build();
+        let (daemon, ours) = tokio::join!(daemon, ours);
+        let _daemon = daemon.unwrap();
+        let notifier = Notifier::new(DbusBackend::with_connection(ours.unwrap()));
+
+        notifier
+            .message_received(NotificationLevel::FullPreview, "alice", "hi")
+            .await;
+        let shown = shown_rx.recv().await.unwrap();
+        assert_eq!(shown, ("p2pchat".into(), "alice".into(), "hi".into()));
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use crate::settings::dirs;
+
+/// The profile that uses the top level directories, so existing installs keep their data
+pub const DEFAULT: &str = "default";
+
+/// Checks a profile name from the command line, names become directory names.
+pub fn parse_name(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(name.to_string()),
+        false => Err("use up to 32 letters, digits, '-' or '_'".to_string()),
+    }
+}
+/// Every profile that has been set up, the default one first.
+pub fn list() -> Vec<String> {
+    let mut profiles = std::fs::read_dir(&dirs().profiles)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| parse_name(name).is_ok() && name != DEFAULT)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    profiles.sort();
+    profiles.insert(0, DEFAULT.to_string());
+    profiles
+}
+/// Restarts the app with `profile`, keeping every other argument.
+/// Only returns if the restart failed.
+pub fn relaunch(profile: &str) -> std::io::Error {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return err,
+    };
+    let mut args = Vec::new();
+    let mut skip_value = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            _ if skip_value => skip_value = false,
+            "--profile" => skip_value = true,
+            _ if arg.starts_with("--profile=") => {}
+            _ => args.push(arg),
+        }
+    }
+    let mut command = std::process::Command::new(Path::new(&exe));
+    command.args(args).arg("--profile").arg(profile);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.exec()
+    }
+    #[cfg(not(unix))]
+    match command.status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or_default()),
+        Err(err) => err,
+    }
+}
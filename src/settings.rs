@@ -1,14 +1,111 @@
-use std::{collections::HashMap, path::PathBuf};
+mod migrate_settings;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{LazyLock, OnceLock},
+};
 
 use directories::ProjectDirs;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::fs::create_dir_all;
 use tokio::fs::read_to_string;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-enum Constraint {
-    MaxValue(i32),
-    MinValue(i32),
+/// Restricts the values a setting accepts, strings that are `None` are always accepted.
+#[derive(Clone, Debug)]
+pub enum Constraint {
+    Range { min: i32, max: i32 },
+    Length { min: usize, max: usize },
+    Pattern(Regex),
+    OneOf(&'static [&'static str]),
+}
+impl Constraint {
+    fn check(&self, value: &SettingValue) -> Result<(), SettingError> {
+        match (self, value) {
+            (Constraint::Range { min, max }, SettingValue::Int(value)) => {
+                match (min..=max).contains(&value) {
+                    true => Ok(()),
+                    false => Err(SettingError::OutOfRange {
+                        min: *min,
+                        max: *max,
+                    }),
+                }
+            }
+            (Constraint::Length { min, max }, SettingValue::String(Some(value))) => {
+                match (min..=max).contains(&&value.chars().count()) {
+                    true => Ok(()),
+                    false => Err(SettingError::Length {
+                        min: *min,
+                        max: *max,
+                    }),
+                }
+            }
+            (Constraint::Pattern(regex), SettingValue::String(Some(value))) => {
+                match regex.is_match(value) {
+                    true => Ok(()),
+                    false => Err(SettingError::Pattern(regex.as_str().to_string())),
+                }
+            }
+            (Constraint::OneOf(choices), SettingValue::String(Some(value))) => {
+                match choices.contains(&value.as_str()) {
+                    true => Ok(()),
+                    false => Err(SettingError::NotAChoice(choices)),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Range { min, max } => write!(f, "{min}..={max}"),
+            Constraint::Length { min, max } => write!(f, "{min} to {max} characters"),
+            Constraint::Pattern(regex) => write!(f, "matches {}", regex.as_str()),
+            Constraint::OneOf(choices) => write!(f, "one of {}", choices.join(", ")),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingError {
+    WrongType {
+        expected: SettingKind,
+    },
+    OutOfRange {
+        min: i32,
+        max: i32,
+    },
+    Length {
+        min: usize,
+        max: usize,
+    },
+    Pattern(String),
+    NotAChoice(&'static [&'static str]),
+    /// The settings file couldn't be written
+    Save(String),
+}
+impl std::fmt::Display for SettingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingError::WrongType { expected } => write!(f, "expected {}", expected.as_str()),
+            SettingError::OutOfRange { min, max } => {
+                write!(f, "must be between {min} and {max}")
+            }
+            SettingError::Length { min, max } => {
+                write!(f, "must be {min} to {max} characters long")
+            }
+            SettingError::Pattern(pattern) => write!(f, "must match {pattern}"),
+            SettingError::NotAChoice(choices) => write!(f, "must be one of {}", choices.join(", ")),
+            SettingError::Save(err) => write!(f, "couldn't be saved: {err}"),
+        }
+    }
+}
+impl std::error::Error for SettingError {}
+impl From<std::io::Error> for SettingError {
+    fn from(err: std::io::Error) -> Self {
+        SettingError::Save(err.to_string())
+    }
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum SettingValue {
@@ -16,6 +113,43 @@ pub enum SettingValue {
     Bool(bool),
     String(Option<String>),
 }
+impl SettingValue {
+    pub fn kind(&self) -> SettingKind {
+        match self {
+            SettingValue::Int(_) => SettingKind::Int,
+            SettingValue::Bool(_) => SettingKind::Bool,
+            SettingValue::String(_) => SettingKind::String,
+        }
+    }
+    /// Parses user input as a value of `kind`, an empty string unsets a string setting.
+    pub fn parse(kind: SettingKind, input: &str) -> Result<Self, SettingError> {
+        let input = input.trim();
+        match kind {
+            SettingKind::Int => input
+                .parse()
+                .map(SettingValue::Int)
+                .map_err(|_| SettingError::WrongType { expected: kind }),
+            SettingKind::Bool => match input {
+                "true" | "on" | "yes" => Ok(SettingValue::Bool(true)),
+                "false" | "off" | "no" => Ok(SettingValue::Bool(false)),
+                _ => Err(SettingError::WrongType { expected: kind }),
+            },
+            SettingKind::String => Ok(SettingValue::String(
+                Some(input.to_string()).filter(|s| !s.is_empty()),
+            )),
+        }
+    }
+}
+impl std::fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingValue::Int(value) => write!(f, "{value}"),
+            SettingValue::Bool(value) => write!(f, "{value}"),
+            SettingValue::String(Some(value)) => write!(f, "{value}"),
+            SettingValue::String(None) => Ok(()),
+        }
+    }
+}
 impl TryInto<String> for SettingValue {
     type Error = std::io::Error;
     fn try_into(self) -> Result<String, Self::Error> {
@@ -29,32 +163,274 @@ impl TryInto<String> for SettingValue {
         }
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingKind {
+    Int,
+    Bool,
+    String,
+}
+impl SettingKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SettingKind::Int => "a number",
+            SettingKind::Bool => "true or false",
+            SettingKind::String => "text",
+        }
+    }
+}
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum SettingName {
     Name,
+    SharePresence,
+    SendTypingIndicators,
+    Status,
+    StatusMessage,
+    Notifications,
+    ListenTcp,
+    TcpPort,
+    ListenQuic,
+    QuicPort,
+    LanDiscovery,
+    Dht,
+    Ipv6,
+    IdleTimeout,
+    RequestTimeout,
+    MaxStreams,
+    MdnsInterval,
+    PinPorts,
+    Relays,
+    RelayServer,
+}
+impl SettingName {
+    pub fn spec(&self) -> &'static SettingSpec {
+        REGISTRY
+            .iter()
+            .find(|spec| spec.name == *self)
+            .expect("every setting to be registered")
+    }
+}
+impl std::str::FromStr for SettingName {
+    type Err = String;
+    /// Accepts the name in any case, with or without separators, e.g. `share-presence`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalize = |name: &str| {
+            name.chars()
+                .filter(|c| !matches!(c, '-' | '_' | ' '))
+                .collect::<String>()
+                .to_lowercase()
+        };
+        REGISTRY
+            .iter()
+            .map(|spec| spec.name)
+            .find(|name| normalize(&format!("{name:?}")) == normalize(s))
+            .ok_or_else(|| format!("unknown setting {s}"))
+    }
+}
+/// Describes a setting, every `SettingName` has one in `REGISTRY`.
+#[derive(Debug)]
+pub struct SettingSpec {
+    pub name: SettingName,
+    pub kind: SettingKind,
+    pub default: SettingValue,
+    pub description: &'static str,
+    pub constraints: Vec<Constraint>,
+}
+impl SettingSpec {
+    pub fn validate(&self, value: &SettingValue) -> Result<(), SettingError> {
+        if value.kind() != self.kind {
+            return Err(SettingError::WrongType {
+                expected: self.kind,
+            });
+        }
+        self.constraints.iter().try_for_each(|c| c.check(value))
+    }
+    pub fn default_setting(&self) -> Setting {
+        Setting {
+            value: self.default.clone(),
+        }
+    }
 }
+/// Every setting, in the order they are listed in the settings screen.
+pub fn registry() -> &'static [SettingSpec] {
+    &REGISTRY
+}
+static REGISTRY: LazyLock<Vec<SettingSpec>> = LazyLock::new(|| {
+    vec![
+        SettingSpec {
+            name: SettingName::Name,
+            kind: SettingKind::String,
+            default: SettingValue::String(None),
+            description: "Display name shown to other peers",
+            constraints: vec![
+                Constraint::Length { min: 1, max: 32 },
+                Constraint::Pattern(Regex::new(r"^\S(.*\S)?$").expect("valid pattern")),
+            ],
+        },
+        SettingSpec {
+            name: SettingName::SharePresence,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(true),
+            description: "Share our status with peers, they still see when we're connected",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::SendTypingIndicators,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(true),
+            description: "Let the other side know while we're typing",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::Status,
+            kind: SettingKind::String,
+            default: SettingValue::String(None),
+            description: "Our status",
+            constraints: vec![Constraint::OneOf(&["online", "away", "offline"])],
+        },
+        SettingSpec {
+            name: SettingName::StatusMessage,
+            kind: SettingKind::String,
+            default: SettingValue::String(None),
+            description: "Shown next to our status",
+            constraints: vec![Constraint::Length { min: 1, max: 100 }],
+        },
+        SettingSpec {
+            name: SettingName::Notifications,
+            kind: SettingKind::String,
+            default: SettingValue::String(None),
+            description: "Desktop notifications for new messages",
+            constraints: vec![Constraint::OneOf(&["off", "sender", "full"])],
+        },
+        SettingSpec {
+            name: SettingName::ListenTcp,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(true),
+            description: "Accept connections over TCP, applies after a restart",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::TcpPort,
+            kind: SettingKind::Int,
+            default: SettingValue::Int(0),
+            description: "TCP port to listen on, 0 picks a free one",
+            constraints: vec![Constraint::Range { min: 0, max: 65535 }],
+        },
+        SettingSpec {
+            name: SettingName::ListenQuic,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(true),
+            description: "Accept connections over QUIC, applies after a restart",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::QuicPort,
+            kind: SettingKind::Int,
+            default: SettingValue::Int(0),
+            description: "UDP port to listen on for QUIC, 0 picks a free one",
+            constraints: vec![Constraint::Range { min: 0, max: 65535 }],
+        },
+        SettingSpec {
+            name: SettingName::LanDiscovery,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(true),
+            description: "Find peers on the local network with mDNS, applies after a restart",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::Dht,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(false),
+            description: "Find peers and offline messages through the DHT once it is supported",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::Ipv6,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(false),
+            description: "Also listen on IPv6, applies after a restart",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::IdleTimeout,
+            kind: SettingKind::Int,
+            default: SettingValue::Int(60),
+            description: "Seconds before an unused connection is closed, \
+                          longer than the presence heartbeat keeps contacts connected",
+            constraints: vec![Constraint::Range { min: 5, max: 3600 }],
+        },
+        SettingSpec {
+            name: SettingName::RequestTimeout,
+            kind: SettingKind::Int,
+            default: SettingValue::Int(10),
+            description: "Seconds to wait for a peer to answer a request",
+            constraints: vec![Constraint::Range { min: 1, max: 300 }],
+        },
+        SettingSpec {
+            name: SettingName::MaxStreams,
+            kind: SettingKind::Int,
+            default: SettingValue::Int(100),
+            description: "Concurrent streams per connection and protocol",
+            constraints: vec![Constraint::Range { min: 1, max: 1024 }],
+        },
+        SettingSpec {
+            name: SettingName::MdnsInterval,
+            kind: SettingKind::Int,
+            default: SettingValue::Int(300),
+            description: "Seconds between mDNS queries for peers on the local network",
+            constraints: vec![Constraint::Range { min: 1, max: 3600 }],
+        },
+        SettingSpec {
+            name: SettingName::PinPorts,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(true),
+            description: "Keep the ports picked for port 0 so contacts can reach us again",
+            constraints: Vec::new(),
+        },
+        SettingSpec {
+            name: SettingName::Relays,
+            kind: SettingKind::String,
+            default: SettingValue::String(None),
+            description: "Relays to be reachable through behind NAT, comma separated addresses ending in /p2p/<peer id>",
+            constraints: vec![Constraint::Pattern(
+                Regex::new(r"^/\S+(\s*,\s*/\S+)*$").expect("valid pattern"),
+            )],
+        },
+        SettingSpec {
+            name: SettingName::RelayServer,
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(false),
+            description: "Relay connections for peers behind NAT, for publicly reachable nodes",
+            constraints: Vec::new(),
+        },
+    ]
+});
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Setting {
-    constraints: Option<Vec<Constraint>>,
     value: SettingValue,
 }
 impl Setting {
     pub fn get_value(&self) -> &SettingValue {
         &self.value
     }
-    // TODO: Implement error if value doesnt comply with constraints
-    // std::io::ErrorKind::InvalidInput
-    pub fn set_value(&mut self, val: SettingValue) -> std::io::Result<()> {
+    /// Sets the value if it's valid for the setting `name`.
+    pub fn set_value(&mut self, name: SettingName, val: SettingValue) -> Result<(), SettingError> {
+        name.spec().validate(&val)?;
         self.value = val;
         Ok(())
     }
 }
+/// The settings file as written to disk
+#[derive(Serialize)]
+struct SettingsFile<'a> {
+    version: u64,
+    settings: &'a HashMap<SettingName, Setting>,
+}
 pub struct Settings;
 impl Settings {
     pub async fn load() -> HashMap<SettingName, Setting> {
-        let settings: HashMap<SettingName, Setting> = REQUIRED_SETTINGS
+        let settings: HashMap<SettingName, Setting> = REGISTRY
             .iter()
-            .map(|(name, setting)| (*name, setting.clone()))
+            .map(|spec| (spec.name, spec.default_setting()))
             .collect();
         create_config_path().unwrap();
         // TODO: If there is no configuration we can return
@@ -62,51 +438,209 @@ impl Settings {
         let settings_json = read_to_string(&settings_path).await;
         let json = match settings_json {
             Ok(settings) => settings,
-            Err(err) => {
+            Err(_) => {
                 tokio::fs::File::create(settings_path.clone())
                     .await
                     .unwrap();
                 "".to_string()
             }
         };
-        let mut user_settings = match serde_json::from_str::<HashMap<SettingName, Setting>>(&json) {
-            Ok(s) => s,
+        if json.trim().is_empty() {
+            return settings;
+        }
+        let file = match serde_json::from_str::<serde_json::Value>(&json) {
+            Ok(file) => file,
             Err(err) => {
-                tracing::error!("{:?}", err);
-                settings.clone()
+                tracing::error!("settings file is not valid json, using defaults: {err}");
+                backup(&settings_path, "corrupt");
+                return settings;
             }
         };
+        let (file, version) = migrate_settings::migrate(file);
+        let rewrite = version < migrate_settings::VERSION;
+        if rewrite {
+            tracing::info!(
+                "migrating settings from version {version} to {}",
+                migrate_settings::VERSION
+            );
+            backup(&settings_path, &format!("v{version}"));
+        } else if version > migrate_settings::VERSION {
+            tracing::warn!(
+                "settings were written by a newer version ({version}), keeping what we know"
+            );
+        }
 
-        // TODO: Set default values to missing options, enforce constraints
-        for (opt_key, opt_val) in settings {
-            if let Some(setting) = user_settings.get_mut(&opt_key) {
-                // TODO: enforce the constraints here
-            } else {
-                // insert the default if opt is missing
-                user_settings.insert(opt_key, opt_val);
-            }
+        let (user_settings, repaired) = parse_settings(&file);
+        if (rewrite || repaired)
+            && let Err(err) = Settings::save(&user_settings).await
+        {
+            tracing::error!("failed to save the repaired settings: {err}");
         }
         user_settings
     }
-    pub async fn save(settings: &HashMap<SettingName, Setting>) {
+    /// Writes the settings, the previous file is kept as `settings.bak`.
+    pub async fn save(settings: &HashMap<SettingName, Setting>) -> std::io::Result<()> {
         let settings_path = get_config_save_file_path(SaveFile::Settings);
         tracing::info!("saving to path: {:?}", settings_path);
-        let serialized = serde_json::to_string::<HashMap<SettingName, Setting>>(settings)
-            .expect("failed to serialize settings");
-        std::fs::write(settings_path, serialized).expect("failed to write settings");
+        let previous = std::fs::read_to_string(&settings_path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok());
+        let serialized = settings_file(settings, previous.as_ref())
+            .and_then(|file| serde_json::to_string_pretty(&file))
+            .map_err(std::io::Error::other)?;
+        backup(&settings_path, "bak");
+        // write next to the file and rename, so a crash never leaves half a file behind
+        let tmp_path = settings_path.with_extension("tmp");
+        std::fs::write(&tmp_path, serialized)?;
+        std::fs::rename(tmp_path, settings_path)
+    }
+}
+/// The settings file to write over `previous`. One written by a newer version keeps its
+/// version and the settings we don't know, so they are still there when it runs again.
+fn settings_file(
+    settings: &HashMap<SettingName, Setting>,
+    previous: Option<&serde_json::Value>,
+) -> serde_json::Result<serde_json::Value> {
+    let mut file = serde_json::to_value(SettingsFile {
+        version: migrate_settings::VERSION,
+        settings,
+    })?;
+    let Some(previous) = previous else {
+        return Ok(file);
+    };
+    let version = previous
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    // settings older versions had were migrated into ours
+    if version < migrate_settings::VERSION {
+        return Ok(file);
+    }
+    file["version"] = version.into();
+    if let (Some(ours), Some(theirs)) = (
+        file["settings"].as_object_mut(),
+        previous.get("settings").and_then(|s| s.as_object()),
+    ) {
+        for (key, value) in theirs {
+            ours.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    Ok(file)
+}
+/// Reads each setting of a migrated settings file on its own, so an unknown or broken one
+/// doesn't take the rest down. Missing and invalid ones get their default,
+/// `true` if any had to be repaired.
+fn parse_settings(file: &serde_json::Value) -> (HashMap<SettingName, Setting>, bool) {
+    let mut repaired = false;
+    let mut user_settings = HashMap::new();
+    let entries = file
+        .get("settings")
+        .and_then(|s| s.as_object())
+        .cloned()
+        .unwrap_or_default();
+    for (key, value) in entries {
+        let Ok(name) =
+            serde_json::from_value::<SettingName>(serde_json::Value::String(key.clone()))
+        else {
+            tracing::warn!("ignoring unknown setting {key}");
+            continue;
+        };
+        match serde_json::from_value::<Setting>(value) {
+            Ok(setting) => {
+                user_settings.insert(name, setting);
+            }
+            Err(err) => {
+                tracing::warn!("setting {key} can't be read, resetting it: {err}");
+                repaired = true;
+            }
+        }
+    }
+
+    // Missing settings get their default, invalid ones are reset to it
+    for spec in REGISTRY.iter() {
+        match user_settings.get(&spec.name) {
+            Some(setting) => {
+                if let Err(err) = spec.validate(&setting.value) {
+                    tracing::warn!(
+                        "setting {:?} ({}) has invalid value {:?}, resetting it: {err}",
+                        spec.name,
+                        spec.description,
+                        setting.value
+                    );
+                    user_settings.insert(spec.name, spec.default_setting());
+                    repaired = true;
+                }
+            }
+            None => {
+                user_settings.insert(spec.name, spec.default_setting());
+            }
+        }
+    }
+    (user_settings, repaired)
+}
+/// Copies `path` to `path.suffix`, if there is anything to keep.
+fn backup(path: &Path, suffix: &str) {
+    let backup_path = path.with_extension(suffix);
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() > 0 => {
+            if let Err(err) = std::fs::copy(path, &backup_path) {
+                tracing::error!("failed to back up {path:?} to {backup_path:?}: {err}");
+            }
+        }
+        _ => {}
+    }
+}
+/// Where our files are kept, set once at startup from the layered config
+#[derive(Debug, Clone)]
+pub struct Dirs {
+    /// Settings and identity
+    pub config: PathBuf,
+    /// Message database and logs
+    pub data: PathBuf,
+    /// Where named profiles keep their own `config` and `data`
+    pub profiles: PathBuf,
+}
+impl Default for Dirs {
+    fn default() -> Self {
+        let proj_dirs =
+            ProjectDirs::from("com", "Mistr", "p2pchat").expect("Couldnt determine directories");
+        // the database has always lived next to the settings
+        Self {
+            config: proj_dirs.config_dir().to_path_buf(),
+            data: proj_dirs.config_dir().to_path_buf(),
+            profiles: proj_dirs.config_dir().join("profiles"),
+        }
     }
 }
-fn create_config_path() -> std::io::Result<()> {
-    let proj_dir =
-        ProjectDirs::from("com", "Mistr", "p2pchat").expect("Couldnt determine directories");
-    create_dir_all(proj_dir.config_dir())?;
+static DIRS: OnceLock<Dirs> = OnceLock::new();
+/// Overrides the default directories, has to be called before anything is loaded.
+/// Fails once they are in use, e.g. by a profile opened earlier.
+pub fn set_dirs(dirs: Dirs) -> std::io::Result<()> {
+    set_once(&DIRS, dirs)
+}
+fn set_once(cell: &OnceLock<Dirs>, dirs: Dirs) -> std::io::Result<()> {
+    cell.set(dirs).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "the directories of another profile are already in use",
+        )
+    })
+}
+pub fn dirs() -> &'static Dirs {
+    DIRS.get_or_init(Dirs::default)
+}
+pub fn create_config_path() -> std::io::Result<()> {
+    create_dir_all(&dirs().config)?;
+    create_dir_all(&dirs().data)?;
     Ok(())
 }
 
-pub(crate) fn get_config_save_file_path(savefile: SaveFile) -> PathBuf {
-    let proj_dirs =
-        ProjectDirs::from("com", "Mistr", "p2pchat").expect("Couldnt determine directories");
-    proj_dirs.config_dir().join(
+pub fn get_config_save_file_path(savefile: SaveFile) -> PathBuf {
+    let dir = match savefile {
+        SaveFile::Database | SaveFile::Log => &dirs().data,
+        _ => &dirs().config,
+    };
+    dir.join(
         SAVE_FILES
             .iter()
             .find(|x| x.0 == savefile)
@@ -114,15 +648,144 @@ pub(crate) fn get_config_save_file_path(savefile: SaveFile) -> PathBuf {
             .1,
     )
 }
-static REQUIRED_SETTINGS: &[(SettingName, Setting)] = &[(
-    SettingName::Name,
-    Setting {
-        constraints: None,
-        value: SettingValue::String(None),
-    },
-)];
 #[derive(PartialEq)]
-pub(crate) enum SaveFile {
+pub enum SaveFile {
     Settings,
+    Database,
+    Identity,
+    Log,
+    Socket,
+}
+static SAVE_FILES: &[(SaveFile, &str)] = &[
+    (SaveFile::Settings, "settings"),
+    (SaveFile::Database, "p2pchat.db"),
+    (SaveFile::Identity, "identity.key"),
+    (SaveFile::Log, "p2pchat.log"),
+    (SaveFile::Socket, "p2pchat.sock"),
+];
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn value(settings: &HashMap<SettingName, Setting>, name: SettingName) -> &SettingValue {
+        settings[&name].get_value()
+    }
+
+    #[test]
+    fn known_settings_are_kept_and_missing_ones_defaulted() {
+        let file = json!({ "version": 1, "settings": {
+            "Name": { "value": { "String": "alice" } },
+            "TcpPort": { "value": { "Int": 4001 } },
+        } });
+        let (settings, repaired) = parse_settings(&file);
+        assert!(!repaired);
+        assert_eq!(settings.len(), registry().len());
+        assert_eq!(
+            value(&settings, SettingName::Name),
+            &SettingValue::String(Some("alice".to_string()))
+        );
+        assert_eq!(
+            value(&settings, SettingName::TcpPort),
+            &SettingValue::Int(4001)
+        );
+        assert_eq!(
+            value(&settings, SettingName::QuicPort),
+            &SettingName::QuicPort.spec().default
+        );
+    }
+    #[test]
+    fn unknown_settings_are_dropped() {
+        let file = json!({ "version": 1, "settings": {
+            "Telemetry": { "value": { "Bool": true } },
+            "Dht": { "value": { "Bool": false } },
+        } });
+        let (settings, repaired) = parse_settings(&file);
+        assert!(!repaired);
+        assert_eq!(settings.len(), registry().len());
+        assert_eq!(
+            value(&settings, SettingName::Dht),
+            &SettingValue::Bool(false)
+        );
+    }
+    #[test]
+    fn newer_files_keep_their_version_and_unknown_settings() {
+        let newer = migrate_settings::VERSION + 1;
+        let previous = json!({ "version": newer, "settings": {
+            "Telemetry": { "value": { "Bool": true } },
+            "Dht": { "value": { "Bool": true } },
+        } });
+        let (mut settings, _) = parse_settings(&previous);
+        settings
+            .get_mut(&SettingName::Dht)
+            .unwrap()
+            .set_value(SettingName::Dht, SettingValue::Bool(false))
+            .unwrap();
+        let file = settings_file(&settings, Some(&previous)).unwrap();
+        assert_eq!(file["version"], newer);
+        assert_eq!(
+            file["settings"]["Telemetry"],
+            json!({ "value": { "Bool": true } })
+        );
+        // what we know is still ours to change
+        assert_eq!(file["settings"]["Dht"]["value"], json!({ "Bool": false }));
+    }
+    #[test]
+    fn older_files_are_written_at_our_version() {
+        let previous = json!({ "settings": { "Retired": { "value": { "Bool": true } } } });
+        let (settings, _) = parse_settings(&previous);
+        let file = settings_file(&settings, Some(&previous)).unwrap();
+        assert_eq!(file["version"], migrate_settings::VERSION);
+        assert!(file["settings"].get("Retired").is_none());
+    }
+    #[test]
+    fn invalid_values_are_reset() {
+        let file = json!({ "version": 1, "settings": {
+            "TcpPort": { "value": { "Int": 70000 } },
+            "Name": { "value": { "Bool": true } },
+            "Dht": { "value": { "Bool": false } },
+        } });
+        let (settings, repaired) = parse_settings(&file);
+        assert!(repaired);
+        assert_eq!(
+            value(&settings, SettingName::TcpPort),
+            &SettingName::TcpPort.spec().default
+        );
+        assert_eq!(
+            value(&settings, SettingName::Name),
+            &SettingName::Name.spec().default
+        );
+        // the broken ones don't take the others down
+        assert_eq!(
+            value(&settings, SettingName::Dht),
+            &SettingValue::Bool(false)
+        );
+    }
+    #[test]
+    fn directories_are_only_set_once() {
+        // a cell of its own, the global one is shared by every test
+        let cell = OnceLock::new();
+        set_once(&cell, Dirs::default()).unwrap();
+        let err = set_once(&cell, Dirs::default()).expect_err("the directories to be in use");
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+    #[test]
+    fn directories_in_use_can_not_be_set() {
+        let cell = OnceLock::new();
+        cell.get_or_init(Dirs::default);
+        let err = set_once(&cell, Dirs::default()).expect_err("the directories to be in use");
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+    #[test]
+    fn unreadable_settings_are_reset() {
+        let file = json!({ "version": 1, "settings": { "Dht": "yes please" } });
+        let (settings, repaired) = parse_settings(&file);
+        assert!(repaired);
+        assert_eq!(
+            value(&settings, SettingName::Dht),
+            &SettingName::Dht.spec().default
+        );
+    }
 }
-static SAVE_FILES: &[(SaveFile, &str)] = &[(SaveFile::Settings, "settings")];
@@ -0,0 +1,74 @@
+use serde_json::{Map, Value, json};
+
+// Each migration upgrades the settings file by one version, the version is stored in the
+// file itself. Only ever append to this list.
+const MIGRATIONS: &[fn(Value) -> Value] = &[wrap_bare_map];
+
+pub(super) const VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// Upgrades `file` to the current version, returning it with the version it had.
+pub(super) fn migrate(mut file: Value) -> (Value, u64) {
+    let version = file.get("version").and_then(Value::as_u64).unwrap_or(0);
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        file = migration(file);
+        file["version"] = json!(i + 1);
+    }
+    (file, version)
+}
+/// Version 0 was a bare map of settings, each stored with its constraints.
+fn wrap_bare_map(file: Value) -> Value {
+    let settings: Map<String, Value> = match file {
+        Value::Object(settings) => settings
+            .into_iter()
+            .map(|(name, mut setting)| {
+                if let Some(setting) = setting.as_object_mut() {
+                    setting.remove("constraints");
+                }
+                (name, setting)
+            })
+            .collect(),
+        _ => Map::new(),
+    };
+    json!({ "settings": settings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_0_is_wrapped_without_constraints() {
+        let file = json!({
+            "Name": { "value": { "String": "alice" }, "constraints": [] },
+            "TcpPort": { "value": { "Int": 4001 }, "constraints": [] },
+        });
+        let (file, version) = migrate(file);
+        assert_eq!(version, 0);
+        assert_eq!(
+            file,
+            json!({
+                "version": 1,
+                "settings": {
+                    "Name": { "value": { "String": "alice" } },
+                    "TcpPort": { "value": { "Int": 4001 } },
+                },
+            })
+        );
+    }
+    #[test]
+    fn current_files_are_left_alone() {
+        let file =
+            json!({ "version": VERSION, "settings": { "Dht": { "value": { "Bool": true } } } });
+        assert_eq!(migrate(file.clone()), (file, VERSION));
+    }
+    #[test]
+    fn files_from_newer_versions_are_left_alone() {
+        let file = json!({ "version": VERSION + 1, "settings": {} });
+        assert_eq!(migrate(file.clone()), (file, VERSION + 1));
+    }
+    #[test]
+    fn anything_but_a_map_becomes_empty_settings() {
+        let (file, _) = migrate(json!(["not", "settings"]));
+        assert_eq!(file, json!({ "version": 1, "settings": {} }));
+    }
+}
@@ -1,76 +1,102 @@
+pub mod onboarding;
 pub mod types;
-mod widgets;
+use chrono::{Local, TimeZone};
 use crossterm::event::KeyCode;
 use crossterm::event::KeyEventKind;
 use crossterm::event::KeyModifiers;
 use futures::{FutureExt, StreamExt};
 use libp2p::PeerId;
+use qrcode::QrCode;
+use qrcode::render::unicode;
 use ratatui::Frame;
 use ratatui::crossterm::event::KeyCode::Char;
-use ratatui::crossterm::event::{KeyEvent, MouseEvent};
-use ratatui::layout::{Constraint, Direction, Layout};
-use ratatui::style::Style;
-use ratatui::text::Text;
+use ratatui::crossterm::event::KeyEvent;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span, Text};
 use ratatui::widgets::Paragraph;
-use ratatui::widgets::{Block, List, ListDirection, ListState, Scrollbar, ScrollbarState};
+use ratatui::widgets::{Block, Clear, List, ListDirection, ListState, Scrollbar, ScrollbarState};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use types::Message;
 
+use crate::db::Database;
+use crate::db::models::{ContactSummary, LastMessage};
 use crate::network::Client;
+use crate::network::call::{CallEnd, CallState};
+use crate::network::chat::MessageBody;
+use crate::network::contact_card::ContactCard;
+use crate::network::dial::DialTarget;
+use crate::network::nat::Route;
+use crate::network::presence::UserStatus;
+use crate::notify::{NotificationLevel, Notifier};
+use crate::profile;
+use crate::settings::{self, Setting, SettingName, SettingValue};
 use crate::tui::types::Contact;
 
 #[derive(Clone, Debug)]
 pub enum Event {
     Init,
-    Quit,
     Error,
-    Closed,
     Tick,
-    Render,
     FocusGained,
     FocusLost,
-    Paste(String),
     Key(KeyEvent),
-    Mouse(MouseEvent),
-    Resize(u16, u16),
     MessageReceived(Message),
     // TODO: do like refresh contact list from sqlite instead
     AddContact(Contact),
+    ConversationUpdated(PeerId),
+    Presence(PeerId, types::Presence),
+    Typing(PeerId, bool),
+    Call(types::Call),
+    /// Feedback on something the user did, shown under the chat input
+    Notice(String),
+    /// Our exported contact card token
+    ContactCard(String),
+    Route(PeerId, Option<Route>),
 }
 pub struct Tui {
     pub terminal: ratatui::DefaultTerminal,
     pub task: Option<JoinHandle<()>>,
     pub event_rx: UnboundedReceiver<Event>,
     pub event_tx: UnboundedSender<Event>,
-    pub frame_rate: f64,
     pub tick_rate: f64,
 }
 
 impl Tui {
     pub fn start(&mut self) {
-        // let tick_delay = std::time::Duration::from_secs_f64(1.0 / self.tick_rate);
-        // let render_delay = std::time::Duration::from_secs_f64(1.0 / self.frame_rate);
+        // already started, e.g. by the onboarding
+        if self.task.is_some() {
+            return;
+        }
+        let tick_delay = std::time::Duration::from_secs_f64(1.0 / self.tick_rate);
         let _event_tx = self.event_tx.clone();
         self.task = Some(tokio::spawn(async move {
             let mut reader = crossterm::event::EventStream::new();
-            // let mut tick_interval = tokio::time::interval(tick_delay);
-            // let mut render_interval = tokio::time::interval(render_delay);
+            let mut tick_interval = tokio::time::interval(tick_delay);
             _event_tx.send(Event::Init).unwrap();
             loop {
-                // let tick_delay = tick_interval.tick();
-                // let render_delay = render_interval.tick();
+                let tick_delay = tick_interval.tick();
                 let crossterm_event = reader.next().fuse();
                 tokio::select! {
                   maybe_event = crossterm_event => {
                     match maybe_event {
                       Some(Ok(evt)) =>
                         match evt {
-                          crossterm::event::Event::Key(key) => {
-                            if key.kind == KeyEventKind::Press {
-                              _event_tx.send(Event::Key(key)).unwrap();
-                            }
+                          crossterm::event::Event::Key(key) if key.kind == KeyEventKind::Press => {
+                            _event_tx.send(Event::Key(key)).unwrap();
+                          },
+                          crossterm::event::Event::FocusGained => {
+                            _event_tx.send(Event::FocusGained).unwrap();
+                          },
+                          crossterm::event::Event::FocusLost => {
+                            _event_tx.send(Event::FocusLost).unwrap();
                           },
                           _ => { },
                         }
@@ -81,50 +107,34 @@ impl Tui {
                       None => {},
                     }
                   },
-                  // _ = tick_delay => {
-                  //     _event_tx.send(Event::Tick).unwrap();
-                  // },
-                  // _ = render_delay => {
-                  //     _event_tx.send(Event::Render).unwrap();
-                  // },
+                  _ = tick_delay => {
+                      _event_tx.send(Event::Tick).unwrap();
+                  },
                 }
             }
         }));
     }
-    // pub fn tick_rate(self, v: f64) -> Self {
-    //     Self {
-    //         terminal: self.terminal,
-    //         task: self.task,
-    //         event_rx: self.event_rx,
-    //         event_tx: self.event_tx,
-    //         frame_rate: self.frame_rate,
-    //         tick_rate: v,
-    //     }
-    // }
-    // pub fn frame_rate(self, v: f64) -> Self {
-    //     Self {
-    //         terminal: self.terminal,
-    //         task: self.task,
-    //         event_rx: self.event_rx,
-    //         event_tx: self.event_tx,
-    //         frame_rate: v,
-    //         tick_rate: self.tick_rate,
-    //     }
-    // }
     pub fn new() -> Self {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        // ratatui's own hook restores the terminal before this one runs,
+        // the panic is logged since the terminal may be gone by the time it's read
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = crossterm::execute!(std::io::stdout(), crossterm::event::DisableFocusChange);
+            tracing::error!("{info}");
+            default_hook(info);
+        }));
+        let terminal = ratatui::init();
+        // lets us skip notifications while the user is looking at the chat
+        let _ = crossterm::execute!(std::io::stdout(), crossterm::event::EnableFocusChange);
         Self {
             event_rx: rx,
             event_tx: tx,
-            terminal: ratatui::init(),
-            frame_rate: 30.0,
+            terminal,
             tick_rate: 1.0,
             task: None,
         }
     }
-    pub fn event_tx(&self) -> UnboundedSender<Event> {
-        self.event_tx.clone()
-    }
     pub async fn next(&mut self) -> Option<Event> {
         return self.event_rx.recv().await;
     }
@@ -132,9 +142,13 @@ impl Tui {
         if let Some(task) = self.task {
             task.abort();
         }
+        let _ = crossterm::execute!(std::io::stdout(), crossterm::event::DisableFocusChange);
         ratatui::restore();
     }
 }
+// Typing notifications are repeated while typing and expire if they stop arriving
+const TYPING_RESEND: Duration = Duration::from_secs(3);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
 struct Key;
 impl Key {
     const LEFT: KeyCode = Char('h');
@@ -145,6 +159,14 @@ impl Key {
 async fn handle_event(app: &mut App, event: Event) {
     // switch tabline -> SHIFT + H/L
     // switch between selectable widgets -> CTRL + H/J/K/L
+    if let Event::Key(key) = event
+        && (handle_call_overlay(app, key).await
+            || handle_contact_card(app)
+            || handle_setting_input(app, key).await
+            || handle_profile_input(app, key))
+    {
+        return;
+    }
     match event {
         Event::Key(key) => match (key.code, key.modifiers) {
             (KeyCode::Esc, KeyModifiers::NONE) => {
@@ -153,11 +175,11 @@ async fn handle_event(app: &mut App, event: Event) {
                 return;
             }
             (Key::LEFT, KeyModifiers::SHIFT) => {
-                app.selected_tab.left();
+                app.selected_tab = app.selected_tab.left();
                 return;
             }
             (Key::RIGHT, KeyModifiers::SHIFT) => {
-                app.selected_tab.right();
+                app.selected_tab = app.selected_tab.right();
                 return;
             }
             (Key::LEFT | Key::RIGHT | Key::UP | Key::DOWN, KeyModifiers::CONTROL) => {
@@ -171,20 +193,24 @@ async fn handle_event(app: &mut App, event: Event) {
 
                         match app.selected_tab {
                             Tabline::Chatting(c) => Tabline::Chatting(c.left()),
-                            Tabline::FriendRequests(f) => unimplemented!(),
+                            Tabline::FriendRequests(f) => Tabline::FriendRequests(f),
+                            Tabline::Settings => Tabline::Settings,
                         }
                     }
                     Key::RIGHT => match app.selected_tab {
                         Tabline::Chatting(c) => Tabline::Chatting(c.right()),
-                        Tabline::FriendRequests(f) => unimplemented!(),
+                        Tabline::FriendRequests(f) => Tabline::FriendRequests(f),
+                        Tabline::Settings => Tabline::Settings,
                     },
                     Key::UP => match app.selected_tab {
                         Tabline::Chatting(c) => Tabline::Chatting(c.up()),
-                        Tabline::FriendRequests(f) => unimplemented!(),
+                        Tabline::FriendRequests(f) => Tabline::FriendRequests(f),
+                        Tabline::Settings => Tabline::Settings,
                     },
                     Key::DOWN => match app.selected_tab {
                         Tabline::Chatting(c) => Tabline::Chatting(c.down()),
-                        Tabline::FriendRequests(f) => unimplemented!(),
+                        Tabline::FriendRequests(f) => Tabline::FriendRequests(f),
+                        Tabline::Settings => Tabline::Settings,
                     },
                     _ => unreachable!(),
                 };
@@ -193,8 +219,54 @@ async fn handle_event(app: &mut App, event: Event) {
             _ => {}
         },
         Event::MessageReceived(message) => {
-            // TODO: actually handle
-            app.chat.push(message);
+            if !app
+                .contacts
+                .iter()
+                .any(|c| c.peer_id == message.sender.peer_id)
+            {
+                app.contacts.push(message.sender.clone());
+            }
+            load_contacts(app).await;
+            notify_message(app, &message).await;
+            return;
+        }
+        Event::FocusGained | Event::FocusLost => {
+            app.focused = matches!(event, Event::FocusGained);
+            if app.focused {
+                // messages that arrived while away are read now
+                load_chat(app).await;
+                load_contacts(app).await;
+            }
+            return;
+        }
+        Event::ConversationUpdated(peer_id) => {
+            let selected = app
+                .selected_contact
+                .selected()
+                .and_then(|i| app.contacts.get(i));
+            if selected.is_some_and(|c| c.peer_id == peer_id) {
+                load_chat(app).await;
+            }
+            load_contacts(app).await;
+            return;
+        }
+        Event::Call(call) => {
+            app.call = Some(call);
+            return;
+        }
+        Event::Notice(notice) => {
+            app.notice = Some(notice);
+            return;
+        }
+        Event::ContactCard(token) => {
+            app.contact_card = Some(token);
+            return;
+        }
+        Event::Route(peer_id, route) => {
+            match route {
+                Some(route) => app.routes.insert(peer_id, route),
+                None => app.routes.remove(&peer_id),
+            };
             return;
         }
         Event::AddContact(contact) => {
@@ -202,73 +274,508 @@ async fn handle_event(app: &mut App, event: Event) {
             if !app.contacts.contains(&contact) {
                 app.contacts.push(contact);
             }
+            load_contacts(app).await;
             return;
         }
+        Event::Presence(peer_id, presence) => {
+            app.presence.insert(peer_id, presence);
+            return;
+        }
+        Event::Typing(peer_id, typing) => {
+            match typing {
+                true => app.typing.insert(peer_id, Instant::now()),
+                false => app.typing.remove(&peer_id),
+            };
+            return;
+        }
+        // ticks only trigger a re-render, so typing indicators expire
+        Event::Tick => return,
         Event::Init => {}
         _ => {}
     };
     match &app.selected_tab {
         Tabline::Chatting(contact) => match contact {
-            ContactPage::ContactList => handle_contact_list(app, event),
+            ContactPage::ContactList => handle_contact_list(app, event).await,
             ContactPage::Chat => handle_chat(app, event).await,
-            ContactPage::CallButton => handle_call_button(app, event),
-        },
-        Tabline::FriendRequests(fr) => match fr {
-            FriendRequestPage::RequestList => handle_request_list(app, event),
-            FriendRequestPage::Search => handle_search(app, event),
+            ContactPage::CallButton => handle_call_button(app, event).await,
         },
+        Tabline::FriendRequests(FriendRequestPage::RequestList) => handle_request_list(app, event),
+        Tabline::Settings => handle_settings(app, event).await,
     }
 }
-fn handle_contact_list(app: &mut App, event: Event) {
+async fn handle_contact_list(app: &mut App, event: Event) {
     if let Event::Key(key) = event {
         match key.code {
             Key::RIGHT => app.selected_tab = Tabline::Chatting(ContactPage::Chat),
             Key::UP => app.selected_contact.select_previous(),
             Key::DOWN | KeyCode::Enter => app.selected_contact.select_next(),
-            _ => unimplemented!(),
+            Char('p') => {
+                toggle_pinned(app).await;
+                return;
+            }
+            _ => return,
+        }
+        load_chat(app).await;
+        load_contacts(app).await;
+    }
+}
+/// Pins the selected contact to the top of the list, or unpins it.
+async fn toggle_pinned(app: &mut App) {
+    let Some(peer_id) = app
+        .selected_contact
+        .selected()
+        .and_then(|i| app.contacts.get(i))
+        .map(|c| c.peer_id)
+    else {
+        return;
+    };
+    let pinned = app.summaries.get(&peer_id).is_some_and(|s| s.pinned);
+    if let Err(err) = app.db.set_pinned(peer_id, !pinned).await {
+        tracing::error!("failed to pin {peer_id}: {err}");
+        return;
+    }
+    load_contacts(app).await;
+}
+/// Where a contact is listed, pinned ones first and then the most recently active.
+fn contact_rank(summary: Option<&ContactSummary>) -> (bool, Reverse<Option<u64>>) {
+    (
+        !summary.is_some_and(|s| s.pinned),
+        Reverse(
+            summary
+                .and_then(|s| s.last_message.as_ref())
+                .map(|m| m.time),
+        ),
+    )
+}
+/// Refreshes unread counts and last messages from the message store, adds contacts
+/// we only know from the store and sorts pinned contacts first, then by last activity.
+async fn load_contacts(app: &mut App) {
+    let summaries = match app.db.contact_summaries().await {
+        Ok(summaries) => summaries,
+        Err(err) => {
+            tracing::error!("failed to load contacts: {err}");
+            return;
+        }
+    };
+    for summary in &summaries {
+        if !app.contacts.iter().any(|c| c.peer_id == summary.peer_id) {
+            app.contacts.push(Contact {
+                peer_id: summary.peer_id,
+                name: summary.name.clone(),
+            });
         }
     }
+    app.summaries = summaries.into_iter().map(|s| (s.peer_id, s)).collect();
+
+    let selected = app
+        .selected_contact
+        .selected()
+        .and_then(|i| app.contacts.get(i))
+        .map(|c| c.peer_id);
+    let summaries = &app.summaries;
+    app.contacts
+        .sort_by_key(|c| contact_rank(summaries.get(&c.peer_id)));
+    if let Some(selected) = selected {
+        let index = app.contacts.iter().position(|c| c.peer_id == selected);
+        app.selected_contact.select(index);
+    }
 }
 async fn handle_chat(app: &mut App, event: Event) {
     if let Event::Key(key) = event {
         match key.code {
             KeyCode::Backspace => {
                 app.chat_input.pop();
+                if app.chat_input.is_empty() {
+                    stop_typing(app).await;
+                }
             }
+            KeyCode::Up => app.selected_message.select_previous(),
+            KeyCode::Down => app.selected_message.select_next(),
             KeyCode::Enter => {
-                let receiver = app
-                    .contacts
-                    .get(app.selected_contact.selected().unwrap())
-                    .unwrap();
-                app.client
-                    .send_message(receiver.peer_id, app.chat_input.clone())
-                    .await;
-                // add the message to our chat log
-                app.chat.push(Message {
-                    sender: Contact {
-                        peer_id: app.client.id,
-                        name: "You".to_string(),
-                    },
-                    content: app.chat_input.clone(),
-                    id: uuid::Uuid::new_v4(),
-                    status: types::MessageStatus::SentOffNotRead,
-                });
+                // dialing works without a contact to chat with
+                if let Some(target) = app.chat_input.strip_prefix("/dial ") {
+                    let target = target.to_string();
+                    app.chat_input.clear();
+                    dial(app, &target).await;
+                    return;
+                }
+                if app.chat_input.trim() == "/card" {
+                    app.chat_input.clear();
+                    app.client.export_contact_card().await;
+                    return;
+                }
+                if let Some(token) = app.chat_input.strip_prefix("/add ") {
+                    let token = token.to_string();
+                    app.chat_input.clear();
+                    import_contact_card(app, &token).await;
+                    return;
+                }
+                let Some(receiver) = app
+                    .selected_contact
+                    .selected()
+                    .and_then(|i| app.contacts.get(i))
+                    .map(|c| c.peer_id)
+                else {
+                    return;
+                };
                 // clear the chat input
-                app.chat_input.clear();
+                let input = std::mem::take(&mut app.chat_input);
+                stop_typing(app).await;
+                if let Some(status) = input.strip_prefix("/status ") {
+                    set_status(app, status).await;
+                    return;
+                }
+                let Some(body) = parse_chat_input(app, input) else {
+                    return;
+                };
+                // the chat log is reloaded once the message is stored
+                let delivery = app.client.send(receiver, body).await;
+                tokio::spawn(async move {
+                    // failures are also reported as network events
+                    if let Err(err) = delivery.result().await {
+                        tracing::info!("message to {receiver} wasn't delivered: {err}");
+                    }
+                });
+            }
+            Char(ch) => {
+                app.chat_input.push(ch);
+                start_typing(app).await;
             }
-            Char(ch) => app.chat_input.push(ch),
-            _ => unimplemented!(),
+            _ => {}
         }
     }
 }
-fn handle_call_button(app: &mut App, event: Event) {
-    unimplemented!();
+/// Notifies about a new message unless its conversation is open in a focused terminal.
+async fn notify_message(app: &App, message: &Message) {
+    let selected = app
+        .selected_contact
+        .selected()
+        .and_then(|i| app.contacts.get(i));
+    let viewing = matches!(app.selected_tab, Tabline::Chatting(_))
+        && selected.is_some_and(|c| c.peer_id == message.sender.peer_id);
+    if app.focused && viewing {
+        return;
+    }
+    let level = match app
+        .settings
+        .read()
+        .await
+        .get(&SettingName::Notifications)
+        .map(|s| s.get_value())
+    {
+        Some(SettingValue::String(Some(level))) => level.parse().unwrap_or_default(),
+        _ => NotificationLevel::default(),
+    };
+    let sender = contact_name(app, &message.sender.peer_id);
+    // a slow notification daemon mustn't hold up input and drawing
+    let notifier = app.notifier.clone();
+    let content = message.content.clone();
+    tokio::spawn(async move {
+        notifier.message_received(level, &sender, &content).await;
+    });
 }
-fn handle_request_list(app: &mut App, event: Event) {
-    unimplemented!();
+/// Lets the selected contact know we are typing, repeated while the user keeps typing.
+async fn start_typing(app: &mut App) {
+    let Some(peer_id) = app
+        .selected_contact
+        .selected()
+        .and_then(|i| app.contacts.get(i))
+        .map(|c| c.peer_id)
+    else {
+        return;
+    };
+    if app.chat_input.starts_with('/') {
+        return;
+    }
+    let recently_sent = app
+        .typing_sent
+        .is_some_and(|(peer, at)| peer == peer_id && at.elapsed() < TYPING_RESEND);
+    if !recently_sent {
+        app.client.set_typing(peer_id, true).await;
+        app.typing_sent = Some((peer_id, Instant::now()));
+    }
 }
-fn handle_search(app: &mut App, event: Event) {
-    unimplemented!();
+async fn stop_typing(app: &mut App) {
+    if let Some((peer_id, _)) = app.typing_sent.take() {
+        app.client.set_typing(peer_id, false).await;
+    }
+}
+/// Handles `/dial <multiaddr>` and `/dial <peer id>@<address>[,<address>...]`
+async fn dial(app: &mut App, target: &str) {
+    match target.parse::<DialTarget>() {
+        Ok(target) => {
+            app.notice = Some(format!("dialing {target}…"));
+            app.client.dial(target).await;
+        }
+        Err(err) => app.notice = Some(err),
+    }
+}
+/// Handles `/add <contact card>`, the peer gets a friend request once reached
+async fn import_contact_card(app: &mut App, token: &str) {
+    match token.parse::<ContactCard>() {
+        Ok(card) => {
+            let name = card.name.clone().unwrap_or_else(|| card.peer.to_string());
+            app.notice = Some(format!("adding {name}…"));
+            app.client.import_contact_card(card).await;
+        }
+        Err(err) => app.notice = Some(err),
+    }
+}
+/// Handles `/status <online|away|offline> [message]`
+async fn set_status(app: &mut App, input: &str) {
+    let (status, message) = input.trim().split_once(' ').unwrap_or((input.trim(), ""));
+    let Ok(status) = status.parse::<UserStatus>() else {
+        tracing::info!("unknown status {status}");
+        return;
+    };
+    let message = Some(message.trim().to_string()).filter(|m| !m.is_empty());
+    if let Err(err) = app.client.set_status(status, message).await {
+        tracing::info!("invalid status: {err}");
+    }
+}
+/// Turns the chat input into a message, commands act on the selected message:
+/// `/reply <text>`, `/edit <text>`, `/delete`, `/react <emoji>`, `/unreact <emoji>`
+fn parse_chat_input(app: &App, input: String) -> Option<MessageBody> {
+    let selected = app
+        .selected_message
+        .selected()
+        .and_then(|i| app.chat.get(i))
+        .map(|m| m.id);
+    let (command, arg) = input.split_once(' ').unwrap_or((input.as_str(), ""));
+    let arg = arg.trim().to_string();
+    match command {
+        "/reply" if !arg.is_empty() => selected.map(|target| MessageBody::Text {
+            content: arg,
+            reply_to: Some(target),
+        }),
+        "/edit" if !arg.is_empty() => selected.map(|target| MessageBody::Edit {
+            target,
+            content: arg,
+        }),
+        "/delete" => selected.map(|target| MessageBody::Delete { target }),
+        "/react" | "/unreact" if !arg.is_empty() => selected.map(|target| MessageBody::React {
+            target,
+            emoji: arg,
+            remove: command == "/unreact",
+        }),
+        _ if input.trim().is_empty() => None,
+        _ => Some(MessageBody::Text {
+            content: input,
+            reply_to: None,
+        }),
+    }
+}
+/// Reloads the chat log of the selected contact from the message store.
+async fn load_chat(app: &mut App) {
+    let Some(peer_id) = app
+        .selected_contact
+        .selected()
+        .and_then(|i| app.contacts.get(i))
+        .map(|c| c.peer_id)
+    else {
+        app.chat.clear();
+        return;
+    };
+    // the conversation is only read if someone is looking at it
+    if app.focused
+        && matches!(app.selected_tab, Tabline::Chatting(_))
+        && let Err(err) = app.db.mark_read(peer_id).await
+    {
+        tracing::error!("failed to mark conversation with {peer_id} as read: {err}");
+    }
+    let records = match app.db.conversation(peer_id).await {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::error!("failed to load conversation with {peer_id}: {err}");
+            return;
+        }
+    };
+    let chat = records
+        .into_iter()
+        .map(|record| Message {
+            sender: Contact {
+                peer_id: record.sender,
+                name: match record.sender == app.client.id {
+                    true => "You".to_string(),
+                    false => contact_name(app, &record.sender),
+                },
+            },
+            content: record.content,
+            id: record.id,
+            reply_to: record.reply_to,
+            edited: record.edited,
+            deleted: record.deleted,
+            reactions: record.reactions,
+            sent_at: record.sent_at,
+            received_at: record.received_at,
+        })
+        .collect();
+    app.chat = chat;
+}
+async fn handle_call_button(app: &mut App, event: Event) {
+    let Event::Key(key) = event else {
+        return;
+    };
+    if key.code != KeyCode::Enter {
+        return;
+    }
+    match &app.call {
+        Some(call) if matches!(call.state, CallState::Dialing | CallState::Active) => {
+            app.client.hangup(call.id).await;
+        }
+        _ => {
+            let Some(contact) = app
+                .selected_contact
+                .selected()
+                .and_then(|i| app.contacts.get(i))
+            else {
+                return;
+            };
+            app.client.start_call(contact.peer_id).await;
+        }
+    }
+}
+/// Any key closes the contact card, returns true if it was open.
+fn handle_contact_card(app: &mut App) -> bool {
+    app.contact_card.take().is_some()
+}
+/// Handles keys for the modal parts of the call overlay,
+/// returns true if the key was consumed.
+async fn handle_call_overlay(app: &mut App, key: KeyEvent) -> bool {
+    let Some(call) = &app.call else {
+        return false;
+    };
+    match call.state {
+        CallState::Ringing => {
+            match key.code {
+                Char('y') => app.client.accept_call(call.id).await,
+                Char('n') => app.client.reject_call(call.id).await,
+                _ => {}
+            }
+            true
+        }
+        CallState::Ended(_) => {
+            // any key dismisses the overlay
+            app.call = None;
+            true
+        }
+        CallState::Dialing | CallState::Active => false,
+    }
+}
+fn handle_request_list(_app: &mut App, _event: Event) {
+    // TODO: friend requests
+}
+async fn handle_settings(app: &mut App, event: Event) {
+    let Event::Key(key) = event else {
+        return;
+    };
+    match key.code {
+        Key::UP | KeyCode::Up => app.selected_setting.select_previous(),
+        Key::DOWN | KeyCode::Down => app.selected_setting.select_next(),
+        Char('p') => app.profile_input = Some(String::new()),
+        KeyCode::Enter => {
+            let Some(spec) = app
+                .selected_setting
+                .selected()
+                .and_then(|i| settings::registry().get(i))
+            else {
+                return;
+            };
+            let value = app
+                .settings
+                .read()
+                .await
+                .get(&spec.name)
+                .map(|s| s.get_value().clone())
+                .unwrap_or_else(|| spec.default.clone());
+            match value {
+                // booleans are toggled right away
+                SettingValue::Bool(value) => {
+                    save_setting(app, spec.name, SettingValue::Bool(!value)).await;
+                }
+                value => {
+                    app.setting_error = None;
+                    app.setting_input = Some(value.to_string());
+                }
+            }
+        }
+        _ => {}
+    }
+}
+/// Handles keys while a setting is being edited, returns true if the key was consumed.
+async fn handle_setting_input(app: &mut App, key: KeyEvent) -> bool {
+    let Some(input) = &mut app.setting_input else {
+        return false;
+    };
+    match key.code {
+        Char(ch) => input.push(ch),
+        KeyCode::Backspace => {
+            input.pop();
+        }
+        KeyCode::Esc => app.setting_input = None,
+        KeyCode::Enter => {
+            let Some(spec) = app
+                .selected_setting
+                .selected()
+                .and_then(|i| settings::registry().get(i))
+            else {
+                app.setting_input = None;
+                return true;
+            };
+            let input = app.setting_input.take().unwrap_or_default();
+            let saved = match SettingValue::parse(spec.kind, &input) {
+                Ok(value) => save_setting(app, spec.name, value).await,
+                Err(err) => {
+                    app.setting_error = Some(err.to_string());
+                    false
+                }
+            };
+            // keep editing so the input can be fixed
+            if !saved {
+                app.setting_input = Some(input);
+            }
+        }
+        _ => {}
+    }
+    true
+}
+/// Handles keys while a profile name is entered, returns true if the key was consumed.
+/// Switching restarts the app, a new profile starts with the onboarding.
+fn handle_profile_input(app: &mut App, key: KeyEvent) -> bool {
+    let Some(input) = &mut app.profile_input else {
+        return false;
+    };
+    match key.code {
+        Char(ch) => input.push(ch),
+        KeyCode::Backspace => {
+            input.pop();
+        }
+        KeyCode::Esc => app.profile_input = None,
+        KeyCode::Enter => match profile::parse_name(input.trim()) {
+            Ok(name) if name == app.profile => app.profile_input = None,
+            Ok(name) => {
+                app.switch_profile = Some(name);
+                app.should_quit = true;
+                app.token.cancel();
+            }
+            Err(err) => app.setting_error = Some(format!("profile name: {err}")),
+        },
+        _ => {}
+    }
+    true
+}
+/// Saves a setting, showing why if it was refused. Returns whether it was saved.
+async fn save_setting(app: &mut App, name: SettingName, value: SettingValue) -> bool {
+    match app.client.set_setting(name, value).await {
+        Ok(()) => {
+            app.setting_error = None;
+            true
+        }
+        Err(err) => {
+            app.setting_error = Some(err.to_string());
+            false
+        }
+    }
 }
 trait MoveHorizontal {
     fn left(self) -> Self;
@@ -282,6 +789,7 @@ trait MoveVertical {
 enum Tabline {
     Chatting(ContactPage),
     FriendRequests(FriendRequestPage),
+    Settings,
 }
 impl Default for Tabline {
     fn default() -> Self {
@@ -290,16 +798,18 @@ impl Default for Tabline {
 }
 impl MoveHorizontal for Tabline {
     fn left(self) -> Self {
-        if let Self::FriendRequests(_) = self {
-            return Self::Chatting(ContactPage::default());
+        match self {
+            Self::FriendRequests(_) => Self::Chatting(ContactPage::default()),
+            Self::Settings => Self::FriendRequests(FriendRequestPage::default()),
+            _ => self,
         }
-        self
     }
     fn right(self) -> Self {
-        if let Self::Chatting(_) = self {
-            return Self::FriendRequests(FriendRequestPage::default());
+        match self {
+            Self::Chatting(_) => Self::FriendRequests(FriendRequestPage::default()),
+            Self::FriendRequests(_) => Self::Settings,
+            _ => self,
         }
-        self
     }
 }
 impl MoveHorizontal for ContactPage {
@@ -342,7 +852,6 @@ enum ContactPage {
 enum FriendRequestPage {
     #[default]
     RequestList,
-    Search,
 }
 fn ui(f: &mut Frame, app: &mut App) {
     let layout = Layout::default()
@@ -352,10 +861,37 @@ fn ui(f: &mut Frame, app: &mut App) {
     // Tabline
     let tabline = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints(vec![Constraint::Percentage(50), Constraint::Percentage(50)])
+        .constraints(vec![Constraint::Ratio(1, 3); 3])
         .split(layout[0].offset(ratatui::layout::Offset { x: 0, y: 1 }));
-    f.render_widget(Paragraph::new("Chatting").centered(), tabline[0]);
-    f.render_widget(Paragraph::new("Friend requests").centered(), tabline[1]);
+    let tab_style = |selected: bool| match selected {
+        true => Style::new().bold().underlined(),
+        false => Style::new(),
+    };
+    f.render_widget(
+        Paragraph::new("Chatting")
+            .centered()
+            .style(tab_style(matches!(app.selected_tab, Tabline::Chatting(_)))),
+        tabline[0],
+    );
+    f.render_widget(
+        Paragraph::new("Friend requests")
+            .centered()
+            .style(tab_style(matches!(
+                app.selected_tab,
+                Tabline::FriendRequests(_)
+            ))),
+        tabline[1],
+    );
+    f.render_widget(
+        Paragraph::new("Settings")
+            .centered()
+            .style(tab_style(app.selected_tab == Tabline::Settings)),
+        tabline[2],
+    );
+    if app.selected_tab == Tabline::Settings {
+        render_settings(f, app, layout[1]);
+        return;
+    }
 
     let main_layout = Layout::default()
         .direction(Direction::Horizontal)
@@ -363,7 +899,11 @@ fn ui(f: &mut Frame, app: &mut App) {
         .split(layout[1]);
     let chat_layout = Layout::default()
         .direction(Direction::Vertical)
-        .constraints(vec![Constraint::Fill(1), Constraint::Length(3)])
+        .constraints(vec![
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Length(3),
+        ])
         .split(main_layout[1]);
     // contacts
     let contact_layout = Layout::default()
@@ -371,7 +911,7 @@ fn ui(f: &mut Frame, app: &mut App) {
         .constraints(vec![Constraint::Length(2), Constraint::Fill(1)])
         .split(main_layout[0]);
 
-    let contact_list = List::new(app.contacts.iter().map(|c| c.name.clone()))
+    let contact_list = List::new(app.contacts.iter().map(|c| contact_item(app, c)))
         .block(Block::bordered().title("Contacts"))
         .style(Style::new().white())
         .highlight_style(Style::new().italic())
@@ -389,29 +929,385 @@ fn ui(f: &mut Frame, app: &mut App) {
     f.render_stateful_widget(contact_scroll_bar, contact_layout[0], &mut scrollbar_state);
 
     // chat
+    let mut chat_input_block = Block::bordered();
+    if let Some(notice) = &app.notice {
+        chat_input_block = chat_input_block.title_bottom(Line::raw(format!(" {notice} ")).italic());
+    }
     let chat_input =
-        Paragraph::new(format!(" {} {}", ">", app.chat_input.clone())).block(Block::bordered());
-    let messages = app
-        .chat
-        .iter()
-        .map(|m| Text::raw(format!("{}: {}", m.sender.name, m.content)));
-    let chat_log = List::new(messages).block(Block::bordered());
-    f.render_widget(chat_log, chat_layout[0]);
-    f.render_widget(chat_input, chat_layout[1]);
+        Paragraph::new(format!(" {} {}", ">", app.chat_input.clone())).block(chat_input_block);
+    let messages = app.chat.iter().enumerate().map(|(i, m)| {
+        let previous = i.checked_sub(1).and_then(|i| app.chat.get(i));
+        message_text(app, previous, m)
+    });
+    let chat_log = List::new(messages)
+        .block(chat_block(app))
+        .highlight_style(Style::new().reversed());
+    render_call_button(f, app, chat_layout[0]);
+    f.render_stateful_widget(chat_log, chat_layout[1], &mut app.selected_message);
+    f.render_widget(chat_input, chat_layout[2]);
+    render_call_overlay(f, app);
+    render_contact_card(f, app);
     // friend list
 }
+fn message_text<'a>(app: &App, previous: Option<&Message>, message: &'a Message) -> Text<'a> {
+    let mut text = Text::default();
+    let time = message.time();
+    if previous.is_none_or(|p| p.time().date_naive() != time.date_naive()) {
+        let date = time.format("%a, %-d %b %Y");
+        text.push_line(Line::raw(format!("── {date} ──")).centered().dark_gray());
+    }
+    if let Some(reply_to) = message.reply_to {
+        let quoted = match app.chat.iter().find(|m| m.id == reply_to) {
+            Some(m) if m.deleted => format!("  ↪ {}: [deleted]", m.sender.name),
+            Some(m) => format!("  ↪ {}: {}", m.sender.name, m.content),
+            None => "  ↪ [unknown message]".to_string(),
+        };
+        text.push_line(Line::raw(quoted).italic());
+    }
+    let time = Span::raw(format!("{} ", time.format("%H:%M"))).dark_gray();
+    let line = match (message.deleted, message.edited) {
+        (true, _) => Line::from(vec![
+            time,
+            Span::raw(format!("{}: [message deleted]", message.sender.name)).italic(),
+        ]),
+        (false, true) => Line::from(vec![
+            time,
+            Span::raw(format!(
+                "{}: {} (edited)",
+                message.sender.name, message.content
+            )),
+        ]),
+        (false, false) => Line::from(vec![
+            time,
+            Span::raw(format!("{}: {}", message.sender.name, message.content)),
+        ]),
+    };
+    text.push_line(line);
+    if !message.reactions.is_empty() {
+        let mut counts = Vec::<(&str, usize)>::new();
+        for reaction in &message.reactions {
+            match counts
+                .iter_mut()
+                .find(|(emoji, _)| *emoji == reaction.emoji)
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((&reaction.emoji, 1)),
+            }
+        }
+        let line = counts
+            .iter()
+            .map(|(emoji, count)| format!("{emoji} {count}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        text.push_line(format!("  {line}"));
+    }
+    text
+}
+/// Contact list entry, the name with presence, unread count and time of the last message
+/// and a preview of the last message below
+fn contact_item(app: &App, contact: &Contact) -> Text<'static> {
+    let summary = app.summaries.get(&contact.peer_id);
+    let status = app.presence.get(&contact.peer_id).map(|p| p.status);
+    let unread = summary.map(|s| s.unread).unwrap_or_default();
+    let mut line = Line::from(Span::styled("● ", status_style(status)));
+    if summary.is_some_and(|s| s.pinned) {
+        line.push_span(Span::raw("★ ").yellow());
+    }
+    match unread {
+        0 => line.push_span(Span::raw(contact.name.clone())),
+        _ => {
+            line.push_span(Span::raw(contact.name.clone()).bold());
+            line.push_span(Span::raw(format!(" ({unread})")).bold().cyan());
+        }
+    }
+    if is_typing(app, &contact.peer_id) {
+        line.push_span(Span::raw(" …").italic());
+    }
+    let Some(last) = summary.and_then(|s| s.last_message.as_ref()) else {
+        return Text::from(line);
+    };
+    let time = Local
+        .timestamp_millis_opt(last.time as i64)
+        .single()
+        .unwrap_or_default();
+    let time = match time.date_naive() == Local::now().date_naive() {
+        true => time.format("%H:%M"),
+        false => time.format("%-d %b"),
+    };
+    line.push_span(Span::raw(format!(" {time}")).dark_gray());
+    let author = match last.sender == app.client.id {
+        true => "You: ",
+        false => "",
+    };
+    Text::from(vec![
+        line,
+        Line::raw(format!("  {author}{}", preview(last))).dark_gray(),
+    ])
+}
+fn preview(last: &LastMessage) -> String {
+    match last.deleted {
+        true => "[message deleted]".to_string(),
+        false => last.content.replace('\n', " "),
+    }
+}
+fn status_style(status: Option<UserStatus>) -> Style {
+    match status {
+        Some(UserStatus::Online) => Style::new().green(),
+        Some(UserStatus::Away) => Style::new().yellow(),
+        Some(UserStatus::Offline) | None => Style::new().dark_gray(),
+    }
+}
+fn is_typing(app: &App, peer_id: &PeerId) -> bool {
+    typing_at(&app.typing, peer_id, Instant::now())
+}
+/// Whether `peer_id` told us they're typing recently enough to still show it at `now`
+fn typing_at(typing: &HashMap<PeerId, Instant>, peer_id: &PeerId, now: Instant) -> bool {
+    typing
+        .get(peer_id)
+        .is_some_and(|since| now.duration_since(*since) < TYPING_TIMEOUT)
+}
+/// Chat log border with the contact's presence in the header and typing in the footer
+fn chat_block(app: &App) -> Block<'static> {
+    let Some(contact) = app
+        .selected_contact
+        .selected()
+        .and_then(|i| app.contacts.get(i))
+    else {
+        return Block::bordered();
+    };
+    let presence = app.presence.get(&contact.peer_id);
+    let status = presence.map(|p| p.status).unwrap_or(UserStatus::Offline);
+    let mut header = vec![
+        Span::raw(format!(" {} ", contact.name)),
+        Span::styled(
+            format!("● {} ", status.as_str()),
+            status_style(Some(status)),
+        ),
+    ];
+    if let Some(message) = presence.and_then(|p| p.message.as_ref()) {
+        header.push(Span::raw(format!("- {message} ")).italic());
+    }
+    if let Some(route) = app.routes.get(&contact.peer_id) {
+        header.push(Span::raw(format!("({}) ", route.as_str())).dark_gray());
+    }
+    let block = Block::bordered().title(Line::from(header));
+    match is_typing(app, &contact.peer_id) {
+        true => block.title_bottom(Line::raw(format!(" {} is typing… ", contact.name)).italic()),
+        false => block,
+    }
+}
+fn contact_name(app: &App, peer_id: &PeerId) -> String {
+    app.contacts
+        .iter()
+        .find(|c| c.peer_id == *peer_id)
+        .map(|c| c.name.clone())
+        .unwrap_or_else(|| peer_id.to_string())
+}
+fn render_settings(f: &mut Frame, app: &mut App, area: Rect) {
+    let settings = app.settings.try_read().ok();
+    let selected = app.selected_setting.selected();
+    let items = settings::registry().iter().enumerate().map(|(i, spec)| {
+        let value = settings
+            .as_ref()
+            .and_then(|s| s.get(&spec.name))
+            .map(|s| s.get_value().clone())
+            .unwrap_or_else(|| spec.default.clone());
+        let value = match (&app.setting_input, selected == Some(i)) {
+            (Some(input), true) => Span::raw(format!("{input}▏")).underlined(),
+            _ if value == SettingValue::String(None) => Span::raw("unset").dark_gray(),
+            _ => Span::raw(value.to_string()),
+        };
+        let mut help = vec![Span::raw(format!("  {}", spec.description))];
+        if !spec.constraints.is_empty() {
+            let constraints = spec
+                .constraints
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            help.push(Span::raw(format!(" ({constraints})")));
+        }
+        Text::from(vec![
+            Line::from(vec![Span::raw(format!("{:?}: ", spec.name)).bold(), value]),
+            Line::from(help).dark_gray(),
+        ])
+    });
+    let mut block = Block::bordered()
+        .title(format!("Settings - profile {}", app.profile))
+        .title_bottom(" enter: edit or toggle, esc: cancel, p: switch profile ");
+    if let Some(error) = &app.setting_error {
+        block = block.title_bottom(Line::raw(format!(" {error} ")).red().right_aligned());
+    }
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::new().reversed());
+    drop(settings);
+    f.render_stateful_widget(list, area, &mut app.selected_setting);
+    render_profile_picker(f, app);
+}
+fn render_profile_picker(f: &mut Frame, app: &App) {
+    let Some(input) = &app.profile_input else {
+        return;
+    };
+    let mut text = Text::default();
+    for profile in profile::list() {
+        let line = match profile == app.profile {
+            true => Line::raw(format!("  {profile} (current)")).bold(),
+            false => Line::raw(format!("  {profile}")),
+        };
+        text.push_line(line);
+    }
+    text.push_line(Line::raw(""));
+    text.push_line(Line::raw(format!("> {input}▏")));
+    let area = f.area().centered(
+        Constraint::Percentage(40),
+        Constraint::Length(text.height() as u16 + 2),
+    );
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text).block(
+            Block::bordered()
+                .title("Switch profile")
+                .title_bottom(" enter: switch or create, esc: cancel "),
+        ),
+        area,
+    );
+}
+fn render_call_button(f: &mut Frame, app: &App, area: Rect) {
+    let label = match &app.call {
+        Some(call) => match call.state {
+            CallState::Dialing => format!("Calling {}... [Hang up]", contact_name(app, &call.peer)),
+            CallState::Active => {
+                format!("In call with {} [Hang up]", contact_name(app, &call.peer))
+            }
+            _ => "[Call]".to_string(),
+        },
+        None => "[Call]".to_string(),
+    };
+    let style = match app.selected_tab {
+        Tabline::Chatting(ContactPage::CallButton) => Style::new().reversed(),
+        _ => Style::new(),
+    };
+    f.render_widget(
+        Paragraph::new(label).style(style).block(Block::bordered()),
+        area,
+    );
+}
+fn render_contact_card(f: &mut Frame, app: &App) {
+    let Some(token) = &app.contact_card else {
+        return;
+    };
+    // dark modules are left blank, which reads right on dark terminal themes
+    let mut text = match QrCode::new(token) {
+        Ok(code) => Text::raw(
+            code.render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build(),
+        ),
+        Err(err) => Text::raw(format!("can't show a QR code: {err}")),
+    };
+    text.push_line(Line::raw(""));
+    // the token has no spaces to wrap at, so it is split by hand
+    let width = (f.area().width * 9 / 10).saturating_sub(2).max(1) as usize;
+    for chunk in token.as_bytes().chunks(width) {
+        text.push_line(Line::raw(String::from_utf8_lossy(chunk).into_owned()));
+    }
+    let area = f.area().centered(
+        Constraint::Percentage(90),
+        Constraint::Length(text.height() as u16 + 2),
+    );
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text).centered().block(
+            Block::bordered()
+                .title("Contact card - import it with /add <card>")
+                .title_bottom(" press any key "),
+        ),
+        area,
+    );
+}
+fn render_call_overlay(f: &mut Frame, app: &App) {
+    let Some(call) = &app.call else {
+        return;
+    };
+    let name = contact_name(app, &call.peer);
+    let text = match call.state {
+        CallState::Ringing => format!("{name} is calling you\n\n[y] accept  [n] reject"),
+        CallState::Ended(reason) => {
+            let reason = match reason {
+                CallEnd::HungUp => "Call ended",
+                CallEnd::Rejected => "Call rejected",
+                CallEnd::Busy => "Busy",
+                CallEnd::Failed => "Call failed",
+                CallEnd::NoAnswer => "No answer",
+                CallEnd::NoCommonCodec => "Call failed, no audio codec in common",
+                CallEnd::NoAudio => "Call failed, no audio devices",
+            };
+            format!("{reason}: {name}\n\npress any key")
+        }
+        CallState::Dialing | CallState::Active => return,
+    };
+    let area = f
+        .area()
+        .centered(Constraint::Percentage(50), Constraint::Length(6));
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text)
+            .centered()
+            .block(Block::bordered().title("Call")),
+        area,
+    );
+}
 // App state
 struct App {
     selected_tab: Tabline,
     selected_contact: ListState,
     contacts: Vec<Contact>,
+    /// Unread counts and last messages, by contact
+    summaries: HashMap<PeerId, ContactSummary>,
     should_quit: bool,
     chat: Vec<Message>,
+    selected_message: ListState,
     chat_input: String,
     client: Client,
+    db: Database,
+    settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
+    notifier: Notifier,
+    /// Whether the terminal has focus
+    focused: bool,
     token: CancellationToken,
+    call: Option<types::Call>,
+    presence: HashMap<PeerId, types::Presence>,
+    /// When each contact last told us they're typing
+    typing: HashMap<PeerId, Instant>,
+    /// When we last told a contact we're typing
+    typing_sent: Option<(PeerId, Instant)>,
+    selected_setting: ListState,
+    /// The value being typed while a setting is edited
+    setting_input: Option<String>,
+    /// Why the last edit was rejected
+    setting_error: Option<String>,
+    profile: String,
+    /// The profile name being typed while switching profiles
+    profile_input: Option<String>,
+    /// Profile to restart with once the app quit
+    switch_profile: Option<String>,
+    /// Latest feedback, e.g. how a dial went
+    notice: Option<String>,
+    /// Our contact card while it is shown
+    contact_card: Option<String>,
+    /// Whether connected contacts are reached directly or through a relay
+    routes: HashMap<PeerId, Route>,
 }
-pub async fn run(client: Client, token: CancellationToken, mut tui: Tui) -> anyhow::Result<()> {
+pub async fn run(
+    client: Client,
+    db: Database,
+    settings: Arc<RwLock<HashMap<SettingName, Setting>>>,
+    token: CancellationToken,
+    mut tui: Tui,
+    profile: String,
+) -> anyhow::Result<Option<String>> {
     // ratatui terminal
     tui.start();
 
@@ -427,6 +1323,7 @@ pub async fn run(client: Client, token: CancellationToken, mut tui: Tui) -> anyh
             // },
             // "Zuckerlizard".to_string(),
         ],
+        summaries: HashMap::new(),
         selected_contact: ListState::default().with_selected(Some(0)),
         chat: vec![
         //     Message {
@@ -439,21 +1336,52 @@ pub async fn run(client: Client, token: CancellationToken, mut tui: Tui) -> anyh
         //     status: types::MessageStatus::ReceivedRead,
         // }
         ],
+        selected_message: ListState::default(),
         chat_input: String::new(),
+        db,
+        settings,
+        notifier: Notifier::connect().await,
+        focused: true,
         token,
+        call: None,
+        presence: HashMap::new(),
+        typing: HashMap::new(),
+        typing_sent: None,
+        selected_setting: ListState::default().with_selected(Some(0)),
+        setting_input: None,
+        setting_error: None,
+        profile,
+        profile_input: None,
+        switch_profile: None,
+        notice: None,
+        contact_card: None,
+        routes: HashMap::new(),
     };
+    load_contacts(&mut app).await;
+    load_chat(&mut app).await;
 
+    let token = app.token.clone();
+    // lets main shut down even if the interface panics
+    let _quit = token.clone().drop_guard();
     loop {
-        let event = tui.next().await; // blocks until next event
+        // blocks until next event, or until we are told to quit from outside
+        let event = tokio::select! {
+            event = tui.next() => event,
+            _ = token.cancelled() => break,
+        };
         let Some(event) = event else {
             continue;
         };
         // application update
         handle_event(&mut app, event).await;
 
-        tui.terminal.draw(|f| {
+        // the terminal is restored before the error goes up
+        if let Err(err) = tui.terminal.draw(|f| {
             ui(f, &mut app);
-        })?;
+        }) {
+            tui.exit();
+            return Err(err.into());
+        }
 
         // application exit
         if app.should_quit {
@@ -462,5 +1390,75 @@ pub async fn run(client: Client, token: CancellationToken, mut tui: Tui) -> anyh
     }
     tui.exit();
 
-    Ok(())
+    Ok(app.switch_profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(pinned: bool, time: Option<u64>) -> ContactSummary {
+        let peer_id = PeerId::random();
+        ContactSummary {
+            peer_id,
+            name: "alice".to_string(),
+            pinned,
+            unread: 0,
+            last_message: time.map(|time| LastMessage {
+                sender: peer_id,
+                content: "hi".to_string(),
+                deleted: false,
+                time,
+            }),
+        }
+    }
+
+    #[test]
+    fn pinned_contacts_come_first_then_the_latest() {
+        let old_pinned = summary(true, Some(1));
+        let new = summary(false, Some(3));
+        let old = summary(false, Some(2));
+        let quiet = summary(false, None);
+        let mut contacts = [
+            None,
+            Some(&old),
+            Some(&quiet),
+            Some(&new),
+            Some(&old_pinned),
+        ];
+        contacts.sort_by_key(|s| contact_rank(*s));
+        let order: Vec<_> = contacts.iter().map(|s| s.map(|s| s.peer_id)).collect();
+        assert_eq!(
+            order[..3],
+            [
+                Some(old_pinned.peer_id),
+                Some(new.peer_id),
+                Some(old.peer_id)
+            ]
+        );
+        // contacts without messages keep their place at the end
+        assert!(order[3..].contains(&None));
+        assert!(order[3..].contains(&Some(quiet.peer_id)));
+    }
+    #[test]
+    fn previews_fit_on_a_line_and_hide_deleted_messages() {
+        let mut last = summary(false, Some(1)).last_message.unwrap();
+        last.content = "two\nlines".to_string();
+        assert_eq!(preview(&last), "two lines");
+        last.content = String::new();
+        last.deleted = true;
+        assert_eq!(preview(&last), "[message deleted]");
+    }
+    #[test]
+    fn typing_expires_unless_it_is_repeated() {
+        let peer = PeerId::random();
+        let since = Instant::now();
+        let mut typing = HashMap::from([(peer, since)]);
+        assert!(typing_at(&typing, &peer, since + TYPING_RESEND));
+        assert!(!typing_at(&typing, &peer, since + TYPING_TIMEOUT));
+        assert!(!typing_at(&typing, &PeerId::random(), since));
+        // each resend starts the timeout over
+        typing.insert(peer, since + TYPING_RESEND);
+        assert!(typing_at(&typing, &peer, since + TYPING_TIMEOUT));
+    }
 }
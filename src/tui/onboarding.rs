@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crossterm::event::KeyCode::{self, Char};
+use libp2p::identity::Keypair;
+use ratatui::Frame;
+use ratatui::layout::Constraint;
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Clear, Paragraph};
+
+use crate::identity;
+use crate::settings::{Setting, SettingKind, SettingName, SettingValue, Settings};
+use crate::tui::{Event, Tui};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Step {
+    Name,
+    Identity,
+    Transports,
+    Discovery,
+    Finish,
+}
+impl Step {
+    const ALL: [Step; 5] = [
+        Step::Name,
+        Step::Identity,
+        Step::Transports,
+        Step::Discovery,
+        Step::Finish,
+    ];
+    fn title(&self) -> &'static str {
+        match self {
+            Step::Name => "Display name",
+            Step::Identity => "Identity",
+            Step::Transports => "Transports",
+            Step::Discovery => "Discovery",
+            Step::Finish => "Done",
+        }
+    }
+    fn fields(&self, import: bool) -> Vec<Field> {
+        match self {
+            Step::Name => vec![Field::Setting(SettingName::Name)],
+            Step::Identity if import => vec![Field::IdentitySource, Field::KeyFile],
+            Step::Identity => vec![Field::IdentitySource],
+            Step::Transports => vec![
+                Field::Setting(SettingName::ListenTcp),
+                Field::Setting(SettingName::TcpPort),
+                Field::Setting(SettingName::ListenQuic),
+                Field::Setting(SettingName::QuicPort),
+            ],
+            Step::Discovery => vec![
+                Field::Setting(SettingName::LanDiscovery),
+                Field::Setting(SettingName::Dht),
+            ],
+            Step::Finish => Vec::new(),
+        }
+    }
+    fn index(&self) -> usize {
+        Step::ALL.iter().position(|s| s == self).unwrap_or_default()
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    Setting(SettingName),
+    /// Whether to generate a new identity or import one
+    IdentitySource,
+    KeyFile,
+}
+impl Field {
+    fn label(&self) -> &'static str {
+        match self {
+            Field::Setting(SettingName::Name) => "Display name",
+            Field::Setting(SettingName::ListenTcp) => "TCP",
+            Field::Setting(SettingName::TcpPort) => "TCP port",
+            Field::Setting(SettingName::ListenQuic) => "QUIC",
+            Field::Setting(SettingName::QuicPort) => "QUIC port",
+            Field::Setting(SettingName::LanDiscovery) => "LAN discovery",
+            Field::Setting(SettingName::Dht) => "DHT",
+            Field::Setting(_) => "",
+            Field::IdentitySource => "Identity",
+            Field::KeyFile => "Key file",
+        }
+    }
+    fn is_toggle(&self) -> bool {
+        match self {
+            Field::Setting(name) => name.spec().kind == SettingKind::Bool,
+            Field::IdentitySource => true,
+            Field::KeyFile => false,
+        }
+    }
+}
+struct Wizard {
+    step: Step,
+    selected: usize,
+    /// What was entered for each setting, parsed when leaving a step
+    inputs: HashMap<SettingName, String>,
+    import: bool,
+    key_file: String,
+    imported: Option<Keypair>,
+    error: Option<String>,
+}
+impl Wizard {
+    fn fields(&self) -> Vec<Field> {
+        self.step.fields(self.import)
+    }
+    fn field(&self) -> Option<Field> {
+        self.fields().get(self.selected).copied()
+    }
+    fn value(&self, name: SettingName) -> Result<SettingValue, String> {
+        let input = self.inputs.get(&name).map(String::as_str).unwrap_or("");
+        let spec = name.spec();
+        let value = SettingValue::parse(spec.kind, input)
+            .and_then(|value| spec.validate(&value).map(|_| value))
+            .map_err(|err| format!("{}: {err}", Field::Setting(name).label()))?;
+        Ok(value)
+    }
+    fn enabled(&self, name: SettingName) -> bool {
+        self.value(name) == Ok(SettingValue::Bool(true))
+    }
+    fn toggle(&mut self, field: Field) {
+        match field {
+            Field::IdentitySource => {
+                self.import = !self.import;
+                self.imported = None;
+            }
+            Field::Setting(name) => {
+                let value = !self.enabled(name);
+                self.inputs.insert(name, value.to_string());
+            }
+            Field::KeyFile => {}
+        }
+    }
+    /// Checks the current step before moving on.
+    fn validate(&mut self) -> Result<(), String> {
+        for field in self.fields() {
+            if let Field::Setting(name) = field {
+                self.value(name)?;
+            }
+        }
+        match self.step {
+            Step::Name if self.value(SettingName::Name)? == SettingValue::String(None) => {
+                Err("Pick a name others will see".to_string())
+            }
+            Step::Identity if self.import => {
+                let keys = identity::import(Path::new(self.key_file.trim()))
+                    .map_err(|err| format!("Can't import {}: {err}", self.key_file.trim()))?;
+                self.imported = Some(keys);
+                Ok(())
+            }
+            Step::Transports
+                if !self.enabled(SettingName::ListenTcp)
+                    && !self.enabled(SettingName::ListenQuic) =>
+            {
+                Err("Enable at least one transport".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Writes the settings and identity, returning the identity to start the network with.
+    async fn finish(self, settings: &mut HashMap<SettingName, Setting>) -> anyhow::Result<Keypair> {
+        for name in self.inputs.keys() {
+            let value = self.value(*name).map_err(anyhow::Error::msg)?;
+            settings
+                .entry(*name)
+                .or_insert_with(|| name.spec().default_setting())
+                .set_value(*name, value)?;
+        }
+        Settings::save(settings).await?;
+        let keys = match self.imported {
+            Some(keys) => keys,
+            None => Keypair::generate_ed25519(),
+        };
+        identity::save(&keys)?;
+        Ok(keys)
+    }
+}
+
+/// Walks through the first-run setup, `None` if the user quit before finishing.
+/// Nothing is written until the last step is confirmed.
+pub async fn run(
+    tui: &mut Tui,
+    settings: &mut HashMap<SettingName, Setting>,
+) -> anyhow::Result<Option<Keypair>> {
+    tui.start();
+    let inputs = Step::ALL
+        .iter()
+        .flat_map(|step| step.fields(false))
+        .filter_map(|field| match field {
+            Field::Setting(name) => Some(name),
+            _ => None,
+        })
+        .map(|name| {
+            let value = settings
+                .get(&name)
+                .map(|s| s.get_value().clone())
+                .unwrap_or_else(|| name.spec().default.clone());
+            (name, value.to_string())
+        })
+        .collect();
+    let mut wizard = Wizard {
+        step: Step::Name,
+        selected: 0,
+        inputs,
+        import: false,
+        key_file: String::new(),
+        imported: None,
+        error: None,
+    };
+    loop {
+        tui.terminal.draw(|f| render(f, &wizard))?;
+        let Some(event) = tui.next().await else {
+            return Ok(None);
+        };
+        let Event::Key(key) = event else {
+            continue;
+        };
+        let field = wizard.field();
+        match key.code {
+            KeyCode::Esc => match Step::ALL.get(wizard.step.index().wrapping_sub(1)) {
+                Some(step) => {
+                    wizard.step = *step;
+                    wizard.selected = 0;
+                    wizard.error = None;
+                }
+                None => return Ok(None),
+            },
+            KeyCode::Up | KeyCode::BackTab => wizard.selected = wizard.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Tab => {
+                wizard.selected = (wizard.selected + 1).min(wizard.fields().len().saturating_sub(1))
+            }
+            KeyCode::Enter => {
+                if let Err(err) = wizard.validate() {
+                    wizard.error = Some(err);
+                    continue;
+                }
+                wizard.error = None;
+                match Step::ALL.get(wizard.step.index() + 1) {
+                    Some(step) => {
+                        wizard.step = *step;
+                        wizard.selected = 0;
+                    }
+                    None => return wizard.finish(settings).await.map(Some),
+                }
+            }
+            Char(' ') if field.is_some_and(|f| f.is_toggle()) => {
+                wizard.toggle(field.expect("field to be selected"))
+            }
+            Char(ch) => match field {
+                Some(Field::Setting(name)) => wizard.inputs.entry(name).or_default().push(ch),
+                Some(Field::KeyFile) => wizard.key_file.push(ch),
+                _ => {}
+            },
+            KeyCode::Backspace => match field {
+                Some(Field::Setting(name)) => {
+                    wizard.inputs.entry(name).or_default().pop();
+                }
+                Some(Field::KeyFile) => {
+                    wizard.key_file.pop();
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+fn render(f: &mut Frame, wizard: &Wizard) {
+    let area = f
+        .area()
+        .centered(Constraint::Percentage(60), Constraint::Length(14));
+    let mut text = Text::default();
+    for (i, field) in wizard.fields().into_iter().enumerate() {
+        let selected = i == wizard.selected;
+        let value = match field {
+            Field::IdentitySource => match wizard.import {
+                true => "import from a key file".to_string(),
+                false => "generate a new one".to_string(),
+            },
+            Field::KeyFile => wizard.key_file.clone(),
+            Field::Setting(name) if field.is_toggle() => match wizard.enabled(name) {
+                true => "[x]".to_string(),
+                false => "[ ]".to_string(),
+            },
+            Field::Setting(name) => wizard.inputs.get(&name).cloned().unwrap_or_default(),
+        };
+        let style = match selected {
+            true => Style::new().reversed(),
+            false => Style::new(),
+        };
+        text.push_line(Line::from(vec![
+            Span::raw(format!("{}: ", field.label())).bold(),
+            Span::styled(value, style),
+        ]));
+        if selected && let Field::Setting(name) = field {
+            text.push_line(Line::raw(format!("  {}", name.spec().description)).dark_gray());
+        }
+    }
+    if wizard.step == Step::Finish {
+        let identity = match &wizard.imported {
+            Some(keys) => format!("imported {}", keys.public().to_peer_id()),
+            None => "a new one will be generated".to_string(),
+        };
+        text.push_line(Line::raw(format!(
+            "Name: {}",
+            wizard
+                .inputs
+                .get(&SettingName::Name)
+                .cloned()
+                .unwrap_or_default()
+        )));
+        text.push_line(Line::raw(format!("Identity: {identity}")));
+        text.push_line(Line::raw(""));
+        text.push_line(Line::raw("Press enter to save and start chatting."));
+    }
+    if let Some(error) = &wizard.error {
+        text.push_line(Line::raw(""));
+        text.push_line(Line::raw(error.clone()).red());
+    }
+    let block = Block::bordered()
+        .title(format!(
+            " Welcome to p2pchat - {} ({}/{}) ",
+            wizard.step.title(),
+            wizard.step.index() + 1,
+            Step::ALL.len()
+        ))
+        .title_bottom(" enter: next, esc: back, space: toggle ");
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(text).block(block), area);
+}
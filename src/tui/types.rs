@@ -1,22 +1,72 @@
+use chrono::{DateTime, Local, TimeZone};
 use libp2p::PeerId;
 
-#[derive(Debug, Clone)]
-pub enum MessageStatus {
-    ReceivedNotRead,
-    ReceivedRead,
-    SentOffNotRead,
-    SentOffRead,
-}
+use crate::db::models::Reaction;
+use crate::network::call::CallState;
+use crate::network::chat;
+use crate::network::clock::now_millis;
+use crate::network::presence::UserStatus;
+
 #[derive(Debug, Clone)]
 pub struct Message {
     pub content: String,
     pub id: uuid::Uuid,
     pub sender: Contact,
-    pub status: MessageStatus,
-    // TODO: date
+    pub reply_to: Option<uuid::Uuid>,
+    pub edited: bool,
+    pub deleted: bool,
+    pub reactions: Vec<Reaction>,
+    /// Milliseconds since the unix epoch, as claimed by the sender
+    pub sent_at: Option<u64>,
+    /// Milliseconds since the unix epoch
+    pub received_at: u64,
+}
+impl Message {
+    /// When the message was sent, falling back to when we got it for old messages
+    pub fn time(&self) -> DateTime<Local> {
+        let millis = self.sent_at.unwrap_or(self.received_at);
+        Local
+            .timestamp_millis_opt(millis as i64)
+            .single()
+            .unwrap_or_default()
+    }
+}
+impl Message {
+    /// A message `peer` just sent us as the chat shows it, `None` for edits and other
+    /// changes to earlier messages.
+    pub fn received(message: &chat::Message, peer: PeerId) -> Option<Self> {
+        let chat::MessageBody::Text { content, reply_to } = message.body() else {
+            return None;
+        };
+        Some(Message {
+            id: message.id(),
+            content: content.clone(),
+            sender: Contact {
+                name: "Anonymous".to_string(),
+                peer_id: peer,
+            },
+            reply_to: *reply_to,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            sent_at: message.sent_at(),
+            received_at: now_millis(),
+        })
+    }
 }
 #[derive(Debug, Clone, PartialEq)]
 pub struct Contact {
     pub peer_id: PeerId,
     pub name: String,
 }
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub id: uuid::Uuid,
+    pub peer: PeerId,
+    pub state: CallState,
+}
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub status: UserStatus,
+    pub message: Option<String>,
+}
@@ -1,6 +0,0 @@
-struct ScrollableList<T>
-where
-    T: std::fmt::Display,
-{
-    list: Vec<T>,
-}